@@ -1,6 +1,21 @@
+#[cfg(feature = "json-adaptor")]
+use ollama_rs::generation::chat::request::ChatMessageRequest;
+use ollama_rs::generation::completion::GenerationResponse;
+#[cfg(feature = "json-adaptor")]
+use ollama_rs::generation::completion::request::GenerationRequest;
+#[cfg(feature = "json-adaptor")]
+use ollama_rs::generation::embeddings::request::GenerateEmbeddingsRequest;
+use ollama_rs::models::{LocalModel, ModelOptions};
+#[cfg(feature = "json-adaptor")]
+use prosa::core::adaptor::Adaptor;
 use prosa_utils::msg::tvf::Tvf;
+use std::time::SystemTime;
+use tracing::debug;
 
-use crate::proc::{OllamaError, OllamaProc, OllamaRequest, OllamaResponse};
+use crate::proc::{
+    AdaptorRequest, EvalSample, OllamaError, OllamaProc, OllamaProcSettings, OllamaRequest,
+    OllamaRequestKind, OllamaResponse, RequestMetadata,
+};
 
 pub trait OllamaAdaptor<M>
 where
@@ -19,13 +34,253 @@ where
     where
         Self: Sized;
 
+    /// Method called once the models are pulled, with the local models of the Ollama server (name, size, digest), before the services are declared.
+    /// An error stops the processor, e.g. if a model needed by the adaptor is missing
+    fn on_models_ready(&mut self, _local_models: &[LocalModel]) -> Result<(), OllamaError> {
+        Ok(())
+    }
+
+    /// Method to attach business metadata to an incomming request, called before [`OllamaAdaptor::process_request`].
+    /// The metadata are echoed in the completion log, and the keys listed in `metadata_metric_keys` are added to the metrics.
+    ///
+    /// The metadata are bounded to [`crate::proc::MAX_REQUEST_METADATA_ENTRIES`] entries.
+    fn request_metadata(&self, _service_name: &str, _request: &M) -> RequestMetadata {
+        RequestMetadata::new()
+    }
+
+    /// Method to get the priority of an incomming request (the higher the more urgent, e.g. interactive chat over background embeddings).
+    /// With `priority_queue_capacity`, the requests waiting for a slot are served from the highest priority
+    fn request_priority(&self, _service_name: &str, _request: &M) -> u8 {
+        0
+    }
+
+    /// Method to get the deadline of an incomming request (e.g. from a field of the message), called before [`OllamaAdaptor::process_request`].
+    /// The Ollama call is bounded by the deadline instead of the `request_timeout`, and a request past its deadline is returned in timeout without calling the backend.
+    fn request_deadline(&self, _service_name: &str, _request: &M) -> Option<SystemTime> {
+        None
+    }
+
     /// Method to process incomming requests
+    ///
+    /// A malformed request should be reported with [`OllamaError::BadRequest`], to tell the client which field is invalid.
+    /// A service the adaptor doesn't recognize should be rejected with [`OllamaError::UnknownService`]
     fn process_request<'a>(
         &mut self,
         service_name: &str,
         request: M,
     ) -> Result<OllamaRequest<'a>, OllamaError>;
 
+    /// Method to process incomming requests, that can also answer them without calling the backend (e.g. cache hit, guardrail rejection).
+    /// A [`AdaptorRequest::Response`] is returned to the sender as is, with only the latency metric recorded (with the `local` type).
+    ///
+    /// By default, it calls [`OllamaAdaptor::process_request`]
+    fn process_request_or_response<'a>(
+        &mut self,
+        service_name: &str,
+        request: M,
+    ) -> Result<AdaptorRequest<'a, M>, OllamaError> {
+        self.process_request(service_name, request)
+            .map(AdaptorRequest::Ollama)
+    }
+
+    /// Method to know if a generation or chat request can be sent to the `fallback_model` when its model is missing on the server.
+    /// Return `false` to get the error instead (e.g. when the answer of another model is not acceptable)
+    fn allow_fallback_model(&self, _service_name: &str, _request: &OllamaRequest<'_>) -> bool {
+        true
+    }
+
     /// Method to process Ollama responses
+    ///
+    /// The token usage of a generation or chat response is given by [`OllamaResponse::get_token_usage`], to embed it in the reply message
     fn process_ollama_response(&mut self, response: OllamaResponse) -> Result<M, OllamaError>;
+
+    /// Method to process the chunks of a [`OllamaRequest::GenerateStreamRequest`], called in the generation order.
+    /// The returned partial message is sent as a request to the `stream_service` (if set), before the next chunk is processed.
+    ///
+    /// Concurrent streams interleave their chunks: they're told apart by the service name and the id of the streamed request message ([`prosa::core::msg::Msg::get_id`]), to put in the partial message.
+    /// The last chunk has its `done` flag set. Once it's processed, the complete response is given to [`OllamaAdaptor::process_ollama_response`], so the adaptor can flush its state.
+    fn process_stream_chunk(
+        &mut self,
+        _service_name: &str,
+        _request_id: u64,
+        _chunk: &GenerationResponse,
+    ) -> Result<Option<M>, OllamaError> {
+        Ok(None)
+    }
+
+    /// Method to process Ollama responses, with the options effectively sent to the backend after the service defaults merge.
+    /// The model parameter defaults of the backend apply to the options that are not set.
+    ///
+    /// By default, it calls [`OllamaAdaptor::process_ollama_response`]
+    fn process_ollama_response_with_options(
+        &mut self,
+        response: OllamaResponse,
+        _options: Option<&ModelOptions>,
+    ) -> Result<M, OllamaError> {
+        self.process_ollama_response(response)
+    }
+
+    /// Method called when an Ollama call fails, with the original error before it's mapped to a service error (e.g. for custom logging or metrics).
+    /// Return an error to replace the original one (e.g. with a custom message)
+    fn on_backend_error(
+        &self,
+        _request_kind: OllamaRequestKind,
+        _error: &OllamaError,
+    ) -> Option<OllamaError> {
+        None
+    }
+
+    /// Method to build an error response, called when `errors_as_response` is enabled.
+    /// Return `None` to send the error as a service error
+    fn process_error(&mut self, _error: &OllamaError) -> Option<M> {
+        None
+    }
+
+    /// Method called on a prompt and response pair sampled for offline evaluation, to redact private content.
+    /// Return `None` to drop the sample
+    fn redact_eval_sample(&self, sample: EvalSample) -> Option<EvalSample> {
+        Some(sample)
+    }
+
+    /// Method called when the Ollama server rejects the auth token (HTTP 401), to get a new one.
+    /// The request is retried once with the new token.
+    /// If no token is returned, it is fetched with [`OllamaProcSettings::refresh_auth_token`], and the error is sent back if there is still none.
+    ///
+    /// By default, no token is returned
+    fn refresh_auth_token(
+        &mut self,
+        _settings: &OllamaProcSettings,
+    ) -> Result<Option<String>, OllamaError> {
+        Ok(None)
+    }
+
+    /// Method called when the processor receives a configuration message, to get its new settings (e.g. to rotate the auth token or change the server URL).
+    /// When new settings are returned, the Ollama client is rebuilt, the services are updated and the missing models are pulled.
+    ///
+    /// By default, the settings are kept
+    fn reload_config(
+        &mut self,
+        _settings: &OllamaProcSettings,
+    ) -> Result<Option<OllamaProcSettings>, OllamaError> {
+        Ok(None)
+    }
+
+    /// Method called once the processor applied the settings returned by [`OllamaAdaptor::reload_config`], to keep the state of the adaptor consistent with them (e.g. reload its own configuration, clear its caches)
+    fn on_config_reload(&mut self, _settings: &OllamaProcSettings) {}
+
+    /// Method called when the processor receives a command, to implement management operations (e.g. flush a cache, reload the models)
+    ///
+    /// By default, the command is logged and ignored
+    fn process_command(&mut self, command: &str) -> Result<(), OllamaError> {
+        debug!("Ignore the unknown command `{command}`");
+        Ok(())
+    }
+
+    /// Method called on shutdown, before [`prosa::core::adaptor::Adaptor::terminate`], to report the failures of the adaptor termination (e.g. state not persisted).
+    /// An error is logged, and doesn't prevent the processor removal
+    fn try_terminate(&mut self) -> Result<(), OllamaError> {
+        Ok(())
+    }
+}
+
+/// Request read from the JSON of a message: the fields of the Ollama API request, with a `type` tag (`generate`, `chat` or `embeddings`)
+#[cfg(feature = "json-adaptor")]
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonRequest {
+    Generate(Box<GenerationRequest<'static>>),
+    Chat(Box<ChatMessageRequest>),
+    Embeddings(Box<GenerateEmbeddingsRequest>),
+}
+
+/// Pass-through adaptor, for the JSON-in/JSON-out use cases
+///
+/// The request is a JSON document in the [`JsonOllamaAdaptor::JSON_FIELD`] string field of the message, with the fields of the Ollama API request and a `type` tag:
+/// ```json
+/// {"type": "generate", "model": "mistral", "prompt": "Why is the sky blue?"}
+/// {"type": "chat", "model": "mistral", "messages": [{"role": "user", "content": "Hello"}]}
+/// {"type": "embeddings", "model": "all-minilm", "input": ["Hello", "World"]}
+/// ```
+/// The response is the JSON of the Ollama API response, in the same field.
+#[cfg(feature = "json-adaptor")]
+#[derive(Debug, Default, Adaptor)]
+pub struct JsonOllamaAdaptor;
+
+#[cfg(feature = "json-adaptor")]
+impl JsonOllamaAdaptor {
+    /// Field of the message containing the JSON of the request and of the response
+    pub const JSON_FIELD: usize = 1;
+}
+
+#[cfg(feature = "json-adaptor")]
+impl<M> OllamaAdaptor<M> for JsonOllamaAdaptor
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+{
+    fn new(_proc: &OllamaProc<M>) -> Result<Self, OllamaError> {
+        Ok(JsonOllamaAdaptor)
+    }
+
+    fn process_request<'a>(
+        &mut self,
+        _service_name: &str,
+        request: M,
+    ) -> Result<OllamaRequest<'a>, OllamaError> {
+        let json = request
+            .get_string(Self::JSON_FIELD)
+            .map_err(|e| OllamaError::bad_field(Self::JSON_FIELD.to_string(), e.to_string()))?;
+        match serde_json::from_str(&json)
+            .map_err(|e| OllamaError::bad_field(Self::JSON_FIELD.to_string(), e.to_string()))?
+        {
+            JsonRequest::Generate(request) => Ok(OllamaRequest::GenerateRequest(request)),
+            JsonRequest::Chat(request) => Ok(OllamaRequest::ChatRequest(request)),
+            JsonRequest::Embeddings(request) => {
+                Ok(OllamaRequest::GenerateEmbeddingsRequest(request))
+            }
+        }
+    }
+
+    fn process_ollama_response(&mut self, response: OllamaResponse) -> Result<M, OllamaError> {
+        let json = match response {
+            OllamaResponse::GenerateResponse(response) => serde_json::to_string(&response),
+            OllamaResponse::ChatMessageResponse(response) => serde_json::to_string(&response),
+            OllamaResponse::GenerateEmbeddingsResponse(response) => {
+                serde_json::to_string(&response)
+            }
+            OllamaResponse::MarkedEmbeddingsResponse {
+                response,
+                invalid_indices,
+            } => serde_json::to_string(&serde_json::json!({
+                "embeddings": response.embeddings,
+                "invalid_indices": invalid_indices,
+            })),
+            OllamaResponse::PartialEmbeddingsResponse { response, errors } => {
+                serde_json::to_string(&serde_json::json!({
+                    "embeddings": response.embeddings,
+                    "errors": errors
+                        .iter()
+                        .map(|(index, error)| serde_json::json!({"index": index, "error": error}))
+                        .collect::<Vec<_>>(),
+                }))
+            }
+            _ => {
+                return Err(OllamaError::Other(
+                    "The JSON adaptor only handles generate, chat and embeddings responses"
+                        .to_string(),
+                ));
+            }
+        }
+        .map_err(|e| OllamaError::Other(format!("Can't serialize the Ollama response: {e}")))?;
+
+        let mut msg = M::default();
+        msg.put_string(Self::JSON_FIELD, json);
+        Ok(msg)
+    }
 }
@@ -1,2 +1,4 @@
 pub mod adaptor;
 pub mod proc;
+#[cfg(feature = "testing")]
+pub mod testing;
@@ -1,23 +1,46 @@
 use base64::{Engine as _, engine::general_purpose::STANDARD};
 use ollama_rs::Ollama;
-use ollama_rs::generation::chat::ChatMessageResponse;
+use ollama_rs::generation::chat::request::ChatMessageRequest;
+use ollama_rs::generation::chat::{ChatMessage, ChatMessageResponse, MessageRole};
 use ollama_rs::generation::completion::GenerationResponse;
 use ollama_rs::generation::completion::request::GenerationRequest;
 use ollama_rs::generation::embeddings::GenerateEmbeddingsResponse;
-use ollama_rs::generation::embeddings::request::GenerateEmbeddingsRequest;
-use ollama_rs::headers::{HeaderMap, HeaderValue, InvalidHeaderValue};
-use ollama_rs::models::{LocalModel, ModelInfo};
+use ollama_rs::generation::embeddings::request::{EmbeddingsInput, GenerateEmbeddingsRequest};
+use ollama_rs::generation::images::Image;
+use ollama_rs::generation::parameters::{FormatType, KeepAlive};
+use ollama_rs::headers::{
+    HeaderMap, HeaderName, HeaderValue, InvalidHeaderName, InvalidHeaderValue,
+};
+use ollama_rs::models::create::CreateModelRequest;
+use ollama_rs::models::pull::PullModelStatus;
+use ollama_rs::models::{LocalModel, ModelInfo, ModelOptions};
 use opentelemetry::KeyValue;
+use opentelemetry::metrics::{Counter, Gauge, Histogram, Meter};
 use prosa::core::adaptor::Adaptor;
 use prosa::core::error::ProcError;
-use prosa::core::msg::{InternalMsg, Msg};
+use prosa::core::msg::{InternalMsg, Msg, RequestMsg};
 use prosa::core::proc::{Proc, ProcBusParam, ProcConfig as _, proc, proc_settings};
-use prosa::core::service::ServiceError;
+use prosa::core::service::{ServiceError, ServiceTable};
+use prosa_utils::config::tracing::TelemetryLevel;
+use prosa_utils::msg::tvf::Tvf;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::env;
+use std::hash::{DefaultHasher, Hash as _, Hasher as _};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
-use tracing::{debug, info, warn};
+use tokio::io::AsyncWriteExt as _;
+use tokio::process::Command;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, RwLock, Semaphore, mpsc, oneshot, watch};
+use tokio_stream::StreamExt as _;
+use tracing::{Instrument as _, Level, Span, debug, info, info_span, warn};
 use url::Url;
 
 use crate::adaptor::OllamaAdaptor;
@@ -28,21 +51,145 @@ pub enum OllamaError {
     /// IO error
     #[error("Ollama error `{0}`")]
     Ollama(#[from] ollama_rs::error::OllamaError),
+    /// Error reply of the Ollama server, with its HTTP status
+    #[error("Ollama server replied {status}: {message}")]
+    Http {
+        status: reqwest::StatusCode,
+        message: String,
+    },
     /// Header value error
     #[error("Invalide header value `{0}`")]
     InvalidHeaderValue(#[from] InvalidHeaderValue),
+    /// The processor is shutting down
+    #[error("Ollama processor shutting down")]
+    Shutdown,
+    /// Header name error
+    #[error("Invalide header name `{0}`")]
+    InvalidHeaderName(#[from] InvalidHeaderName),
+    /// The request didn't complete before the timeout
+    #[error("Ollama request for `{service}` timed out after {timeout:?}")]
+    Timeout { service: String, timeout: Duration },
+    /// The maximum number of requests in flight is reached
+    #[error("Ollama processor overloaded, {max_concurrent_requests} requests already in flight")]
+    Overloaded { max_concurrent_requests: usize },
+    /// The model needed by the request doesn't fit in the free VRAM of any server
+    #[error("Ollama servers over capacity, not enough free VRAM to load the model `{model}`")]
+    OverCapacity { model: String },
+    /// Invalid request, with the field at fault if known
+    #[error("Bad request{}: {reason}", field.as_ref().map(|field| format!(" on field `{field}`")).unwrap_or_default())]
+    BadRequest {
+        field: Option<String>,
+        reason: String,
+    },
+    /// The service is not handled by the processor or the adaptor
+    #[error("The service `{0}` is not handled by the Ollama processor")]
+    UnknownService(String),
+    /// The pull of a model failed
+    #[error("Fail to pull the model `{model}` (last status `{}`): {source}", status.as_deref().unwrap_or("none"))]
+    Pull {
+        model: String,
+        status: Option<String>,
+        source: ollama_rs::error::OllamaError,
+    },
+    /// A TLS file (certificate or key) can't be loaded
+    #[error("Invalid TLS file `{}`: {reason}", path.display())]
+    Tls { path: PathBuf, reason: String },
+    /// Every server of the pool is marked unhealthy, until a probe restores one
+    #[error("Ollama no healthy backend")]
+    NoHealthyBackend,
+    /// The circuit breaker is open, the backend calls are suspended during its cool-down
+    #[error("Ollama circuit open")]
+    CircuitOpen,
+    /// The embeddings batch of the request failed, with an error shared by all its requests
+    #[error("Ollama embeddings batch error: {0}")]
+    Batch(Arc<OllamaError>),
     /// Other error
     #[error("Ollama other error `{0}`")]
     Other(String),
 }
 
+impl OllamaError {
+    /// Create a bad request error on a specific field
+    pub fn bad_field<F, R>(field: F, reason: R) -> Self
+    where
+        F: Into<String>,
+        R: Into<String>,
+    {
+        OllamaError::BadRequest {
+            field: Some(field.into()),
+            reason: reason.into(),
+        }
+    }
+
+    /// Check if the error is a failure of the backend, counted by the circuit breaker: a recoverable error, or no healthy server in the pool.
+    /// The open circuit itself is not a failure, no backend is called
+    fn is_backend_failure(&self) -> bool {
+        match self {
+            OllamaError::NoHealthyBackend => true,
+            OllamaError::CircuitOpen => false,
+            OllamaError::Batch(error) => error.is_backend_failure(),
+            error => error.recoverable(),
+        }
+    }
+
+    /// Coarse category of the error, used as metric attribute
+    fn category(&self) -> &'static str {
+        match self {
+            OllamaError::Ollama(error) if is_transient(error) => "unreachable",
+            OllamaError::Ollama(_) => "backend",
+            OllamaError::Http { status, .. } if is_transient_status(*status) => "server_error",
+            OllamaError::Http { .. } => "backend",
+            OllamaError::InvalidHeaderValue(_) | OllamaError::InvalidHeaderName(_) => "config",
+            OllamaError::Timeout { .. } => "timeout",
+            OllamaError::Overloaded { .. } => "overloaded",
+            OllamaError::OverCapacity { .. } => "over_capacity",
+            OllamaError::Shutdown => "shutdown",
+            OllamaError::BadRequest { .. } => "bad_request",
+            OllamaError::UnknownService(_) => "unknown_service",
+            OllamaError::Pull { .. } => "pull",
+            OllamaError::Tls { .. } => "config",
+            OllamaError::NoHealthyBackend => "no_healthy_backend",
+            OllamaError::CircuitOpen => "circuit_open",
+            OllamaError::Batch(error) => error.category(),
+            OllamaError::Other(_) => "other",
+        }
+    }
+}
+
+impl From<reqwest::Error> for OllamaError {
+    fn from(e: reqwest::Error) -> Self {
+        OllamaError::Ollama(e.into())
+    }
+}
+
 impl From<OllamaError> for ServiceError {
     fn from(e: OllamaError) -> Self {
         match e {
             OllamaError::Ollama(ollama_error) => {
                 ServiceError::UnableToReachService(ollama_error.to_string())
             }
+            e @ OllamaError::Http { .. } => ServiceError::UnableToReachService(e.to_string()),
             OllamaError::InvalidHeaderValue(e) => ServiceError::ProtocolError(e.to_string()),
+            OllamaError::InvalidHeaderName(e) => ServiceError::ProtocolError(e.to_string()),
+            OllamaError::Timeout { service, timeout } => {
+                ServiceError::Timeout(service, timeout.as_millis() as u64)
+            }
+            e @ OllamaError::Overloaded { .. } => ServiceError::UnableToReachService(e.to_string()),
+            e @ OllamaError::OverCapacity { .. } => {
+                ServiceError::UnableToReachService(e.to_string())
+            }
+            e @ OllamaError::Shutdown => ServiceError::UnableToReachService(e.to_string()),
+            e @ OllamaError::BadRequest { .. } => ServiceError::ProtocolError(e.to_string()),
+            e @ OllamaError::UnknownService(_) => ServiceError::UnableToReachService(e.to_string()),
+            e @ OllamaError::Pull { .. } => ServiceError::UnableToReachService(e.to_string()),
+            e @ OllamaError::Tls { .. } => ServiceError::ProtocolError(e.to_string()),
+            e @ OllamaError::NoHealthyBackend => ServiceError::UnableToReachService(e.to_string()),
+            e @ OllamaError::CircuitOpen => ServiceError::UnableToReachService(e.to_string()),
+            // The errors of a batch come from the backend call, so the backend is unreachable
+            OllamaError::Batch(error) => Arc::try_unwrap(error).map_or_else(
+                |error| ServiceError::UnableToReachService(error.to_string()),
+                ServiceError::from,
+            ),
             OllamaError::Other(error) => ServiceError::UnableToReachService(error),
         }
     }
@@ -51,8 +198,22 @@ impl From<OllamaError> for ServiceError {
 impl ProcError for OllamaError {
     fn recoverable(&self) -> bool {
         match self {
-            OllamaError::Ollama(_error) => false,
+            OllamaError::Ollama(error) => is_transient(error),
+            OllamaError::Http { status, .. } => is_transient_status(*status),
             OllamaError::InvalidHeaderValue(_error) => false,
+            OllamaError::InvalidHeaderName(_error) => false,
+            OllamaError::Timeout { .. } => true,
+            OllamaError::Overloaded { .. } => true,
+            OllamaError::OverCapacity { .. } => true,
+            OllamaError::Shutdown => true,
+            OllamaError::BadRequest { .. } => false,
+            OllamaError::UnknownService(_) => false,
+            OllamaError::Pull { source, .. } => is_transient(source),
+            OllamaError::Tls { .. } => false,
+            // Transient: the servers are probed until one recovers, and the circuit closes after its cool-down
+            OllamaError::NoHealthyBackend => true,
+            OllamaError::CircuitOpen => true,
+            OllamaError::Batch(error) => error.recoverable(),
             OllamaError::Other(_error) => false,
         }
     }
@@ -64,15 +225,238 @@ pub struct OllamaProcSettings {
     /// Url of the Ollama API server
     #[serde(default = "OllamaProcSettings::default_url")]
     url: Url,
-    /// List of model that will be used with the processor
+    /// Urls of several Ollama API servers, balanced round-robin. Replace `url` when set
     #[serde(default)]
+    urls: Vec<Url>,
+    /// List of model that will be used with the processor, from the comma-separated `OLLAMA_MODELS` environment variable if not set
+    #[serde(default = "OllamaProcSettings::default_models")]
     models: Vec<String>,
-    /// Allow insecure connections to the library. Only use this if you are pulling from your own library during development.
+    /// Pull the configured models missing on the server. If disabled (e.g. air-gapped deployments), the missing models are only reported
+    #[serde(default = "OllamaProcSettings::default_auto_pull")]
+    auto_pull: bool,
+    /// Interval between two counts of the configured models available on the servers (e.g. to notice a model deleted out of band)
+    #[serde(
+        default = "OllamaProcSettings::default_models_refresh_interval",
+        with = "humantime_serde"
+    )]
+    models_refresh_interval: Duration,
+    /// Interval between two synchronizations of the configured models with the servers (pull of the missing models), only at startup if not set
+    #[serde(default, with = "humantime_serde")]
+    model_sync_interval: Option<Duration>,
+    /// Allow insecure connections to the library, and to a server with an invalid certificate. Only use this if you are pulling from your own library during development.
     #[serde(default)]
     allow_insecure: bool,
     /// Service declared for the processor
     #[serde(default = "OllamaProcSettings::default_services")]
     services: Vec<String>,
+    /// Default model and options applied to the requests of a service
+    #[serde(default)]
+    service_defaults: HashMap<String, ServiceDefaults>,
+    /// Options merged under the request options of every service, after the service defaults
+    #[serde(default)]
+    default_options: Option<ModelOptions>,
+    /// Bearer token sent to the servers without their own credentials (URL credentials or `backend_auth` api key)
+    #[serde(default)]
+    auth_token: Option<AuthToken>,
+    /// Custom headers sent to the server (e.g. for a gateway)
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Credentials and headers of a server of the pool, by `host:port`, taking precedence over the global ones
+    #[serde(default)]
+    backend_auth: HashMap<String, BackendAuth>,
+    /// PEM client certificate, for a server requiring mutual TLS
+    #[serde(default)]
+    client_cert_path: Option<PathBuf>,
+    /// PEM (PKCS #8) private key of the client certificate
+    #[serde(default)]
+    client_key_path: Option<PathBuf>,
+    /// PEM certificate of an additional authority trusted for the server certificate
+    #[serde(default)]
+    ca_cert_path: Option<PathBuf>,
+    /// Timeout to establish the connection to the server, the HTTP client default if not set
+    #[serde(default, with = "humantime_serde")]
+    connect_timeout: Option<Duration>,
+    /// Timeout of each read on the connection to the server, the HTTP client default if not set
+    #[serde(default, with = "humantime_serde")]
+    read_timeout: Option<Duration>,
+    /// Command that print a new bearer token on its standard output, called when the server reject the current token
+    #[serde(default)]
+    auth_refresh_command: Option<String>,
+    /// Log the prompt and response sizes (characters and tokens) of the generation and chat requests, never their content
+    #[serde(default)]
+    log_payload_sizes: bool,
+    /// Level of the structured log emitted when a request is completed (`OFF` to disable it)
+    #[serde(default = "OllamaProcSettings::default_completion_log_level")]
+    completion_log_level: TelemetryLevel,
+    /// Prefix of the metric names, `prosa_ollama` if not set
+    #[serde(default)]
+    metric_prefix: Option<String>,
+    /// Name of the meter of the metrics, `ollama` if not set
+    #[serde(default)]
+    meter_name: Option<String>,
+    /// Policy applied when the backend returns empty or degenerated embedding vectors
+    #[serde(default)]
+    invalid_embeddings: InvalidEmbeddingsPolicy,
+    /// Embed the inputs of a rejected multi-input embeddings request one by one, to return the vectors of the valid inputs with the errors of the others
+    #[serde(default)]
+    partial_embeddings: bool,
+    /// Request metadata keys added as attributes to the token metrics.
+    /// Every distinct value creates a new time series, so only use keys with a small set of values.
+    #[serde(default)]
+    metadata_metric_keys: Vec<String>,
+    /// Idle period after which a light call is sent to the backend to keep its connection warm
+    #[serde(default, with = "humantime_serde")]
+    connection_warmup: Option<Duration>,
+    /// Maximum number of distinct models kept loaded by the backend, the least recently used model is unloaded beyond it
+    #[serde(default)]
+    max_loaded_models: Option<usize>,
+    /// Interval between two refreshes of the models loaded by the servers (`/api/ps`), to reconcile the models tracked for `max_loaded_models` and to estimate the free VRAM for `vram_capacity`. Not refreshed if not set
+    #[serde(default, with = "humantime_serde")]
+    running_models_refresh_interval: Option<Duration>,
+    /// VRAM of each server (bytes), to reject right away a request that needs to load a model that doesn't fit in the free VRAM of any server. No admission control if not set
+    #[serde(default)]
+    vram_capacity: Option<u64>,
+    /// Load duration beyond which a generation or chat response is counted as a cold start (model loaded by the backend)
+    #[serde(
+        default = "OllamaProcSettings::default_cold_start_threshold",
+        with = "humantime_serde"
+    )]
+    cold_start_threshold: Duration,
+    /// Only allow read-only requests (list models, model info), without any model pull at startup
+    #[serde(default)]
+    introspection_only: bool,
+    /// Kinds of requests allowed, all kinds if empty
+    #[serde(default)]
+    allowed_request_kinds: Vec<OllamaRequestKind>,
+    /// Fraction (between 0 and 1) of the generation prompts and responses sampled for offline evaluation
+    #[serde(default)]
+    eval_sampling_rate: f64,
+    /// JSON Lines file where the sampled prompts and responses are appended
+    #[serde(default)]
+    eval_sampling_file: Option<PathBuf>,
+    /// Aliases of models (e.g. `fast`), resolved to their concrete model name
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    /// Model substituted to a missing model of a generation or chat request, to degrade the service instead of erroring
+    #[serde(default)]
+    fallback_model: Option<String>,
+    /// Return the request errors as a response built by the adaptor, instead of a service error
+    #[serde(default)]
+    errors_as_response: bool,
+    /// Policy applied when a generation request has an empty prompt
+    #[serde(default)]
+    empty_prompt_policy: EmptyPromptPolicy,
+    /// Maximum length of the prompt of a generation or chat request, in characters (not tokens). Not limited if not set
+    #[serde(default)]
+    max_prompt_chars: Option<usize>,
+    /// Service where the partial messages of the streamed generations are sent, as requests
+    #[serde(default)]
+    stream_service: Option<String>,
+    /// Timeout of the Ollama calls, none by default
+    #[serde(default, with = "humantime_serde")]
+    request_timeout: Option<Duration>,
+    /// Maximum duration of a streamed generation, cancelled beyond it
+    #[serde(default, with = "humantime_serde")]
+    max_stream_duration: Option<Duration>,
+    /// Maximum size (in bytes) of the text of a streamed generation, truncated and cancelled beyond it
+    #[serde(default)]
+    max_response_bytes: Option<usize>,
+    /// Maximum number of retries of the generation, chat and embeddings calls on recoverable errors
+    #[serde(default)]
+    max_retries: u32,
+    /// Backoff before the first retry, doubled on each new retry
+    #[serde(
+        default = "OllamaProcSettings::default_retry_backoff",
+        with = "humantime_serde"
+    )]
+    retry_backoff: Duration,
+    /// Maximum backoff between two retries
+    #[serde(
+        default = "OllamaProcSettings::default_retry_max_backoff",
+        with = "humantime_serde"
+    )]
+    retry_max_backoff: Duration,
+    /// Maximum number of requests processed concurrently, one at a time if not set
+    #[serde(default)]
+    max_concurrent_requests: Option<usize>,
+    /// Duration over which the concurrency ramps up from a single request to `max_concurrent_requests` after startup, so the backend loads its models before the peak load. No ramp-up if not set
+    #[serde(default, with = "humantime_serde")]
+    concurrency_rampup_duration: Option<Duration>,
+    /// Number of requests waiting for a slot when `max_concurrent_requests` is reached, served by priority. Rejected right away if not set
+    #[serde(default)]
+    priority_queue_capacity: Option<usize>,
+    /// Wait after which a request waiting for a slot gains a priority level, so the low priority requests are not starved
+    #[serde(
+        default = "OllamaProcSettings::default_priority_aging",
+        with = "humantime_serde"
+    )]
+    priority_aging: Duration,
+    /// Keep alive of the generation and chat requests that don't set one, the backend default if not set
+    #[serde(default)]
+    keep_alive: Option<KeepAlive>,
+    /// Format of the generation and chat requests that don't set one: `json`, or a JSON schema of the structured output
+    #[serde(default)]
+    default_format: Option<String>,
+    /// Load the configured models in memory at startup
+    #[serde(default)]
+    preload_models: bool,
+    /// Interval before the second readiness probe of the server, doubled on each new probe
+    #[serde(
+        default = "OllamaProcSettings::default_readiness_probe_interval",
+        with = "humantime_serde"
+    )]
+    readiness_probe_interval: Duration,
+    /// Maximum wait for the server to be ready before declaring the services
+    #[serde(
+        default = "OllamaProcSettings::default_readiness_max_wait",
+        with = "humantime_serde"
+    )]
+    readiness_max_wait: Duration,
+    /// Grace period given to the requests in flight to finish on shutdown
+    #[serde(
+        default = "OllamaProcSettings::default_shutdown_grace_period",
+        with = "humantime_serde"
+    )]
+    shutdown_grace_period: Duration,
+    /// Time to live of the cached model information, no cache if not set
+    #[serde(default, with = "humantime_serde")]
+    model_info_cache_ttl: Option<Duration>,
+    /// Reject the generation, chat and embeddings requests on a model that is not in `models`
+    #[serde(default)]
+    enforce_model_allowlist: bool,
+    /// Number of consecutive backend failures opening the circuit breaker, no circuit breaker if not set
+    #[serde(default)]
+    circuit_breaker_threshold: Option<u32>,
+    /// Period during which the requests are rejected once the circuit breaker is open, before probing the backend again
+    #[serde(
+        default = "OllamaProcSettings::default_circuit_breaker_cooldown",
+        with = "humantime_serde"
+    )]
+    circuit_breaker_cooldown: Duration,
+    /// Number of consecutive failures marking a server of a pool (several `urls`) unhealthy
+    #[serde(default = "OllamaProcSettings::default_backend_unhealthy_threshold")]
+    backend_unhealthy_threshold: u32,
+    /// Interval between two probes of the unhealthy servers of a pool, to restore them once they recover
+    #[serde(
+        default = "OllamaProcSettings::default_backend_probe_interval",
+        with = "humantime_serde"
+    )]
+    backend_probe_interval: Duration,
+    /// Maximum number of generation responses cached, no cache if not set
+    #[serde(default)]
+    generation_cache_capacity: Option<usize>,
+    /// Time to live of the cached generation responses, kept until evicted if not set
+    #[serde(default, with = "humantime_serde")]
+    generation_cache_ttl: Option<Duration>,
+    /// Also cache the generations that are not deterministic (temperature not set to 0)
+    #[serde(default)]
+    generation_cache_nondeterministic: bool,
+    /// Window during which the embeddings requests of a same model are buffered, to be sent in a single backend call. No batching if not set
+    #[serde(default, with = "humantime_serde")]
+    embeddings_batch_window: Option<Duration>,
+    /// Maximum number of inputs of a batched embeddings call
+    #[serde(default = "OllamaProcSettings::default_embeddings_max_batch_size")]
+    embeddings_max_batch_size: usize,
 }
 
 impl OllamaProcSettings {
@@ -84,392 +468,5970 @@ impl OllamaProcSettings {
             .unwrap_or(Url::from_str("http://localhost:11434").unwrap())
     }
 
+    fn default_models() -> Vec<String> {
+        env::var("OLLAMA_MODELS")
+            .map(|models| {
+                models
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|model| !model.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn default_auto_pull() -> bool {
+        true
+    }
+
+    fn default_models_refresh_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+
     fn default_services() -> Vec<String> {
         vec![String::from("ollama")]
     }
 
+    fn default_completion_log_level() -> TelemetryLevel {
+        TelemetryLevel::DEBUG
+    }
+
+    fn default_priority_aging() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn default_cold_start_threshold() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn default_retry_backoff() -> Duration {
+        Duration::from_millis(500)
+    }
+
+    fn default_retry_max_backoff() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn default_readiness_probe_interval() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn default_readiness_max_wait() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_shutdown_grace_period() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_circuit_breaker_cooldown() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn default_backend_unhealthy_threshold() -> u32 {
+        3
+    }
+
+    fn default_backend_probe_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_embeddings_max_batch_size() -> usize {
+        32
+    }
+
+    /// Create a builder of settings, to configure them fluently
+    pub fn builder() -> OllamaProcSettingsBuilder {
+        OllamaProcSettingsBuilder::new()
+    }
+
     /// Create a settings with Ollama URL and processor services names
     pub fn new(url: Url, allow_insecure: bool, services: Vec<String>) -> OllamaProcSettings {
         OllamaProcSettings {
             url,
-            models: Vec::new(),
+            models: Self::default_models(),
             allow_insecure,
             services,
             ..Default::default()
         }
     }
 
+    /// Setter of the urls of several Ollama API servers, balanced round-robin. Replace the url of the settings when not empty
+    pub fn set_urls(&mut self, urls: Vec<Url>) {
+        self.urls = urls;
+    }
+
+    /// Getter of the urls of the Ollama API servers
+    pub fn get_urls(&self) -> &[Url] {
+        if self.urls.is_empty() {
+            std::slice::from_ref(&self.url)
+        } else {
+            &self.urls
+        }
+    }
+
     /// Setter to list model that processor need before starting
     pub fn set_models(&mut self, models: Vec<String>) {
         self.models = models;
     }
 
-    pub fn get_ollama(&self) -> Result<Ollama, OllamaError> {
-        let mut ollama = Ollama::from_url(self.url.clone());
-        let mut header_map = HeaderMap::new();
+    /// Setter to pull the configured models missing on the server, or only report them
+    pub fn set_auto_pull(&mut self, auto_pull: bool) {
+        self.auto_pull = auto_pull;
+    }
 
-        if let Some(password) = self.url.password() {
-            if self.url.username().is_empty() {
-                header_map.insert(
-                    "Authorization",
-                    HeaderValue::from_str(format!("Bearer {}", password).as_str())?,
-                );
-            } else {
-                header_map.insert(
-                    "Authorization",
-                    HeaderValue::from_str(
-                        format!(
-                            "Basic {}",
-                            STANDARD.encode(format!("{}:{}", self.url.username(), password))
-                        )
-                        .as_str(),
-                    )?,
-                );
-            }
-        }
+    /// Setter of the interval between two counts of the configured models available on the servers
+    pub fn set_models_refresh_interval(&mut self, models_refresh_interval: Duration) {
+        self.models_refresh_interval = models_refresh_interval;
+    }
 
-        if !header_map.is_empty() {
-            ollama.set_headers(Some(header_map));
-        }
+    /// Setter of the interval between two synchronizations of the configured models with the servers, only at startup if not set
+    pub fn set_model_sync_interval(&mut self, model_sync_interval: Option<Duration>) {
+        self.model_sync_interval = model_sync_interval;
+    }
 
-        Ok(ollama)
+    /// Setter of the default model and options of a service
+    pub fn set_service_defaults(&mut self, service: String, defaults: ServiceDefaults) {
+        self.service_defaults.insert(service, defaults);
     }
-}
 
-#[proc_settings]
-impl Default for OllamaProcSettings {
-    fn default() -> Self {
-        OllamaProcSettings {
-            url: Self::default_url(),
-            models: Vec::default(),
-            allow_insecure: false,
-            services: Self::default_services(),
+    /// Getter of the default model and options of a service
+    pub fn get_service_defaults(&self, service: &str) -> Option<&ServiceDefaults> {
+        self.service_defaults.get(service)
+    }
+
+    /// Apply the defaults of a service, then the default options of every service, to an Ollama request
+    pub(crate) fn apply_defaults(
+        &self,
+        service: &str,
+        request: &mut OllamaRequest<'_>,
+    ) -> Result<(), OllamaError> {
+        if let Some(defaults) = self.get_service_defaults(service) {
+            defaults.apply(request)?;
         }
+        if let Some(default_options) = &self.default_options
+            && let Some(options) = request.get_options_mut()
+        {
+            *options = merge_options(default_options, options.take())?;
+        }
+        Ok(())
     }
-}
 
-/// Ollama requests
-pub enum OllamaRequest<'a> {
-    ListLocalModels,
-    ModelInfo(String),
-    GenerateRequest(Box<GenerationRequest<'a>>),
-    GenerateEmbeddingsRequest(Box<GenerateEmbeddingsRequest>),
-}
+    /// Setter of the default options of every service, applied under the request options and the service defaults
+    pub fn set_default_options(&mut self, options: Option<ModelOptions>) {
+        self.default_options = options;
+    }
 
-/// Ollama responses
-pub enum OllamaResponse {
-    LocalModels(Vec<LocalModel>),
-    ModelInfo(ModelInfo),
-    GenerateResponse(Box<GenerationResponse>),
-    GenerateEmbeddingsResponse(Box<GenerateEmbeddingsResponse>),
-    ChatMessageResponse(Box<ChatMessageResponse>),
-}
+    /// Setter of the bearer token sent to the servers without their own credentials
+    pub fn set_auth_token(&mut self, token: Option<String>) {
+        self.auth_token = token.map(AuthToken);
+    }
 
-impl From<GenerationResponse> for OllamaResponse {
-    fn from(response: GenerationResponse) -> Self {
-        OllamaResponse::GenerateResponse(Box::new(response))
+    /// Setter of the custom headers sent to the server
+    pub fn set_headers(&mut self, headers: HashMap<String, String>) {
+        self.headers = headers;
     }
-}
 
-impl From<GenerateEmbeddingsResponse> for OllamaResponse {
-    fn from(response: GenerateEmbeddingsResponse) -> Self {
-        OllamaResponse::GenerateEmbeddingsResponse(Box::new(response))
+    /// Setter of the credentials and headers of a server of the pool (`host:port`), taking precedence over the global ones
+    pub fn set_backend_auth(&mut self, host: String, auth: BackendAuth) {
+        self.backend_auth.insert(host, auth);
     }
-}
 
-impl From<ChatMessageResponse> for OllamaResponse {
-    fn from(response: ChatMessageResponse) -> Self {
-        OllamaResponse::ChatMessageResponse(Box::new(response))
+    /// Setter of the client certificate and its key for a server requiring mutual TLS, and of an additional trusted authority
+    pub fn set_client_tls(
+        &mut self,
+        cert_path: Option<PathBuf>,
+        key_path: Option<PathBuf>,
+        ca_cert_path: Option<PathBuf>,
+    ) {
+        self.client_cert_path = cert_path;
+        self.client_key_path = key_path;
+        self.ca_cert_path = ca_cert_path;
     }
-}
 
-#[proc(settings = OllamaProcSettings)]
-pub struct OllamaProc {}
+    /// Setter of the connect and read timeouts of the HTTP client
+    pub fn set_http_timeouts(
+        &mut self,
+        connect_timeout: Option<Duration>,
+        read_timeout: Option<Duration>,
+    ) {
+        self.connect_timeout = connect_timeout;
+        self.read_timeout = read_timeout;
+    }
 
-// You must implement the trait Proc to define your processing
-#[proc]
-impl<A> Proc<A> for OllamaProc
-where
-    A: Default + Adaptor + OllamaAdaptor<M> + std::marker::Send + std::marker::Sync,
-{
-    async fn internal_run(
+    /// Setter of the command used to get a new bearer token when the current one expired
+    pub fn set_auth_refresh_command(&mut self, command: Option<String>) {
+        self.auth_refresh_command = command;
+    }
+
+    /// Setter to log the prompt and response sizes of the generation and chat requests
+    pub fn set_log_payload_sizes(&mut self, log_payload_sizes: bool) {
+        self.log_payload_sizes = log_payload_sizes;
+    }
+
+    /// Setter of the level of the structured log emitted when a request is completed
+    pub fn set_completion_log_level(&mut self, level: TelemetryLevel) {
+        self.completion_log_level = level;
+    }
+
+    /// Setter of the prefix of the metric names and of the meter name, to run several processors with distinct metric namespaces
+    pub fn set_metric_namespace(
         &mut self,
-        _name: String,
-    ) -> Result<(), Box<dyn ProcError + Send + Sync>> {
-        let ollama = self.settings.get_ollama()?;
+        metric_prefix: Option<String>,
+        meter_name: Option<String>,
+    ) {
+        self.metric_prefix = metric_prefix;
+        self.meter_name = meter_name;
+    }
 
-        // List of models
-        let local_models = ollama
-            .list_local_models()
-            .await
-            .map_err(OllamaError::Ollama)?;
+    /// Getter of the prefix of the metric names
+    pub fn get_metric_prefix(&self) -> &str {
+        self.metric_prefix.as_deref().unwrap_or("prosa_ollama")
+    }
 
-        // Pull missing models
-        'model: for model in &self.settings.models {
-            for local_model in &local_models {
-                if &local_model.name == model {
-                    continue 'model;
-                }
+    /// Getter of the name of the meter of the metrics
+    fn get_meter_name(&self) -> &'static str {
+        // The meter needs a static name: each distinct name is leaked once, and reused by the next processor starts
+        static METER_NAMES: std::sync::Mutex<Vec<&'static str>> = std::sync::Mutex::new(Vec::new());
+
+        let Some(meter_name) = &self.meter_name else {
+            return "ollama";
+        };
+        let mut meter_names = METER_NAMES
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        match meter_names.iter().find(|name| **name == meter_name) {
+            Some(name) => name,
+            None => {
+                let name: &'static str = Box::leak(meter_name.clone().into_boxed_str());
+                meter_names.push(name);
+                name
             }
+        }
+    }
 
-            // Pull model
-            let pull_model_status = ollama
-                .pull_model(model.to_string(), self.settings.allow_insecure)
-                .await
-                .map_err(OllamaError::Ollama)?;
-            info!("Pulled the model {}: {:?}", model, pull_model_status);
+    /// Keep the running value of the settings only applied at startup, and return the names of those changed by a reload
+    fn retain_startup_settings(&mut self, running: &OllamaProcSettings) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.max_concurrent_requests != running.max_concurrent_requests {
+            changed.push("max_concurrent_requests");
+            self.max_concurrent_requests = running.max_concurrent_requests;
+        }
+        if self.embeddings_batch_window != running.embeddings_batch_window {
+            changed.push("embeddings_batch_window");
+            self.embeddings_batch_window = running.embeddings_batch_window;
+        }
+        if self.embeddings_max_batch_size != running.embeddings_max_batch_size {
+            changed.push("embeddings_max_batch_size");
+            self.embeddings_max_batch_size = running.embeddings_max_batch_size;
         }
+        if self.model_sync_interval != running.model_sync_interval {
+            changed.push("model_sync_interval");
+            self.model_sync_interval = running.model_sync_interval;
+        }
+        if self.running_models_refresh_interval != running.running_models_refresh_interval {
+            changed.push("running_models_refresh_interval");
+            self.running_models_refresh_interval = running.running_models_refresh_interval;
+        }
+        if self.metric_prefix != running.metric_prefix {
+            changed.push("metric_prefix");
+            self.metric_prefix.clone_from(&running.metric_prefix);
+        }
+        if self.meter_name != running.meter_name {
+            changed.push("meter_name");
+            self.meter_name.clone_from(&running.meter_name);
+        }
+        changed
+    }
 
-        // Initiate an adaptor for the Ollama processor
-        let mut adaptor = A::new(self)?;
+    /// Setter of the policy applied when the backend returns invalid embedding vectors
+    pub fn set_invalid_embeddings(&mut self, policy: InvalidEmbeddingsPolicy) {
+        self.invalid_embeddings = policy;
+    }
 
-        // Declare the processor
-        self.proc.add_proc().await?;
+    /// Setter to embed the inputs of a rejected multi-input embeddings request one by one, instead of failing the whole request
+    pub fn set_partial_embeddings(&mut self, partial_embeddings: bool) {
+        self.partial_embeddings = partial_embeddings;
+    }
 
-        // Add all service to listen
-        self.proc
-            .add_service_proc(self.settings.services.clone())
-            .await?;
+    /// Setter of the request metadata keys added as attributes to the token metrics
+    pub fn set_metadata_metric_keys(&mut self, keys: Vec<String>) {
+        self.metadata_metric_keys = keys;
+    }
 
-        // Meter to log AI statistics
-        let meter = self.get_proc_param().meter("ollama");
-        let observable_prompt_call_counter = meter
-            .u64_counter("prosa_ollama_prompt_token_count")
-            .with_description("Counter of prompt tokens")
-            .build();
-        let observable_gen_call_counter = meter
-            .u64_counter("prosa_ollama_gen_token_count")
-            .with_description("Counter of generated tokens")
-            .build();
-        let observable_token_histogram = meter
-            .u64_histogram("prosa_ollama_token_histogram")
-            .with_description("Histogram generations")
-            .build();
+    /// Setter of the idle period after which the connection to the backend is warmed up
+    pub fn set_connection_warmup(&mut self, period: Option<Duration>) {
+        self.connection_warmup = period;
+    }
 
-        loop {
-            if let Some(msg) = self.internal_rx_queue.recv().await {
-                match msg {
-                    InternalMsg::Request(mut msg) => {
-                        if let Some(data) = msg.take_data() {
-                            let enter_span = msg.enter_span();
-                            let ollama_request = adaptor.process_request(msg.get_service(), data);
-                            match ollama_request {
-                                Ok(OllamaRequest::ListLocalModels) => {
-                                    debug!("List local models");
-                                    match ollama.list_local_models().await {
-                                        Ok(local_model) => {
-                                            match adaptor.process_ollama_response(
-                                                OllamaResponse::LocalModels(local_model),
-                                            ) {
-                                                Ok(resp) => {
-                                                    drop(enter_span);
-                                                    msg.return_to_sender(resp).await?
-                                                }
-                                                Err(e) => {
-                                                    drop(enter_span);
-                                                    msg.return_error_to_sender(None, e.into())
-                                                        .await?
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            drop(enter_span);
-                                            msg.return_error_to_sender(
-                                                None,
-                                                OllamaError::Ollama(e).into(),
-                                            )
-                                            .await?
-                                        }
-                                    }
-                                }
-                                Ok(OllamaRequest::ModelInfo(model_name)) => {
-                                    debug!("Model info {model_name}");
-                                    match ollama.show_model_info(model_name).await {
-                                        Ok(model_info) => {
-                                            match adaptor.process_ollama_response(
-                                                OllamaResponse::ModelInfo(model_info),
-                                            ) {
-                                                Ok(resp) => {
-                                                    drop(enter_span);
-                                                    msg.return_to_sender(resp).await?
-                                                }
-                                                Err(e) => {
-                                                    drop(enter_span);
-                                                    msg.return_error_to_sender(None, e.into())
-                                                        .await?
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            drop(enter_span);
-                                            msg.return_error_to_sender(
-                                                None,
-                                                OllamaError::Ollama(e).into(),
-                                            )
-                                            .await?
-                                        }
-                                    }
-                                }
-                                Ok(OllamaRequest::GenerateRequest(request)) => {
-                                    debug!("Generate");
-                                    match ollama.generate(*request).await {
-                                        Ok(response) => {
-                                            if let Some(prompt_eval_count) =
-                                                response.prompt_eval_count
-                                            {
-                                                observable_prompt_call_counter.add(
-                                                    prompt_eval_count,
-                                                    &[
-                                                        KeyValue::new("type", "gen"),
-                                                        KeyValue::new(
-                                                            "model",
-                                                            response.model.clone(),
-                                                        ),
-                                                    ],
-                                                );
-                                            }
-                                            if let Some(eval_count) = response.eval_count {
-                                                observable_gen_call_counter.add(
-                                                    eval_count,
-                                                    &[
-                                                        KeyValue::new("type", "gen"),
-                                                        KeyValue::new(
-                                                            "model",
-                                                            response.model.clone(),
-                                                        ),
-                                                    ],
-                                                );
-                                            }
-                                            if let Some(total_duration) = response.total_duration {
-                                                observable_token_histogram.record(
-                                                    total_duration / 1000000,
-                                                    &[
-                                                        KeyValue::new("type", "total"),
-                                                        KeyValue::new(
-                                                            "model",
-                                                            response.model.clone(),
-                                                        ),
-                                                    ],
-                                                );
-                                            }
-                                            if let Some(load_duration) = response.load_duration {
-                                                observable_token_histogram.record(
-                                                    load_duration / 1000000,
-                                                    &[
-                                                        KeyValue::new("type", "load"),
-                                                        KeyValue::new(
-                                                            "model",
-                                                            response.model.clone(),
-                                                        ),
-                                                    ],
-                                                );
-                                            }
-                                            if let Some(prompt_eval_duration) =
-                                                response.prompt_eval_duration
-                                            {
-                                                observable_token_histogram.record(
-                                                    prompt_eval_duration / 1000000,
-                                                    &[
-                                                        KeyValue::new("type", "prompt"),
-                                                        KeyValue::new(
-                                                            "model",
-                                                            response.model.clone(),
-                                                        ),
-                                                    ],
-                                                );
-                                            }
-                                            if let Some(eval_duration) = response.eval_duration {
-                                                observable_token_histogram.record(
-                                                    eval_duration / 1000000,
-                                                    &[
-                                                        KeyValue::new("type", "eval"),
-                                                        KeyValue::new(
-                                                            "model",
-                                                            response.model.clone(),
-                                                        ),
-                                                    ],
-                                                );
-                                            }
+    /// Setter of the maximum number of distinct models kept loaded by the backend
+    pub fn set_max_loaded_models(&mut self, max_loaded_models: Option<usize>) {
+        self.max_loaded_models = max_loaded_models;
+    }
 
-                                            match adaptor.process_ollama_response(response.into()) {
-                                                Ok(resp) => {
-                                                    drop(enter_span);
-                                                    msg.return_to_sender(resp).await?
-                                                }
-                                                Err(e) => {
-                                                    drop(enter_span);
-                                                    msg.return_error_to_sender(None, e.into())
-                                                        .await?
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            drop(enter_span);
-                                            msg.return_error_to_sender(
-                                                None,
-                                                OllamaError::Ollama(e).into(),
-                                            )
-                                            .await?
-                                        }
-                                    }
-                                }
-                                Ok(OllamaRequest::GenerateEmbeddingsRequest(
-                                    embeddings_request,
-                                )) => {
-                                    debug!("Generate embeddings");
-                                    match ollama.generate_embeddings(*embeddings_request).await {
-                                        Ok(response) => {
-                                            observable_gen_call_counter.add(
-                                                response.embeddings.iter().len() as u64,
-                                                &[KeyValue::new("type", "embed")],
-                                            );
-
-                                            match adaptor.process_ollama_response(response.into()) {
-                                                Ok(resp) => {
-                                                    drop(enter_span);
-                                                    msg.return_to_sender(resp).await?
-                                                }
-                                                Err(e) => {
-                                                    drop(enter_span);
-                                                    msg.return_error_to_sender(None, e.into())
-                                                        .await?
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            drop(enter_span);
-                                            msg.return_error_to_sender(
-                                                None,
-                                                OllamaError::Ollama(e).into(),
-                                            )
-                                            .await?
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("Request error: {e}");
-                                    drop(enter_span);
-                                    msg.return_error_to_sender(None, e.into()).await?
-                                }
-                            }
-                        }
-                    }
-                    InternalMsg::Response(msg) => panic!(
-                        "The Ollama processor {} receive a response {:?}",
-                        self.get_proc_id(),
-                        msg
-                    ),
-                    InternalMsg::Error(err) => panic!(
-                        "The Ollama processor {} receive an error {:?}",
-                        self.get_proc_id(),
-                        err
-                    ),
-                    InternalMsg::Command(_) => todo!(),
-                    InternalMsg::Config => todo!(),
-                    InternalMsg::Service(table) => self.service = table,
-                    InternalMsg::Shutdown => {
-                        adaptor.terminate();
-                        self.proc.remove_proc(None).await?;
+    /// Setter of the interval between two refreshes of the models loaded by the servers, not refreshed if not set
+    pub fn set_running_models_refresh_interval(&mut self, refresh_interval: Option<Duration>) {
+        self.running_models_refresh_interval = refresh_interval;
+    }
+
+    /// Setter of the VRAM of each server (bytes), to reject the requests that need to load a model that doesn't fit
+    pub fn set_vram_capacity(&mut self, vram_capacity: Option<u64>) {
+        self.vram_capacity = vram_capacity;
+    }
+
+    /// Setter of the load duration beyond which a response is counted as a cold start
+    pub fn set_cold_start_threshold(&mut self, cold_start_threshold: Duration) {
+        self.cold_start_threshold = cold_start_threshold;
+    }
+
+    /// Setter of the read-only introspection mode
+    pub fn set_introspection_only(&mut self, introspection_only: bool) {
+        self.introspection_only = introspection_only;
+    }
+
+    /// Setter of the kinds of requests allowed, all kinds if empty
+    pub fn set_allowed_request_kinds(&mut self, allowed_request_kinds: Vec<OllamaRequestKind>) {
+        self.allowed_request_kinds = allowed_request_kinds;
+    }
+
+    /// Setter of the evaluation sampling rate, and of the file where the samples are written
+    pub fn set_eval_sampling(&mut self, rate: f64, file: Option<PathBuf>) {
+        self.eval_sampling_rate = rate;
+        self.eval_sampling_file = file;
+    }
+
+    /// Setter of the model aliases
+    pub fn set_aliases(&mut self, aliases: HashMap<String, String>) {
+        self.aliases = aliases;
+    }
+
+    /// Setter of the model substituted to a missing model of a generation or chat request
+    pub fn set_fallback_model(&mut self, fallback_model: Option<String>) {
+        self.fallback_model = fallback_model;
+    }
+
+    /// Setter to return the request errors as a response built by the adaptor
+    pub fn set_errors_as_response(&mut self, errors_as_response: bool) {
+        self.errors_as_response = errors_as_response;
+    }
+
+    /// Setter of the policy applied when a generation request has an empty prompt
+    pub fn set_empty_prompt_policy(&mut self, policy: EmptyPromptPolicy) {
+        self.empty_prompt_policy = policy;
+    }
+
+    /// Setter of the maximum length of the prompt of a generation or chat request, in characters
+    pub fn set_max_prompt_chars(&mut self, max_prompt_chars: Option<usize>) {
+        self.max_prompt_chars = max_prompt_chars;
+    }
+
+    /// Setter of the service where the partial messages of the streamed generations are sent
+    pub fn set_stream_service(&mut self, stream_service: Option<String>) {
+        self.stream_service = stream_service;
+    }
+
+    /// Setter of the timeout of the Ollama calls
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Setter of the maximum duration of a streamed generation
+    pub fn set_max_stream_duration(&mut self, max_stream_duration: Option<Duration>) {
+        self.max_stream_duration = max_stream_duration;
+    }
+
+    /// Setter of the maximum size (in bytes) of the text of a streamed generation
+    pub fn set_max_response_bytes(&mut self, max_response_bytes: Option<usize>) {
+        self.max_response_bytes = max_response_bytes;
+    }
+
+    /// Setter of the retries of the generation, chat and embeddings calls on recoverable errors
+    pub fn set_retries(&mut self, max_retries: u32, retry_backoff: Duration) {
+        self.max_retries = max_retries;
+        self.retry_backoff = retry_backoff;
+    }
+
+    /// Setter of the maximum backoff between two retries
+    pub fn set_retry_max_backoff(&mut self, retry_max_backoff: Duration) {
+        self.retry_max_backoff = retry_max_backoff;
+    }
+
+    /// Backoff before a retry (starting from 1), doubled on each new retry up to the `retry_max_backoff`
+    fn get_retry_backoff(&self, attempt: u32) -> Duration {
+        2u32.checked_pow(attempt.saturating_sub(1))
+            .and_then(|factor| self.retry_backoff.checked_mul(factor))
+            .unwrap_or(self.retry_max_backoff)
+            .min(self.retry_max_backoff)
+    }
+
+    /// Setter of the maximum number of requests processed concurrently
+    pub fn set_max_concurrent_requests(&mut self, max_concurrent_requests: Option<usize>) {
+        self.max_concurrent_requests = max_concurrent_requests;
+    }
+
+    /// Setter of the duration over which the concurrency ramps up to `max_concurrent_requests` after startup
+    pub fn set_concurrency_rampup_duration(&mut self, rampup_duration: Option<Duration>) {
+        self.concurrency_rampup_duration = rampup_duration;
+    }
+
+    /// Setter of the priority queue of the requests waiting for a slot: capacity, and wait after which a request gains a priority level
+    pub fn set_priority_queue(&mut self, capacity: Option<usize>, aging: Duration) {
+        self.priority_queue_capacity = capacity;
+        self.priority_aging = aging;
+    }
+
+    /// Setter of the keep alive of the generation and chat requests that don't set one
+    pub fn set_keep_alive(&mut self, keep_alive: Option<KeepAlive>) {
+        self.keep_alive = keep_alive;
+    }
+
+    /// Setter of the format of the generation and chat requests that don't set one: `json`, or a JSON schema
+    pub fn set_default_format(&mut self, default_format: Option<String>) {
+        self.default_format = default_format;
+    }
+
+    /// Getter of the format of the generation and chat requests that don't set one, in error if it's neither `json` nor a JSON schema
+    pub fn get_default_format(&self) -> Result<Option<FormatType>, OllamaError> {
+        match self.default_format.as_deref() {
+            Some("json") => Ok(Some(FormatType::Json)),
+            Some(schema) => serde_json::from_str(schema).map(Some).map_err(|e| {
+                OllamaError::Other(format!(
+                    "The default format must be `json` or a JSON schema: {e}"
+                ))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Setter to load the configured models in memory at startup
+    pub fn set_preload_models(&mut self, preload_models: bool) {
+        self.preload_models = preload_models;
+    }
+
+    /// Setter of the readiness probe of the server, with the interval before the second probe and the maximum wait
+    pub fn set_readiness_probe(&mut self, probe_interval: Duration, max_wait: Duration) {
+        self.readiness_probe_interval = probe_interval;
+        self.readiness_max_wait = max_wait;
+    }
+
+    /// Setter of the grace period given to the requests in flight to finish on shutdown
+    pub fn set_shutdown_grace_period(&mut self, grace_period: Duration) {
+        self.shutdown_grace_period = grace_period;
+    }
+
+    /// Setter of the time to live of the cached model information
+    pub fn set_model_info_cache_ttl(&mut self, ttl: Option<Duration>) {
+        self.model_info_cache_ttl = ttl;
+    }
+
+    /// Setter of the enforcement of the `models` list on the generation, chat and embeddings requests
+    pub fn set_enforce_model_allowlist(&mut self, enforce_model_allowlist: bool) {
+        self.enforce_model_allowlist = enforce_model_allowlist;
+    }
+
+    /// Setter of the circuit breaker: number of consecutive backend failures opening it, and cool-down before probing the backend again
+    pub fn set_circuit_breaker(&mut self, threshold: Option<u32>, cooldown: Duration) {
+        self.circuit_breaker_threshold = threshold;
+        self.circuit_breaker_cooldown = cooldown;
+    }
+
+    /// Setter of the health of the servers of a pool: number of consecutive failures marking a server unhealthy, and interval between two probes of the unhealthy servers
+    pub fn set_backend_health(&mut self, unhealthy_threshold: u32, probe_interval: Duration) {
+        self.backend_unhealthy_threshold = unhealthy_threshold;
+        self.backend_probe_interval = probe_interval;
+    }
+
+    /// Setter of the cache of the generation responses: capacity, time to live, and caching of the non deterministic generations
+    pub fn set_generation_cache(
+        &mut self,
+        capacity: Option<usize>,
+        ttl: Option<Duration>,
+        nondeterministic: bool,
+    ) {
+        self.generation_cache_capacity = capacity;
+        self.generation_cache_ttl = ttl;
+        self.generation_cache_nondeterministic = nondeterministic;
+    }
+
+    /// Setter of the batching of the embeddings requests: buffering window, and maximum number of inputs of a batched call
+    pub fn set_embeddings_batching(&mut self, window: Option<Duration>, max_batch_size: usize) {
+        self.embeddings_batch_window = window;
+        self.embeddings_max_batch_size = max_batch_size;
+    }
+
+    /// Resolve a model alias to its concrete model name. Unknown names are returned as is
+    pub fn resolve_model<'s>(&'s self, model: &'s str) -> &'s str {
+        self.aliases.get(model).map(String::as_str).unwrap_or(model)
+    }
+
+    /// Method to know if a model is one of the configured `models` (or of their aliases). A model without tag is the `latest` one
+    pub fn is_configured_model(&self, model: &str) -> bool {
+        let model = model.strip_suffix(":latest").unwrap_or(model);
+        self.models.iter().any(|configured_model| {
+            let configured_model = self.resolve_model(configured_model);
+            configured_model
+                .strip_suffix(":latest")
+                .unwrap_or(configured_model)
+                == model
+        })
+    }
+
+    /// Fetch a new bearer token, by running the `auth_refresh_command` if set or by reading the `OLLAMA_API_KEY` environment variable otherwise.
+    /// The command is killed if it runs longer than [`AUTH_REFRESH_TIMEOUT`]
+    pub async fn refresh_auth_token(&self) -> Result<Option<String>, OllamaError> {
+        if let Some(command) = &self.auth_refresh_command {
+            let output = tokio::time::timeout(
+                AUTH_REFRESH_TIMEOUT,
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .kill_on_drop(true)
+                    .output(),
+            )
+            .await
+            .map_err(|_| {
+                OllamaError::Other(format!(
+                    "The auth refresh command did not finish within {AUTH_REFRESH_TIMEOUT:?}"
+                ))
+            })?
+            .map_err(|e| OllamaError::Other(format!("Can't run the auth refresh command: {e}")))?;
+            if !output.status.success() {
+                return Err(OllamaError::Other(format!(
+                    "The auth refresh command failed with {}",
+                    output.status
+                )));
+            }
+
+            let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Ok((!token.is_empty()).then_some(token))
+        } else {
+            Ok(env::var("OLLAMA_API_KEY")
+                .ok()
+                .filter(|token| !token.is_empty()))
+        }
+    }
+
+    /// Getter of the urls of the Ollama API servers, without their credentials
+    pub fn get_redacted_urls(&self) -> Vec<Url> {
+        self.get_urls()
+            .iter()
+            .map(|url| {
+                let mut url = url.clone();
+                let _ = url.set_username("");
+                let _ = url.set_password(None);
+                url
+            })
+            .collect()
+    }
+
+    /// Getter of the authentication to the servers: `none`, or the `bearer`, `basic` (URL credentials) and `mtls` modes joined by `+`
+    pub fn get_auth_mode(&self) -> String {
+        let mut modes = Vec::new();
+        if self.auth_token.is_some()
+            || self
+                .backend_auth
+                .values()
+                .any(|backend_auth| backend_auth.api_key.is_some())
+        {
+            modes.push("bearer");
+        }
+        if self.get_urls().iter().any(|url| !url.username().is_empty()) {
+            modes.push("basic");
+        }
+        if self.client_cert_path.is_some() {
+            modes.push("mtls");
+        }
+
+        if modes.is_empty() {
+            "none".to_string()
+        } else {
+            modes.join("+")
+        }
+    }
+
+    /// Get the pool of Ollama clients, one per server url
+    pub fn get_ollama(&self) -> Result<OllamaPool, OllamaError> {
+        self.build_ollama(self.auth_token.as_ref().map(|token| token.0.as_str()))
+    }
+
+    /// Get the pool of Ollama clients, authenticated with a bearer token. The servers with their own credentials keep them
+    pub fn get_ollama_with_token(&self, token: &str) -> Result<OllamaPool, OllamaError> {
+        self.build_ollama(Some(token))
+    }
+
+    /// Load the client identity and the trusted authority, for mutual TLS
+    fn load_tls(
+        &self,
+    ) -> Result<(Option<reqwest::Identity>, Option<reqwest::Certificate>), OllamaError> {
+        fn read(path: &PathBuf) -> Result<Vec<u8>, OllamaError> {
+            std::fs::read(path).map_err(|e| OllamaError::Tls {
+                path: path.clone(),
+                reason: e.to_string(),
+            })
+        }
+
+        let identity = match (&self.client_cert_path, &self.client_key_path) {
+            (Some(cert_path), Some(key_path)) => Some(
+                reqwest::Identity::from_pkcs8_pem(&read(cert_path)?, &read(key_path)?).map_err(
+                    |e| OllamaError::Tls {
+                        path: cert_path.clone(),
+                        reason: e.to_string(),
+                    },
+                )?,
+            ),
+            (Some(path), None) | (None, Some(path)) => {
+                return Err(OllamaError::Tls {
+                    path: path.clone(),
+                    reason: "the client certificate and its key must be set together".to_string(),
+                });
+            }
+            (None, None) => None,
+        };
+        let ca_cert = self
+            .ca_cert_path
+            .as_ref()
+            .map(|path| {
+                reqwest::Certificate::from_pem(&read(path)?).map_err(|e| OllamaError::Tls {
+                    path: path.clone(),
+                    reason: e.to_string(),
+                })
+            })
+            .transpose()?;
+
+        Ok((identity, ca_cert))
+    }
+
+    fn build_ollama(&self, token: Option<&str>) -> Result<OllamaPool, OllamaError> {
+        let tls = self.load_tls()?;
+        let retired = Arc::new(watch::Sender::new(false));
+        let clients = self
+            .get_urls()
+            .iter()
+            .map(|url| self.build_client(url, token, &tls, &retired))
+            .collect::<Result<_, _>>()?;
+        Ok(OllamaPool {
+            clients,
+            next: Arc::new(AtomicUsize::new(0)),
+            generation: 0,
+            retired,
+        })
+    }
+
+    fn build_client(
+        &self,
+        url: &Url,
+        token: Option<&str>,
+        (identity, ca_cert): &(Option<reqwest::Identity>, Option<reqwest::Certificate>),
+        retired: &Arc<watch::Sender<bool>>,
+    ) -> Result<OllamaClient, OllamaError> {
+        let host = format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        let backend_auth = self.backend_auth.get(&host);
+        let mut header_map = HeaderMap::new();
+        for (name, value) in self.headers.iter().chain(
+            backend_auth
+                .into_iter()
+                .flat_map(|backend_auth| &backend_auth.headers),
+        ) {
+            header_map.insert(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+        }
+
+        // The authentication takes precedence over a custom `Authorization` header,
+        // and the credentials of the server (api key, then URL credentials) over the global token
+        if let Some(AuthToken(api_key)) =
+            backend_auth.and_then(|backend_auth| backend_auth.api_key.as_ref())
+        {
+            header_map.insert(
+                "Authorization",
+                HeaderValue::from_str(format!("Bearer {}", api_key).as_str())?,
+            );
+        } else if let Some(password) = url.password() {
+            if url.username().is_empty() {
+                header_map.insert(
+                    "Authorization",
+                    HeaderValue::from_str(format!("Bearer {}", password).as_str())?,
+                );
+            } else {
+                header_map.insert(
+                    "Authorization",
+                    HeaderValue::from_str(
+                        format!(
+                            "Basic {}",
+                            STANDARD.encode(format!("{}:{}", url.username(), password))
+                        )
+                        .as_str(),
+                    )?,
+                );
+            }
+        } else if let Some(token) = token {
+            header_map.insert(
+                "Authorization",
+                HeaderValue::from_str(format!("Bearer {}", token).as_str())?,
+            );
+        }
+
+        // The HTTP client is shared with ollama-rs, so the credentials and the connection pool are the same for all the endpoints
+        let mut http = reqwest::Client::builder().default_headers(header_map);
+        if let Some(connect_timeout) = self.connect_timeout {
+            http = http.connect_timeout(connect_timeout);
+        }
+        if let Some(read_timeout) = self.read_timeout {
+            http = http.read_timeout(read_timeout);
+        }
+        if let Some(identity) = identity {
+            http = http.identity(identity.clone());
+        }
+        if let Some(ca_cert) = ca_cert {
+            http = http.add_root_certificate(ca_cert.clone());
+        }
+        if self.allow_insecure {
+            http = http.danger_accept_invalid_certs(true);
+        }
+        let http = http.build().map_err(ollama_rs::error::OllamaError::from)?;
+        let ollama = Ollama::builder()
+            .url(url.clone())
+            .reqwest_client(http.clone())
+            .build();
+
+        Ok(OllamaClient {
+            ollama,
+            http,
+            host,
+            health: Arc::default(),
+            generation: 0,
+            retired: retired.clone(),
+        })
+    }
+}
+
+/// Command logging the status of every server of the pool, as structured JSON (health, calls in flight, loaded models, circuit state).
+/// The command has no reply: to get the snapshot in a reply, send an [`OllamaRequest::Status`] request and read the `backends` of its response
+pub const BACKEND_STATUS_COMMAND: &str = "backend_status";
+
+/// Maximum duration of the `auth_refresh_command`
+pub const AUTH_REFRESH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pool of Ollama clients, one per server, selected round-robin
+#[derive(Debug, Clone)]
+pub struct OllamaPool {
+    clients: Arc<[OllamaClient]>,
+    next: Arc<AtomicUsize>,
+    /// Number of times the pool was replaced, to know if a client is still current
+    generation: u64,
+    /// Signal to abort the calls in flight on the clients, once the pool is replaced by a reload and drained
+    retired: Arc<watch::Sender<bool>>,
+}
+
+impl OllamaPool {
+    /// Getter of the clients of all the servers
+    pub fn clients(&self) -> &[OllamaClient] {
+        &self.clients
+    }
+
+    /// Select the client of the next healthy server, round-robin. Return `None` if all the servers are unhealthy.
+    /// The single server of a pool is always selected, the circuit breaker handles its failures
+    pub fn select(&self) -> Option<&OllamaClient> {
+        if let [client] = &self.clients[..] {
+            return Some(client);
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.clients.len())
+            .map(|offset| &self.clients[(next + offset) % self.clients.len()])
+            .find(|client| client.is_healthy())
+    }
+
+    /// Getter of the clients of the servers that can be selected
+    fn selectable(&self) -> impl Iterator<Item = &OllamaClient> {
+        self.clients
+            .iter()
+            .filter(|client| self.clients.len() == 1 || client.is_healthy())
+    }
+
+    /// Keep the health of the servers of a previous pool, when its clients are rebuilt (e.g. with a new auth token)
+    fn with_health_of(self, previous: &OllamaPool) -> OllamaPool {
+        let clients = self
+            .clients
+            .iter()
+            .map(|client| {
+                let mut client = client.clone();
+                if let Some(previous_client) = previous
+                    .clients
+                    .iter()
+                    .find(|previous_client| previous_client.host == client.host)
+                {
+                    client.health = previous_client.health.clone();
+                }
+                client.generation = previous.generation + 1;
+                client
+            })
+            .collect();
+        OllamaPool {
+            clients,
+            next: self.next,
+            generation: previous.generation + 1,
+            retired: self.retired,
+        }
+    }
+
+    /// Let the calls in flight on the clients of a pool replaced by a reload finish within the `drain_timeout`, then abort them
+    async fn retire(self, drain_timeout: Duration) {
+        // Each call in flight holds a receiver of the signal
+        if tokio::time::timeout(drain_timeout, self.retired.closed())
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} calls in flight on the replaced Ollama clients are aborted, they didn't finish within {drain_timeout:?}",
+                self.retired.receiver_count()
+            );
+            self.retired.send_replace(true);
+        }
+    }
+}
+
+/// Health of an Ollama server, and the models it keeps loaded, shared by the clones of its client
+#[derive(Debug)]
+struct BackendHealth {
+    healthy: AtomicBool,
+    consecutive_failures: AtomicU32,
+    /// Number of calls in flight to the server
+    in_flight: AtomicUsize,
+    running_models: std::sync::Mutex<Vec<RunningModel>>,
+    /// Local models of the server, at their last listing
+    local_models: std::sync::Mutex<Vec<LocalModel>>,
+}
+
+impl Default for BackendHealth {
+    fn default() -> Self {
+        BackendHealth {
+            healthy: AtomicBool::new(true),
+            consecutive_failures: AtomicU32::new(0),
+            in_flight: AtomicUsize::new(0),
+            running_models: std::sync::Mutex::default(),
+            local_models: std::sync::Mutex::default(),
+        }
+    }
+}
+
+/// Call in flight to a server, counted until it's dropped
+struct InFlightCall(Arc<BackendHealth>);
+
+impl Drop for InFlightCall {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Call to a server, until its outcome is recorded. A server is marked unhealthy after too many consecutive failures.
+/// A call dropped before its outcome (e.g. cut off by the request timeout) is a failure of the server
+struct BackendCall {
+    ollama: OllamaClient,
+    unhealthy_threshold: u32,
+    recorded: bool,
+}
+
+impl BackendCall {
+    /// Record the outcome of the call
+    fn record(mut self, failure: bool) {
+        self.recorded = true;
+        self.record_outcome(failure);
+    }
+
+    fn record_outcome(&self, failure: bool) {
+        if self.ollama.record_call(failure, self.unhealthy_threshold) {
+            warn!("The Ollama server {} is unhealthy", self.ollama.host());
+        }
+    }
+}
+
+impl Drop for BackendCall {
+    fn drop(&mut self) {
+        if !self.recorded {
+            debug!(
+                "The call to the Ollama server {} is cut off",
+                self.ollama.host()
+            );
+            self.record_outcome(true);
+        }
+    }
+}
+
+/// Status of a server of the pool, as seen by the processor
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendStatus {
+    /// Host and port of the server
+    pub host: String,
+    /// The server can be selected for the requests
+    pub healthy: bool,
+    /// Number of consecutive failed calls to the server
+    pub consecutive_failures: u32,
+    /// Number of calls in flight to the server
+    pub in_flight: usize,
+    /// Models loaded by the server, at the last refresh of `running_models_refresh_interval`
+    pub loaded_models: Vec<String>,
+    /// State of the circuit breaker, shared by all the servers: `closed`, `open` or `half_open`
+    pub circuit_state: &'static str,
+}
+
+/// Ollama client, with direct access to the API endpoints not covered by ollama-rs
+#[derive(Debug, Clone)]
+pub struct OllamaClient {
+    ollama: Ollama,
+    http: reqwest::Client,
+    /// Host and port of the server
+    host: String,
+    health: Arc<BackendHealth>,
+    /// Generation of the pool the client belongs to
+    generation: u64,
+    /// Signal of the pool to abort the calls in flight
+    retired: Arc<watch::Sender<bool>>,
+}
+
+impl OllamaClient {
+    /// Getter of the host and port of the server
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Run a call to the server, aborted if the pool of the client is replaced by a reload and the calls in flight are not drained in time
+    async fn until_retired<T, F>(&self, call: F) -> Result<T, OllamaError>
+    where
+        F: Future<Output = Result<T, OllamaError>>,
+    {
+        let mut retired = self.retired.subscribe();
+        tokio::select! {
+            result = call => result,
+            Ok(_) = retired.wait_for(|retired| *retired) => Err(OllamaError::Other(
+                "The call was aborted, its Ollama client was replaced by a reload".to_string(),
+            )),
+        }
+    }
+
+    /// Count a call in flight to the server, until the returned guard is dropped
+    fn start_call(&self) -> InFlightCall {
+        self.health.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightCall(self.health.clone())
+    }
+
+    /// Getter of the status of the server, with the state of the circuit breaker
+    fn status(&self, circuit_state: &'static str) -> BackendStatus {
+        BackendStatus {
+            host: self.host.clone(),
+            healthy: self.is_healthy(),
+            consecutive_failures: self.health.consecutive_failures.load(Ordering::Relaxed),
+            in_flight: self.health.in_flight.load(Ordering::Relaxed),
+            loaded_models: self
+                .running_models()
+                .into_iter()
+                .map(|running_model| running_model.name)
+                .collect(),
+            circuit_state,
+        }
+    }
+
+    /// Method to know if the server is healthy, i.e. it's selected for the requests
+    pub fn is_healthy(&self) -> bool {
+        self.health.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Record the outcome of a call to the server. Return `true` if the server becomes unhealthy
+    fn record_call(&self, failure: bool, unhealthy_threshold: u32) -> bool {
+        if failure {
+            let failures = self
+                .health
+                .consecutive_failures
+                .fetch_add(1, Ordering::Relaxed)
+                .saturating_add(1);
+            failures >= unhealthy_threshold && self.health.healthy.swap(false, Ordering::Relaxed)
+        } else {
+            self.health.consecutive_failures.store(0, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Getter of the models loaded by the server, at the last refresh of `running_models_refresh_interval`
+    pub fn running_models(&self) -> Vec<RunningModel> {
+        self.health
+            .running_models
+            .lock()
+            .map(|running_models| running_models.clone())
+            .unwrap_or_default()
+    }
+
+    /// Estimate if a model fits in the free VRAM of the server: loaded already, or smaller than the VRAM left by the running models.
+    /// A model of unknown size (not in the last listing of the local models) is assumed to fit
+    fn fits_model(&self, model: &str, vram_capacity: u64) -> bool {
+        fn base_name(model: &str) -> &str {
+            model.strip_suffix(":latest").unwrap_or(model)
+        }
+
+        let running_models = self.running_models();
+        if running_models
+            .iter()
+            .any(|running_model| base_name(&running_model.name) == base_name(model))
+        {
+            return true;
+        }
+        let Some(size) = self
+            .health
+            .local_models
+            .lock()
+            .ok()
+            .and_then(|local_models| {
+                local_models
+                    .iter()
+                    .find(|local_model| base_name(&local_model.name) == base_name(model))
+                    .map(|local_model| local_model.size)
+            })
+        else {
+            return true;
+        };
+        let used_vram: u64 = running_models
+            .iter()
+            .map(|running_model| running_model.size_vram)
+            .sum();
+        size <= vram_capacity.saturating_sub(used_vram)
+    }
+
+    /// Refresh the models loaded by the server (`/api/ps`)
+    async fn refresh_running_models(&self) -> Result<Vec<RunningModel>, OllamaError> {
+        let running_models = self.get_api::<RunningModels>("ps").await?.models;
+        if let Ok(mut models) = self.health.running_models.lock() {
+            models.clone_from(&running_models);
+        }
+        Ok(running_models)
+    }
+
+    /// Mark the server healthy, once a probe succeeded
+    fn set_healthy(&self) {
+        self.health.consecutive_failures.store(0, Ordering::Relaxed);
+        self.health.healthy.store(true, Ordering::Relaxed);
+    }
+
+    /// Call a `GET` endpoint of the Ollama API (e.g. `ps` for `/api/ps`)
+    pub async fn get_api<T>(&self, endpoint: &str) -> Result<T, OllamaError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let response = self
+            .http
+            .get(format!("{}api/{endpoint}", self.ollama.url_str()))
+            .send()
+            .await?;
+
+        Ok(check_reply(response).await?.json().await?)
+    }
+
+    /// Call a `POST` endpoint of the Ollama API (e.g. `generate` for `/api/generate`) with a JSON body, without streaming.
+    ///
+    /// ollama-rs turns the error replies into messages, so the main endpoints are called directly to keep their HTTP status
+    pub async fn post_api<B, T>(&self, endpoint: &str, body: &B) -> Result<T, OllamaError>
+    where
+        B: Serialize,
+        T: serde::de::DeserializeOwned,
+    {
+        let response = self.post_json(endpoint, body, false).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Stream a generation from the `/api/generate` endpoint, read chunk by chunk
+    async fn generate_stream_api(
+        &self,
+        request: &GenerationRequest<'_>,
+    ) -> Result<GenerationStream, OllamaError> {
+        Ok(GenerationStream {
+            response: self.post_json("generate", request, true).await?,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Send a JSON body to a `POST` endpoint, with the `stream` flag of the streaming endpoints (ignored by the others)
+    async fn post_json<B>(
+        &self,
+        endpoint: &str,
+        body: &B,
+        stream: bool,
+    ) -> Result<reqwest::Response, OllamaError>
+    where
+        B: Serialize,
+    {
+        let mut body = serde_json::to_value(body).map_err(ollama_rs::error::OllamaError::from)?;
+        if let Some(fields) = body.as_object_mut() {
+            fields.insert("stream".to_string(), stream.into());
+        }
+        let response = self
+            .http
+            .post(format!("{}api/{endpoint}", self.ollama.url_str()))
+            .json(&body)
+            .send()
+            .await?;
+        check_reply(response).await
+    }
+
+    /// List the models of the server, from the `/api/tags` endpoint
+    pub async fn local_models(&self) -> Result<Vec<LocalModel>, OllamaError> {
+        let local_models = self.get_api::<LocalModels>("tags").await?.models;
+        if let Ok(models) = self.health.local_models.lock().as_mut() {
+            models.clone_from(&local_models);
+        }
+        Ok(local_models)
+    }
+
+    /// Call any endpoint of the Ollama server, with a JSON body. Return the body of the response
+    pub async fn call_raw(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, OllamaError> {
+        let mut request = self.http.request(
+            method,
+            format!("{}{}", self.ollama.url_str(), path.trim_start_matches('/')),
+        );
+        if let Some(body) = body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body);
+        }
+
+        let response = check_reply(request.send().await?).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+/// Keep a successful reply of the server, or turn an error reply into an error with its HTTP status (and the `error` field of its body, if any)
+async fn check_reply(response: reqwest::Response) -> Result<reqwest::Response, OllamaError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<ollama_rs::error::InternalOllamaError>(&body)
+        .map(|error| error.message)
+        .unwrap_or(body);
+    Err(OllamaError::Http { status, message })
+}
+
+/// Chunks of a streamed generation, read from the newline delimited JSON body of the response
+struct GenerationStream {
+    response: reqwest::Response,
+    buffer: Vec<u8>,
+}
+
+impl GenerationStream {
+    /// Read the next chunks of the generation, `None` at the end of the stream
+    async fn next(&mut self) -> Option<Result<Vec<GenerationResponse>, OllamaError>> {
+        loop {
+            match self.response.chunk().await {
+                Ok(Some(bytes)) => {
+                    self.buffer.extend_from_slice(&bytes);
+                    if let Some(end) = self.buffer.iter().rposition(|byte| *byte == b'\n') {
+                        let lines: Vec<u8> = self.buffer.drain(..=end).collect();
+                        return Some(Self::parse(&lines));
+                    }
+                }
+                Ok(None) if self.buffer.is_empty() => return None,
+                Ok(None) => {
+                    let lines = std::mem::take(&mut self.buffer);
+                    return Some(Self::parse(&lines));
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+
+    /// Parse complete lines of chunks. A line with an `error` field is an error of the backend during the generation
+    fn parse(lines: &[u8]) -> Result<Vec<GenerationResponse>, OllamaError> {
+        lines
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .map(|line| {
+                serde_json::from_slice::<GenerationResponse>(line).map_err(|e| {
+                    match serde_json::from_slice::<ollama_rs::error::InternalOllamaError>(line) {
+                        Ok(error) => ollama_rs::error::OllamaError::InternalError(error).into(),
+                        Err(_) => ollama_rs::error::OllamaError::from(e).into(),
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+impl Deref for OllamaClient {
+    type Target = Ollama;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ollama
+    }
+}
+
+#[proc_settings]
+impl Default for OllamaProcSettings {
+    fn default() -> Self {
+        OllamaProcSettings {
+            url: Self::default_url(),
+            urls: Vec::default(),
+            models: Self::default_models(),
+            auto_pull: Self::default_auto_pull(),
+            models_refresh_interval: Self::default_models_refresh_interval(),
+            model_sync_interval: None,
+            allow_insecure: false,
+            services: Self::default_services(),
+            service_defaults: HashMap::default(),
+            default_options: None,
+            auth_token: None,
+            headers: HashMap::default(),
+            backend_auth: HashMap::default(),
+            client_cert_path: None,
+            client_key_path: None,
+            ca_cert_path: None,
+            connect_timeout: None,
+            read_timeout: None,
+            auth_refresh_command: None,
+            log_payload_sizes: false,
+            completion_log_level: Self::default_completion_log_level(),
+            metric_prefix: None,
+            meter_name: None,
+            invalid_embeddings: InvalidEmbeddingsPolicy::default(),
+            partial_embeddings: false,
+            metadata_metric_keys: Vec::default(),
+            connection_warmup: None,
+            max_loaded_models: None,
+            running_models_refresh_interval: None,
+            vram_capacity: None,
+            cold_start_threshold: Self::default_cold_start_threshold(),
+            introspection_only: false,
+            allowed_request_kinds: Vec::default(),
+            eval_sampling_rate: 0.0,
+            eval_sampling_file: None,
+            aliases: HashMap::default(),
+            fallback_model: None,
+            errors_as_response: false,
+            empty_prompt_policy: EmptyPromptPolicy::default(),
+            max_prompt_chars: None,
+            stream_service: None,
+            request_timeout: None,
+            max_stream_duration: None,
+            max_response_bytes: None,
+            max_retries: 0,
+            retry_backoff: Self::default_retry_backoff(),
+            retry_max_backoff: Self::default_retry_max_backoff(),
+            max_concurrent_requests: None,
+            concurrency_rampup_duration: None,
+            priority_queue_capacity: None,
+            priority_aging: Self::default_priority_aging(),
+            keep_alive: None,
+            default_format: None,
+            preload_models: false,
+            readiness_probe_interval: Self::default_readiness_probe_interval(),
+            readiness_max_wait: Self::default_readiness_max_wait(),
+            shutdown_grace_period: Self::default_shutdown_grace_period(),
+            model_info_cache_ttl: None,
+            enforce_model_allowlist: false,
+            circuit_breaker_threshold: None,
+            circuit_breaker_cooldown: Self::default_circuit_breaker_cooldown(),
+            backend_unhealthy_threshold: Self::default_backend_unhealthy_threshold(),
+            backend_probe_interval: Self::default_backend_probe_interval(),
+            generation_cache_capacity: None,
+            generation_cache_ttl: None,
+            generation_cache_nondeterministic: false,
+            embeddings_batch_window: None,
+            embeddings_max_batch_size: Self::default_embeddings_max_batch_size(),
+        }
+    }
+}
+
+/// Builder of [`OllamaProcSettings`], starting from the default settings.
+/// The settings not covered by the builder are set afterward with their setters
+#[derive(Debug, Clone, Default)]
+pub struct OllamaProcSettingsBuilder {
+    settings: OllamaProcSettings,
+}
+
+impl OllamaProcSettingsBuilder {
+    /// Create a builder with the default settings
+    pub fn new() -> OllamaProcSettingsBuilder {
+        OllamaProcSettingsBuilder::default()
+    }
+
+    /// Set the url of the Ollama API server
+    pub fn url(mut self, url: Url) -> Self {
+        self.settings.url = url;
+        self
+    }
+
+    /// Allow insecure connections to the library, and to a server with an invalid certificate
+    pub fn allow_insecure(mut self, allow_insecure: bool) -> Self {
+        self.settings.allow_insecure = allow_insecure;
+        self
+    }
+
+    /// Set the models needed by the processor before starting
+    pub fn models(mut self, models: Vec<String>) -> Self {
+        self.settings.set_models(models);
+        self
+    }
+
+    /// Set the names of the services declared by the processor
+    pub fn services(mut self, services: Vec<String>) -> Self {
+        self.settings.services = services;
+        self
+    }
+
+    /// Set the timeout of the Ollama calls
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.settings.set_request_timeout(Some(timeout));
+        self
+    }
+
+    /// Set the retries of the generation, chat and embeddings calls on recoverable errors
+    pub fn retries(mut self, max_retries: u32, retry_backoff: Duration) -> Self {
+        self.settings.set_retries(max_retries, retry_backoff);
+        self
+    }
+
+    /// Add a custom header sent to the server
+    pub fn header<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.settings.headers.insert(name.into(), value.into());
+        self
+    }
+
+    /// Set the bearer token sent to the servers without their own credentials
+    pub fn auth_token<S>(mut self, token: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.settings.set_auth_token(Some(token.into()));
+        self
+    }
+
+    /// Build the settings, in error if a service name is missing or empty, or if a custom header (global or of a server) is invalid
+    pub fn build(self) -> Result<OllamaProcSettings, OllamaError> {
+        if self.settings.services.is_empty() {
+            return Err(OllamaError::Other(
+                "The settings must declare at least one service".to_string(),
+            ));
+        }
+        if self.settings.services.iter().any(String::is_empty) {
+            return Err(OllamaError::Other(
+                "The service names must not be empty".to_string(),
+            ));
+        }
+        for (name, value) in self.settings.headers.iter().chain(
+            self.settings
+                .backend_auth
+                .values()
+                .flat_map(|backend_auth| &backend_auth.headers),
+        ) {
+            HeaderName::from_str(name)?;
+            HeaderValue::from_str(value)?;
+        }
+
+        Ok(self.settings)
+    }
+}
+
+/// Bearer token, redacted when formatted with `Debug` to keep it out of the logs
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AuthToken(String);
+
+impl std::fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AuthToken(***)")
+    }
+}
+
+/// Credentials and headers of a server of the pool, redacted when formatted with `Debug` to keep them out of the logs
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct BackendAuth {
+    /// Bearer token sent to the server, instead of its URL credentials and the global token
+    #[serde(default)]
+    api_key: Option<AuthToken>,
+    /// Custom headers sent to the server, in addition to the global ones
+    #[serde(default)]
+    headers: HashMap<String, String>,
+}
+
+impl BackendAuth {
+    /// Create the credentials and headers of a server
+    pub fn new(api_key: Option<String>, headers: HashMap<String, String>) -> BackendAuth {
+        BackendAuth {
+            api_key: api_key.map(AuthToken),
+            headers,
+        }
+    }
+}
+
+impl std::fmt::Debug for BackendAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Only the names of the headers, their values may be secrets
+        f.debug_struct("BackendAuth")
+            .field("api_key", &self.api_key)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Default model and options of a service
+///
+/// Values set by the adaptor in the request always take precedence over the service defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceDefaults {
+    /// Model used when the request doesn't specify one
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Options merged under the request options
+    #[serde(default)]
+    pub options: Option<ModelOptions>,
+}
+
+/// Merge default options under request options. Options set on the request are kept.
+fn merge_options(
+    default_options: &ModelOptions,
+    options: Option<ModelOptions>,
+) -> Result<Option<ModelOptions>, OllamaError> {
+    match options {
+        Some(options) => {
+            let mut merged = serde_json::to_value(default_options)
+                .map_err(|e| OllamaError::Other(e.to_string()))?;
+            if let (Some(merged), serde_json::Value::Object(options)) = (
+                merged.as_object_mut(),
+                serde_json::to_value(options).map_err(|e| OllamaError::Other(e.to_string()))?,
+            ) {
+                merged.extend(options);
+            }
+
+            Ok(Some(serde_json::from_value(merged).map_err(|e| {
+                OllamaError::bad_field("options", e.to_string())
+            })?))
+        }
+        None => Ok(Some(default_options.clone())),
+    }
+}
+
+impl ServiceDefaults {
+    /// Merge the service default options under request options
+    fn merge_options(
+        &self,
+        options: Option<ModelOptions>,
+    ) -> Result<Option<ModelOptions>, OllamaError> {
+        match &self.options {
+            Some(default_options) => merge_options(default_options, options),
+            None => Ok(options),
+        }
+    }
+
+    /// Apply the service defaults to an Ollama request
+    pub fn apply(&self, request: &mut OllamaRequest<'_>) -> Result<(), OllamaError> {
+        match request {
+            OllamaRequest::GenerateRequest(request)
+            | OllamaRequest::GenerateStreamRequest(request) => {
+                if let Some(model) = &self.model
+                    && request.model_name.is_empty()
+                {
+                    request.model_name = model.clone();
+                }
+                request.options = self.merge_options(request.options.take())?;
+            }
+            OllamaRequest::ChatRequest(request) => {
+                if let Some(model) = &self.model
+                    && request.model_name.is_empty()
+                {
+                    request.model_name = model.clone();
+                }
+                request.options = self.merge_options(request.options.take())?;
+            }
+            OllamaRequest::GenerateEmbeddingsRequest(request) => {
+                if let Some(model) = &self.model
+                    && request.model_name.is_empty()
+                {
+                    request.model_name = model.clone();
+                }
+                request.options = self.merge_options(request.options.take())?;
+            }
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels
+            | OllamaRequest::ModelInfo(_)
+            | OllamaRequest::ModelExists(_)
+            | OllamaRequest::ModelParameters(_)
+            | OllamaRequest::DeleteModel(_)
+            | OllamaRequest::CopyModel { .. }
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. }
+            | OllamaRequest::CountTokens { .. }
+            | OllamaRequest::Warmup(_)
+            | OllamaRequest::UnloadModel(_)
+            | OllamaRequest::Raw { .. } => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Policy applied when an embeddings response contains invalid vectors (empty, all zeros, NaN or infinite)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidEmbeddingsPolicy {
+    /// Invalid vectors are only counted
+    #[default]
+    Ignore,
+    /// The request is returned in error
+    Error,
+    /// The response is returned as [`OllamaResponse::MarkedEmbeddingsResponse`] with the invalid indices
+    Mark,
+}
+
+impl InvalidEmbeddingsPolicy {
+    fn is_invalid(embedding: &[f32]) -> bool {
+        embedding.is_empty()
+            || embedding.iter().any(|value| !value.is_finite())
+            || embedding.iter().all(|value| *value == 0.0)
+    }
+
+    /// Check the embeddings of a response and apply the policy
+    fn check(
+        &self,
+        response: OllamaResponse,
+        model: Option<&str>,
+        meters: &OllamaMeters,
+    ) -> Result<OllamaResponse, OllamaError> {
+        if let OllamaResponse::GenerateEmbeddingsResponse(embeddings_response) = response {
+            let invalid_indices: Vec<usize> = embeddings_response
+                .embeddings
+                .iter()
+                .enumerate()
+                .filter_map(|(i, embedding)| Self::is_invalid(embedding).then_some(i))
+                .collect();
+            if invalid_indices.is_empty() {
+                return Ok(OllamaResponse::GenerateEmbeddingsResponse(
+                    embeddings_response,
+                ));
+            }
+
+            meters.bad_embeddings_counter.add(
+                invalid_indices.len() as u64,
+                &[KeyValue::new(
+                    "model",
+                    model.unwrap_or_default().to_string(),
+                )],
+            );
+            warn!(
+                "Invalid embeddings returned at indices {:?}",
+                invalid_indices
+            );
+            match self {
+                InvalidEmbeddingsPolicy::Ignore => Ok(OllamaResponse::GenerateEmbeddingsResponse(
+                    embeddings_response,
+                )),
+                InvalidEmbeddingsPolicy::Error => Err(OllamaError::Other(format!(
+                    "Invalid embeddings returned at indices {:?}",
+                    invalid_indices
+                ))),
+                InvalidEmbeddingsPolicy::Mark => Ok(OllamaResponse::MarkedEmbeddingsResponse {
+                    response: embeddings_response,
+                    invalid_indices,
+                }),
+            }
+        } else {
+            Ok(response)
+        }
+    }
+}
+
+/// Business metadata attached to a request by the adaptor, echoed in the completion log
+pub type RequestMetadata = BTreeMap<String, String>;
+
+/// Maximum number of metadata entries kept for a request
+pub const MAX_REQUEST_METADATA_ENTRIES: usize = 16;
+
+/// Maximum length (in characters) of a metadata value, longer values are truncated
+pub const MAX_REQUEST_METADATA_VALUE_LEN: usize = 128;
+
+/// Bound the metadata of a request with [`MAX_REQUEST_METADATA_ENTRIES`] and [`MAX_REQUEST_METADATA_VALUE_LEN`]
+fn bound_request_metadata(metadata: RequestMetadata) -> RequestMetadata {
+    metadata
+        .into_iter()
+        .take(MAX_REQUEST_METADATA_ENTRIES)
+        .map(|(key, value)| {
+            let value = if value.chars().count() > MAX_REQUEST_METADATA_VALUE_LEN {
+                value.chars().take(MAX_REQUEST_METADATA_VALUE_LEN).collect()
+            } else {
+                value
+            };
+            (key, value)
+        })
+        .collect()
+}
+
+/// Policy applied when a generation request has an empty prompt
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyPromptPolicy {
+    /// The request is returned in error
+    Error,
+    /// The prompt is replaced by this default prompt
+    Default(String),
+    /// The request is sent as is to the backend
+    #[default]
+    Passthrough,
+}
+
+impl EmptyPromptPolicy {
+    /// Apply the policy to an Ollama request. Empty prompts are counted in the meters
+    fn apply(
+        &self,
+        request: &mut OllamaRequest<'_>,
+        meters: &OllamaMeters,
+    ) -> Result<(), OllamaError> {
+        if let OllamaRequest::GenerateRequest(request)
+        | OllamaRequest::GenerateStreamRequest(request) = request
+            && request.prompt.trim().is_empty()
+        {
+            let policy = match self {
+                EmptyPromptPolicy::Error => "error",
+                EmptyPromptPolicy::Default(_) => "default",
+                EmptyPromptPolicy::Passthrough => "passthrough",
+            };
+            meters.empty_prompt_counter.add(
+                1,
+                &[
+                    KeyValue::new("model", request.model_name.clone()),
+                    KeyValue::new("policy", policy),
+                ],
+            );
+
+            match self {
+                EmptyPromptPolicy::Error => {
+                    return Err(OllamaError::bad_field("prompt", "the prompt is empty"));
+                }
+                EmptyPromptPolicy::Default(prompt) => request.prompt = prompt.clone().into(),
+                EmptyPromptPolicy::Passthrough => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the instructions of a modelfile, as `(instruction, value)` pairs.
+/// Values can be quoted, or span several lines between `"""`
+fn parse_modelfile(modelfile: &str) -> Vec<(&str, &str)> {
+    let mut instructions = Vec::new();
+    let mut rest = modelfile;
+    while !rest.is_empty() {
+        let (line, next) = rest.split_once('\n').unwrap_or((rest, ""));
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            rest = next;
+            continue;
+        }
+
+        let (instruction, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim_start();
+        if value.starts_with("\"\"\"") {
+            // The value is a slice of the remaining modelfile, so it can continue on the next lines
+            let value_start = value.as_ptr() as usize - rest.as_ptr() as usize;
+            let multiline = &rest[value_start + 3..];
+            let (value, after) = multiline.split_once("\"\"\"").unwrap_or((multiline, ""));
+            instructions.push((instruction, value));
+            rest = after
+                .split_once('\n')
+                .map(|(_, next)| next)
+                .unwrap_or_default();
+        } else {
+            let value = value.trim_end();
+            instructions.push((
+                instruction,
+                value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value),
+            ));
+            rest = next;
+        }
+    }
+
+    instructions
+}
+
+/// Build a create model request from a modelfile
+fn create_model_request(name: String, modelfile: &str) -> Result<CreateModelRequest, OllamaError> {
+    let mut request = CreateModelRequest::new(name);
+    let mut parameters = serde_json::Map::new();
+    let mut messages = Vec::new();
+    for (instruction, value) in parse_modelfile(modelfile) {
+        match instruction.to_uppercase().as_str() {
+            "FROM" => request.from_model = Some(value.to_string()),
+            "SYSTEM" => request.system = Some(value.to_string()),
+            "TEMPLATE" => request.template = Some(value.to_string()),
+            "LICENSE" => request
+                .license
+                .get_or_insert_with(Vec::new)
+                .push(value.to_string()),
+            "PARAMETER" => {
+                let (name, value) = value.split_once(char::is_whitespace).ok_or_else(|| {
+                    OllamaError::bad_field("modelfile", format!("invalid parameter `{value}`"))
+                })?;
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                let json_value = serde_json::from_str::<serde_json::Value>(value)
+                    .ok()
+                    .filter(|value| value.is_number() || value.is_boolean())
+                    .unwrap_or_else(|| serde_json::Value::String(value.to_string()));
+                if name == "stop" {
+                    if let serde_json::Value::Array(stops) = parameters
+                        .entry(name)
+                        .or_insert_with(|| serde_json::Value::Array(Vec::new()))
+                    {
+                        stops.push(json_value);
+                    }
+                } else {
+                    parameters.insert(name.to_string(), json_value);
+                }
+            }
+            "MESSAGE" => {
+                let (role, content) = value.split_once(char::is_whitespace).ok_or_else(|| {
+                    OllamaError::bad_field("modelfile", format!("invalid message `{value}`"))
+                })?;
+                let role = serde_json::from_value::<MessageRole>(serde_json::Value::String(
+                    role.to_lowercase(),
+                ))
+                .map_err(|e| {
+                    OllamaError::bad_field("modelfile", format!("invalid message role: {e}"))
+                })?;
+                messages.push(ChatMessage::new(role, content.trim().to_string()));
+            }
+            instruction => {
+                return Err(OllamaError::bad_field(
+                    "modelfile",
+                    format!("unsupported instruction `{instruction}`"),
+                ));
+            }
+        }
+    }
+
+    if request.from_model.is_none() {
+        return Err(OllamaError::bad_field(
+            "modelfile",
+            "missing the `FROM` instruction",
+        ));
+    }
+    if !parameters.is_empty() {
+        request.parameters = Some(
+            serde_json::from_value(serde_json::Value::Object(parameters)).map_err(|e| {
+                OllamaError::bad_field("modelfile", format!("invalid parameters: {e}"))
+            })?,
+        );
+    }
+    if !messages.is_empty() {
+        request.messages = Some(messages);
+    }
+
+    Ok(request)
+}
+
+/// Parse the default parameters of a model, given as `name value` lines with optionally quoted values
+fn parse_model_parameters(parameters: &str) -> BTreeMap<String, Vec<String>> {
+    let mut model_parameters: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for line in parameters.lines() {
+        if let Some((name, value)) = line.trim().split_once(char::is_whitespace) {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            model_parameters
+                .entry(name.to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+    }
+
+    model_parameters
+}
+
+/// Check if an Ollama error is due to a transient network failure (connection refused, DNS, timeout, connection reset)
+fn is_transient(error: &ollama_rs::error::OllamaError) -> bool {
+    match error {
+        // ollama-rs never keeps the HTTP status in a reqwest error, the error replies are classified by `is_transient_status`
+        ollama_rs::error::OllamaError::ReqwestError(e) => {
+            e.is_connect() || e.is_timeout() || e.is_request()
+        }
+        ollama_rs::error::OllamaError::ToolCallError(_)
+        | ollama_rs::error::OllamaError::JsonError(_)
+        | ollama_rs::error::OllamaError::InternalError(_)
+        | ollama_rs::error::OllamaError::Other(_) => false,
+    }
+}
+
+/// Check if the HTTP status of an error reply is transient: a server error (e.g. crashed runner, bad gateway) or a busy server (429)
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Message of an error reply lost by ollama-rs, for the calls left to it (model management)
+fn ollama_rs_message(error: &OllamaError) -> Option<&str> {
+    match error {
+        OllamaError::Ollama(ollama_rs::error::OllamaError::Other(message)) => Some(message),
+        OllamaError::Ollama(ollama_rs::error::OllamaError::InternalError(error)) => {
+            Some(&error.message)
+        }
+        _ => None,
+    }
+}
+
+/// Check if an error is due to a model missing on the server (HTTP 404)
+fn is_model_not_found(error: &OllamaError) -> bool {
+    match error {
+        OllamaError::Http { status, .. } => *status == reqwest::StatusCode::NOT_FOUND,
+        _ => ollama_rs_message(error).is_some_and(|message| {
+            let message = message.to_lowercase();
+            message.contains("model") && message.contains("not found")
+        }),
+    }
+}
+
+/// Check if an error is due to an invalid request (HTTP 400)
+fn is_bad_request(error: &OllamaError) -> bool {
+    match error {
+        OllamaError::BadRequest { .. } => true,
+        OllamaError::Http { status, .. } => *status == reqwest::StatusCode::BAD_REQUEST,
+        _ => false,
+    }
+}
+
+/// Check if an error is due to rejected credentials (HTTP 401)
+fn is_unauthorized(error: &OllamaError) -> bool {
+    match error {
+        OllamaError::Http { status, .. } => *status == reqwest::StatusCode::UNAUTHORIZED,
+        _ => false,
+    }
+}
+
+/// Model loaded in memory by the backend
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RunningModel {
+    /// Name of the model
+    pub name: String,
+    /// Model tag
+    pub model: String,
+    /// Size of the model in memory (bytes)
+    pub size: u64,
+    /// Size of the model in VRAM (bytes)
+    pub size_vram: u64,
+    /// Digest of the model
+    pub digest: String,
+    /// Date when the model will be unloaded, if it's not used
+    pub expires_at: String,
+}
+
+/// Response of the `/api/ps` endpoint
+#[derive(Debug, Deserialize)]
+struct RunningModels {
+    models: Vec<RunningModel>,
+}
+
+/// Response of the `/api/tags` endpoint
+#[derive(Debug, Deserialize)]
+struct LocalModels {
+    models: Vec<LocalModel>,
+}
+
+/// Body of a request of the `/api/show` endpoint
+#[derive(Debug, Serialize)]
+struct ShowModelRequest<'a> {
+    model: &'a str,
+}
+
+/// Response of the `/api/version` endpoint
+#[derive(Debug, Deserialize)]
+struct ServerVersion {
+    version: String,
+}
+
+/// Kind of Ollama request, also used as `type` attribute of the metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OllamaRequestKind {
+    Ping,
+    Status,
+    Version,
+    /// List of the local or running models
+    List,
+    /// Model info, existence or parameters
+    Info,
+    /// Generation, streamed or not
+    Gen,
+    Chat,
+    Embed,
+    /// Deletion, copy, creation or push of a model
+    Manage,
+    /// Token count
+    Count,
+    Warmup,
+    Raw,
+}
+
+impl OllamaRequestKind {
+    /// Getter of the kind name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OllamaRequestKind::Ping => "ping",
+            OllamaRequestKind::Status => "status",
+            OllamaRequestKind::Version => "version",
+            OllamaRequestKind::List => "list",
+            OllamaRequestKind::Info => "info",
+            OllamaRequestKind::Gen => "gen",
+            OllamaRequestKind::Chat => "chat",
+            OllamaRequestKind::Embed => "embed",
+            OllamaRequestKind::Manage => "manage",
+            OllamaRequestKind::Count => "count",
+            OllamaRequestKind::Warmup => "warmup",
+            OllamaRequestKind::Raw => "raw",
+        }
+    }
+}
+
+/// Ollama requests
+pub enum OllamaRequest<'a> {
+    /// Measure the round-trip latency of the backend with a trivial call
+    Ping,
+    /// Get the status of the processor: servers (without credentials), auth mode, configured models and backend health
+    Status,
+    /// Get the version of the Ollama server
+    Version,
+    ListLocalModels,
+    /// List the models loaded in memory by the backend (`/api/ps`)
+    ListRunningModels,
+    ModelInfo(String),
+    /// Check if a model is available on the server, from its local models (cheaper than the model info)
+    ModelExists(String),
+    /// Get the default parameters of a model, from the `PARAMETER` lines of its modelfile
+    ModelParameters(String),
+    GenerateRequest(Box<GenerationRequest<'a>>),
+    /// Generation request streamed chunk by chunk to [`OllamaAdaptor::process_stream_chunk`].
+    /// The complete response is then given to [`OllamaAdaptor::process_ollama_response`]
+    GenerateStreamRequest(Box<GenerationRequest<'a>>),
+    /// Chat completion request
+    ChatRequest(Box<ChatMessageRequest>),
+    GenerateEmbeddingsRequest(Box<GenerateEmbeddingsRequest>),
+    /// Delete a model from the backend
+    DeleteModel(String),
+    /// Copy a model under a new name (e.g. a short alias of a fine-tuned variant)
+    CopyModel {
+        source: String,
+        destination: String,
+    },
+    /// Create a model from a modelfile (`FROM`, `PARAMETER`, `TEMPLATE`, `SYSTEM`, `LICENSE` and `MESSAGE` instructions)
+    CreateModel {
+        name: String,
+        modelfile: String,
+    },
+    /// Push a model to its registry (name in the form `<namespace>/<model>:<tag>`), allowing an insecure connection to the registry if `insecure` is set
+    PushModel {
+        name: String,
+        insecure: bool,
+    },
+    /// Estimate the tokens of a text with the model tokenizer.
+    /// The backend has no tokenize-only endpoint: the count is the prompt token count reported for a raw single-token generation.
+    /// So it costs a generation (the model is loaded if needed), and it can be lower than the real count if the backend reuses a cached prompt prefix.
+    /// The generation is not counted in the token metrics
+    CountTokens {
+        model: String,
+        text: String,
+    },
+    /// Run a tiny generation against a model, to check that it's responsive before routing traffic to it.
+    /// The model is loaded by the backend if needed, and the load and eval durations are returned
+    Warmup(String),
+    /// Evict a model from the memory of the backend, with a minimal generation that doesn't keep it alive (e.g. to free the memory of a warmed up model)
+    UnloadModel(String),
+    /// Call an endpoint of the Ollama API not covered by the other requests, with the processor credentials.
+    /// The `path` is relative to the server URL (e.g. `api/tags`), and the `body` is sent as JSON
+    Raw {
+        method: reqwest::Method,
+        path: String,
+        body: Option<Vec<u8>>,
+    },
+}
+
+impl Clone for OllamaRequest<'_> {
+    fn clone(&self) -> Self {
+        match self {
+            OllamaRequest::Ping => OllamaRequest::Ping,
+            OllamaRequest::Status => OllamaRequest::Status,
+            OllamaRequest::Version => OllamaRequest::Version,
+            OllamaRequest::ListLocalModels => OllamaRequest::ListLocalModels,
+            OllamaRequest::ListRunningModels => OllamaRequest::ListRunningModels,
+            OllamaRequest::ModelInfo(model_name) => OllamaRequest::ModelInfo(model_name.clone()),
+            OllamaRequest::ModelExists(model_name) => {
+                OllamaRequest::ModelExists(model_name.clone())
+            }
+            OllamaRequest::ModelParameters(model_name) => {
+                OllamaRequest::ModelParameters(model_name.clone())
+            }
+            OllamaRequest::DeleteModel(model_name) => {
+                OllamaRequest::DeleteModel(model_name.clone())
+            }
+            OllamaRequest::CopyModel {
+                source,
+                destination,
+            } => OllamaRequest::CopyModel {
+                source: source.clone(),
+                destination: destination.clone(),
+            },
+            OllamaRequest::CreateModel { name, modelfile } => OllamaRequest::CreateModel {
+                name: name.clone(),
+                modelfile: modelfile.clone(),
+            },
+            OllamaRequest::PushModel { name, insecure } => OllamaRequest::PushModel {
+                name: name.clone(),
+                insecure: *insecure,
+            },
+            OllamaRequest::GenerateRequest(request) => {
+                OllamaRequest::GenerateRequest(request.clone())
+            }
+            OllamaRequest::GenerateStreamRequest(request) => {
+                OllamaRequest::GenerateStreamRequest(request.clone())
+            }
+            OllamaRequest::ChatRequest(request) => OllamaRequest::ChatRequest(request.clone()),
+            OllamaRequest::GenerateEmbeddingsRequest(request) => {
+                // The embeddings request doesn't implement Clone
+                OllamaRequest::GenerateEmbeddingsRequest(Box::new(GenerateEmbeddingsRequest {
+                    model_name: request.model_name.clone(),
+                    input: match &request.input {
+                        EmbeddingsInput::Single(input) => EmbeddingsInput::Single(input.clone()),
+                        EmbeddingsInput::Multiple(inputs) => {
+                            EmbeddingsInput::Multiple(inputs.clone())
+                        }
+                    },
+                    truncate: request.truncate,
+                    options: request.options.clone(),
+                    keep_alive: request.keep_alive.clone(),
+                    dimensions: request.dimensions,
+                }))
+            }
+            OllamaRequest::CountTokens { model, text } => OllamaRequest::CountTokens {
+                model: model.clone(),
+                text: text.clone(),
+            },
+            OllamaRequest::Warmup(model) => OllamaRequest::Warmup(model.clone()),
+            OllamaRequest::UnloadModel(model) => OllamaRequest::UnloadModel(model.clone()),
+            OllamaRequest::Raw { method, path, body } => OllamaRequest::Raw {
+                method: method.clone(),
+                path: path.clone(),
+                body: body.clone(),
+            },
+        }
+    }
+}
+
+impl OllamaRequest<'_> {
+    /// Encode the raw bytes of an image (e.g. PNG or JPEG file content) in base64, to attach it to a generation request (`images` field) or a chat message of a multimodal model
+    pub fn image_from_bytes(bytes: impl AsRef<[u8]>) -> Image {
+        Image::from_base64(STANDARD.encode(bytes))
+    }
+
+    /// Getter of the model targeted by the request, if any
+    pub fn get_model_name(&self) -> Option<&str> {
+        match self {
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels => None,
+            OllamaRequest::ModelInfo(model_name)
+            | OllamaRequest::ModelExists(model_name)
+            | OllamaRequest::ModelParameters(model_name)
+            | OllamaRequest::DeleteModel(model_name)
+            | OllamaRequest::CopyModel {
+                source: model_name, ..
+            }
+            | OllamaRequest::CreateModel {
+                name: model_name, ..
+            }
+            | OllamaRequest::PushModel {
+                name: model_name, ..
+            } => Some(model_name),
+            OllamaRequest::GenerateRequest(request)
+            | OllamaRequest::GenerateStreamRequest(request) => Some(&request.model_name),
+            OllamaRequest::ChatRequest(request) => Some(&request.model_name),
+            OllamaRequest::GenerateEmbeddingsRequest(request) => Some(&request.model_name),
+            OllamaRequest::CountTokens { model, .. }
+            | OllamaRequest::Warmup(model)
+            | OllamaRequest::UnloadModel(model) => Some(model),
+            OllamaRequest::Raw { .. } => None,
+        }
+    }
+
+    /// Getter of the model options of the request
+    pub fn get_options(&self) -> Option<&ModelOptions> {
+        match self {
+            OllamaRequest::GenerateRequest(request)
+            | OllamaRequest::GenerateStreamRequest(request) => request.options.as_ref(),
+            OllamaRequest::ChatRequest(request) => request.options.as_ref(),
+            OllamaRequest::GenerateEmbeddingsRequest(request) => request.options.as_ref(),
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels
+            | OllamaRequest::ModelInfo(_)
+            | OllamaRequest::ModelExists(_)
+            | OllamaRequest::ModelParameters(_)
+            | OllamaRequest::DeleteModel(_)
+            | OllamaRequest::CopyModel { .. }
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. }
+            | OllamaRequest::CountTokens { .. }
+            | OllamaRequest::Warmup(_)
+            | OllamaRequest::UnloadModel(_)
+            | OllamaRequest::Raw { .. } => None,
+        }
+    }
+
+    /// Mutable getter of the model options of the request
+    fn get_options_mut(&mut self) -> Option<&mut Option<ModelOptions>> {
+        match self {
+            OllamaRequest::GenerateRequest(request)
+            | OllamaRequest::GenerateStreamRequest(request) => Some(&mut request.options),
+            OllamaRequest::ChatRequest(request) => Some(&mut request.options),
+            OllamaRequest::GenerateEmbeddingsRequest(request) => Some(&mut request.options),
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels
+            | OllamaRequest::ModelInfo(_)
+            | OllamaRequest::ModelExists(_)
+            | OllamaRequest::ModelParameters(_)
+            | OllamaRequest::DeleteModel(_)
+            | OllamaRequest::CopyModel { .. }
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. }
+            | OllamaRequest::CountTokens { .. }
+            | OllamaRequest::Warmup(_)
+            | OllamaRequest::UnloadModel(_)
+            | OllamaRequest::Raw { .. } => None,
+        }
+    }
+
+    /// Mutable getter of the model name of the request
+    fn get_model_name_mut(&mut self) -> Option<&mut String> {
+        match self {
+            // The name of the created model is a new name, not resolved as an alias
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. } => None,
+            OllamaRequest::ModelInfo(model_name)
+            | OllamaRequest::ModelExists(model_name)
+            | OllamaRequest::ModelParameters(model_name)
+            | OllamaRequest::DeleteModel(model_name)
+            | OllamaRequest::CopyModel {
+                source: model_name, ..
+            } => Some(model_name),
+            OllamaRequest::GenerateRequest(request)
+            | OllamaRequest::GenerateStreamRequest(request) => Some(&mut request.model_name),
+            OllamaRequest::ChatRequest(request) => Some(&mut request.model_name),
+            OllamaRequest::GenerateEmbeddingsRequest(request) => Some(&mut request.model_name),
+            OllamaRequest::CountTokens { model, .. }
+            | OllamaRequest::Warmup(model)
+            | OllamaRequest::UnloadModel(model) => Some(model),
+            OllamaRequest::Raw { .. } => None,
+        }
+    }
+
+    /// Mutable getter of the format of the request, for the generation and chat requests
+    fn get_format_mut(&mut self) -> Option<&mut Option<FormatType>> {
+        match self {
+            OllamaRequest::GenerateRequest(request)
+            | OllamaRequest::GenerateStreamRequest(request) => Some(&mut request.format),
+            OllamaRequest::ChatRequest(request) => Some(&mut request.format),
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels
+            | OllamaRequest::ModelInfo(_)
+            | OllamaRequest::ModelExists(_)
+            | OllamaRequest::ModelParameters(_)
+            | OllamaRequest::GenerateEmbeddingsRequest(_)
+            | OllamaRequest::DeleteModel(_)
+            | OllamaRequest::CopyModel { .. }
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. }
+            | OllamaRequest::CountTokens { .. }
+            | OllamaRequest::Warmup(_)
+            | OllamaRequest::UnloadModel(_)
+            | OllamaRequest::Raw { .. } => None,
+        }
+    }
+
+    /// Mutable getter of the keep alive of the request, for the generation and chat requests
+    fn get_keep_alive_mut(&mut self) -> Option<&mut Option<KeepAlive>> {
+        match self {
+            OllamaRequest::GenerateRequest(request)
+            | OllamaRequest::GenerateStreamRequest(request) => Some(&mut request.keep_alive),
+            OllamaRequest::ChatRequest(request) => Some(&mut request.keep_alive),
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels
+            | OllamaRequest::ModelInfo(_)
+            | OllamaRequest::ModelExists(_)
+            | OllamaRequest::ModelParameters(_)
+            | OllamaRequest::GenerateEmbeddingsRequest(_)
+            | OllamaRequest::DeleteModel(_)
+            | OllamaRequest::CopyModel { .. }
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. }
+            | OllamaRequest::CountTokens { .. }
+            | OllamaRequest::Warmup(_)
+            | OllamaRequest::UnloadModel(_)
+            | OllamaRequest::Raw { .. } => None,
+        }
+    }
+
+    /// Getter of the request kind
+    pub fn get_kind(&self) -> OllamaRequestKind {
+        match self {
+            OllamaRequest::Ping => OllamaRequestKind::Ping,
+            OllamaRequest::Status => OllamaRequestKind::Status,
+            OllamaRequest::Version => OllamaRequestKind::Version,
+            OllamaRequest::ListLocalModels | OllamaRequest::ListRunningModels => {
+                OllamaRequestKind::List
+            }
+            OllamaRequest::ModelInfo(_)
+            | OllamaRequest::ModelExists(_)
+            | OllamaRequest::ModelParameters(_) => OllamaRequestKind::Info,
+            OllamaRequest::GenerateRequest(_) | OllamaRequest::GenerateStreamRequest(_) => {
+                OllamaRequestKind::Gen
+            }
+            OllamaRequest::ChatRequest(_) => OllamaRequestKind::Chat,
+            OllamaRequest::GenerateEmbeddingsRequest(_) => OllamaRequestKind::Embed,
+            OllamaRequest::DeleteModel(_)
+            | OllamaRequest::CopyModel { .. }
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. }
+            | OllamaRequest::UnloadModel(_) => OllamaRequestKind::Manage,
+            OllamaRequest::CountTokens { .. } => OllamaRequestKind::Count,
+            OllamaRequest::Warmup(_) => OllamaRequestKind::Warmup,
+            OllamaRequest::Raw { .. } => OllamaRequestKind::Raw,
+        }
+    }
+
+    /// Getter of the request type, used as metric attribute
+    fn get_type(&self) -> &'static str {
+        self.get_kind().as_str()
+    }
+
+    /// Method to know if the request only reads the backend state (no compute nor mutation)
+    pub fn is_introspection(&self) -> bool {
+        match self {
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels
+            | OllamaRequest::ModelInfo(_)
+            | OllamaRequest::ModelExists(_)
+            | OllamaRequest::ModelParameters(_) => true,
+            OllamaRequest::DeleteModel(_)
+            | OllamaRequest::CopyModel { .. }
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. }
+            | OllamaRequest::GenerateRequest(_)
+            | OllamaRequest::GenerateStreamRequest(_)
+            | OllamaRequest::ChatRequest(_)
+            | OllamaRequest::GenerateEmbeddingsRequest(_)
+            | OllamaRequest::CountTokens { .. }
+            | OllamaRequest::Warmup(_)
+            | OllamaRequest::UnloadModel(_)
+            | OllamaRequest::Raw { .. } => false,
+        }
+    }
+
+    /// Getter of the model loaded in memory by the backend to process the request
+    fn get_loaded_model_name(&self) -> Option<&str> {
+        match self {
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels
+            | OllamaRequest::ModelInfo(_)
+            | OllamaRequest::ModelExists(_)
+            | OllamaRequest::ModelParameters(_)
+            | OllamaRequest::DeleteModel(_)
+            | OllamaRequest::CopyModel { .. }
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. }
+            | OllamaRequest::UnloadModel(_)
+            | OllamaRequest::Raw { .. } => None,
+            OllamaRequest::GenerateRequest(_)
+            | OllamaRequest::GenerateStreamRequest(_)
+            | OllamaRequest::ChatRequest(_)
+            | OllamaRequest::GenerateEmbeddingsRequest(_)
+            | OllamaRequest::CountTokens { .. }
+            | OllamaRequest::Warmup(_) => self.get_model_name(),
+        }
+    }
+
+    /// Getter of the prompt length (in characters) of a generation or chat request
+    fn get_prompt_chars(&self) -> Option<usize> {
+        match self {
+            OllamaRequest::GenerateRequest(request)
+            | OllamaRequest::GenerateStreamRequest(request) => Some(request.prompt.chars().count()),
+            OllamaRequest::ChatRequest(request) => Some(
+                request
+                    .messages
+                    .iter()
+                    .map(|message| message.content.chars().count())
+                    .sum(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Span of the backend call of the request (named `ollama.<type>`, with the model), child of the current request span
+    fn backend_span(&self) -> Span {
+        info_span!(
+            "ollama.call",
+            otel.name = format!("ollama.{}", self.get_type()),
+            model = self.get_model_name(),
+        )
+    }
+
+    /// Call the Ollama API corresponding to the request, within its backend span
+    async fn call(
+        self,
+        ollama: &OllamaClient,
+        meters: &OllamaMeters,
+    ) -> Result<OllamaResponse, OllamaError> {
+        let span = self.backend_span();
+        self.call_api(ollama, meters).instrument(span).await
+    }
+
+    /// Call the Ollama API corresponding to the request
+    async fn call_api(
+        self,
+        ollama: &OllamaClient,
+        meters: &OllamaMeters,
+    ) -> Result<OllamaResponse, OllamaError> {
+        match self {
+            OllamaRequest::Ping => {
+                debug!("Ping");
+                let begin = Instant::now();
+                ollama.local_models().await?;
+                Ok(OllamaResponse::Pong(begin.elapsed()))
+            }
+            OllamaRequest::ListLocalModels => {
+                debug!("List local models");
+                Ok(OllamaResponse::LocalModels(ollama.local_models().await?))
+            }
+            // The status is answered by the processor from its settings and its pool, without calling a server
+            OllamaRequest::Status => Err(OllamaError::Other(
+                "The status is answered by the processor, not by an Ollama server".to_string(),
+            )),
+            OllamaRequest::Version => {
+                debug!("Version");
+                let version: ServerVersion = ollama.get_api("version").await?;
+                Ok(OllamaResponse::Version(version.version))
+            }
+            OllamaRequest::ListRunningModels => {
+                debug!("List running models");
+                let running_models: RunningModels = ollama.get_api("ps").await?;
+                Ok(OllamaResponse::RunningModels(running_models.models))
+            }
+            OllamaRequest::ModelInfo(model_name) => {
+                debug!("Model info {model_name}");
+                Ok(OllamaResponse::ModelInfo(
+                    ollama
+                        .post_api("show", &ShowModelRequest { model: &model_name })
+                        .await?,
+                ))
+            }
+            OllamaRequest::ModelExists(model_name) => {
+                debug!("Model exists {model_name}");
+                Ok(OllamaResponse::ModelExists(is_local_model(
+                    &ollama.local_models().await?,
+                    &model_name,
+                )))
+            }
+            OllamaRequest::DeleteModel(model_name) => {
+                debug!("Delete model {model_name}");
+                ollama.delete_model(model_name.clone()).await?;
+                Ok(OllamaResponse::ModelDeleted(model_name))
+            }
+            OllamaRequest::CopyModel {
+                source,
+                destination,
+            } => {
+                debug!("Copy model {source} to {destination}");
+                ollama.copy_model(source, destination).await?;
+                Ok(OllamaResponse::ModelCopied)
+            }
+            OllamaRequest::CreateModel { name, modelfile } => {
+                debug!("Create model {name}");
+                let mut statuses = ollama
+                    .create_model_stream(create_model_request(name.clone(), &modelfile)?)
+                    .await?;
+                while let Some(status) = statuses.next().await {
+                    info!("Create model {name}: {}", status?.message);
+                }
+                Ok(OllamaResponse::ModelCreated(name))
+            }
+            OllamaRequest::PushModel { name, insecure } => {
+                debug!("Push model {name}");
+                push_model(ollama, &name, insecure, meters).await?;
+                Ok(OllamaResponse::ModelPushed(name))
+            }
+            OllamaRequest::ModelParameters(model_name) => {
+                debug!("Model parameters {model_name}");
+                let model_info: ModelInfo = ollama
+                    .post_api("show", &ShowModelRequest { model: &model_name })
+                    .await?;
+                Ok(OllamaResponse::ModelParameters(parse_model_parameters(
+                    &model_info.parameters,
+                )))
+            }
+            OllamaRequest::GenerateRequest(request) => {
+                debug!("Generate");
+                Ok(ollama
+                    .post_api::<_, GenerationResponse>("generate", &request)
+                    .await?
+                    .into())
+            }
+            OllamaRequest::GenerateStreamRequest(request) => {
+                // Streams are handled by the processor, a stream request called directly is generated at once
+                debug!("Generate (not streamed)");
+                Ok(ollama
+                    .post_api::<_, GenerationResponse>("generate", &request)
+                    .await?
+                    .into())
+            }
+            OllamaRequest::ChatRequest(request) => {
+                debug!("Chat");
+                Ok(ollama
+                    .post_api::<_, ChatMessageResponse>("chat", &request)
+                    .await?
+                    .into())
+            }
+            OllamaRequest::GenerateEmbeddingsRequest(embeddings_request) => {
+                debug!("Generate embeddings");
+                Ok(ollama
+                    .post_api::<_, GenerateEmbeddingsResponse>("embed", &embeddings_request)
+                    .await?
+                    .into())
+            }
+            OllamaRequest::CountTokens { model, text } => {
+                debug!("Count tokens {model}");
+                let request = GenerationRequest::new(model, text)
+                    .raw(true)
+                    .options(ModelOptions::default().num_predict(1));
+                Ok(OllamaResponse::TokenCount(
+                    ollama
+                        .post_api::<_, GenerationResponse>("generate", &request)
+                        .await?
+                        .prompt_eval_count
+                        .unwrap_or_default(),
+                ))
+            }
+            OllamaRequest::Warmup(model) => {
+                debug!("Warmup {model}");
+                let request = GenerationRequest::new(model.clone(), "Hi")
+                    .options(ModelOptions::default().num_predict(1));
+                let response: GenerationResponse = ollama.post_api("generate", &request).await?;
+                Ok(OllamaResponse::WarmupResult {
+                    model,
+                    load_ms: response.load_duration.map(duration_ms).unwrap_or_default(),
+                    eval_ms: response.eval_duration.map(duration_ms).unwrap_or_default(),
+                })
+            }
+            OllamaRequest::UnloadModel(model) => {
+                debug!("Unload the model {model}");
+                let request = GenerationRequest::new(model.clone(), "")
+                    .keep_alive(KeepAlive::UnloadOnCompletion);
+                ollama
+                    .post_api::<_, GenerationResponse>("generate", &request)
+                    .await?;
+                Ok(OllamaResponse::ModelUnloaded(model))
+            }
+            OllamaRequest::Raw { method, path, body } => {
+                debug!("Raw {method} {path}");
+                Ok(OllamaResponse::Raw(
+                    ollama.call_raw(method, &path, body).await?,
+                ))
+            }
+        }
+    }
+}
+
+/// Request processed by the adaptor: an Ollama request to call the backend, or a response answered by the adaptor itself (e.g. from a cache)
+pub enum AdaptorRequest<'a, M> {
+    /// Request sent to the Ollama backend
+    Ollama(OllamaRequest<'a>),
+    /// Response returned to the sender without calling the backend
+    Response(M),
+}
+
+impl<'a, M> From<OllamaRequest<'a>> for AdaptorRequest<'a, M> {
+    fn from(request: OllamaRequest<'a>) -> Self {
+        AdaptorRequest::Ollama(request)
+    }
+}
+
+/// Token usage of a generation or chat response
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TokenUsage {
+    /// Number of tokens of the prompt
+    pub prompt_tokens: u64,
+    /// Number of generated tokens
+    pub eval_tokens: u64,
+}
+
+/// Ollama responses
+pub enum OllamaResponse {
+    /// Round-trip duration of a ping
+    Pong(Duration),
+    /// Status of the processor
+    Status {
+        /// Servers of the processor, without their credentials
+        urls: Vec<Url>,
+        /// Authentication to the servers: `none`, or the `bearer`, `basic` (URL credentials) and `mtls` modes joined by `+`
+        auth_mode: String,
+        /// Configured models
+        models: Vec<String>,
+        /// The servers passed the readiness probe, and one of them is healthy
+        backend_healthy: bool,
+        /// Status of each server of the pool
+        backends: Vec<BackendStatus>,
+    },
+    /// Version of the Ollama server
+    Version(String),
+    LocalModels(Vec<LocalModel>),
+    RunningModels(Vec<RunningModel>),
+    ModelInfo(ModelInfo),
+    /// Availability of the model on the server
+    ModelExists(bool),
+    GenerateResponse(Box<GenerationResponse>),
+    GenerateEmbeddingsResponse(Box<GenerateEmbeddingsResponse>),
+    ChatMessageResponse(Box<ChatMessageResponse>),
+    /// Name of the deleted model
+    ModelDeleted(String),
+    /// The model is copied
+    ModelCopied,
+    /// Name of the created model
+    ModelCreated(String),
+    /// Name of the pushed model
+    ModelPushed(String),
+    /// Default parameters of a model, by name. A parameter can have several values (e.g. `stop`)
+    ModelParameters(BTreeMap<String, Vec<String>>),
+    /// Number of tokens of the text
+    TokenCount(u64),
+    /// Load and eval durations of a warmup generation
+    WarmupResult {
+        model: String,
+        load_ms: u64,
+        eval_ms: u64,
+    },
+    /// Name of the model evicted from the backend memory
+    ModelUnloaded(String),
+    /// Embeddings response with the indices of the invalid vectors (empty, all zeros, NaN or infinite).
+    /// Only returned with the [`InvalidEmbeddingsPolicy::Mark`] policy when invalid vectors are found.
+    MarkedEmbeddingsResponse {
+        response: Box<GenerateEmbeddingsResponse>,
+        invalid_indices: Vec<usize>,
+    },
+    /// Embeddings response of a multi-input request rejected by the backend, with the error of each rejected input (index and message).
+    /// Only returned with `partial_embeddings`, the vector of a rejected input is empty.
+    PartialEmbeddingsResponse {
+        response: Box<GenerateEmbeddingsResponse>,
+        errors: Vec<(usize, String)>,
+    },
+    /// Body of the response to a raw request
+    Raw(Vec<u8>),
+}
+
+impl OllamaResponse {
+    /// Getter of the token usage of a generation or chat response (0 if not reported by the backend)
+    pub fn get_token_usage(&self) -> Option<TokenUsage> {
+        match self {
+            OllamaResponse::GenerateResponse(response) => Some(TokenUsage {
+                prompt_tokens: response.prompt_eval_count.unwrap_or_default(),
+                eval_tokens: response.eval_count.unwrap_or_default(),
+            }),
+            OllamaResponse::ChatMessageResponse(response) => Some(
+                response
+                    .final_data
+                    .as_ref()
+                    .map(|data| TokenUsage {
+                        prompt_tokens: data.prompt_eval_count,
+                        eval_tokens: data.eval_count,
+                    })
+                    .unwrap_or_default(),
+            ),
+            OllamaResponse::Pong(_)
+            | OllamaResponse::Version(_)
+            | OllamaResponse::Status { .. }
+            | OllamaResponse::LocalModels(_)
+            | OllamaResponse::RunningModels(_)
+            | OllamaResponse::ModelInfo(_)
+            | OllamaResponse::ModelExists(_)
+            | OllamaResponse::ModelParameters(_)
+            | OllamaResponse::ModelDeleted(_)
+            | OllamaResponse::ModelCopied
+            | OllamaResponse::ModelCreated(_)
+            | OllamaResponse::ModelPushed(_)
+            | OllamaResponse::GenerateEmbeddingsResponse(_)
+            | OllamaResponse::TokenCount(_)
+            | OllamaResponse::WarmupResult { .. }
+            | OllamaResponse::ModelUnloaded(_)
+            | OllamaResponse::MarkedEmbeddingsResponse { .. }
+            | OllamaResponse::PartialEmbeddingsResponse { .. }
+            | OllamaResponse::Raw(_) => None,
+        }
+    }
+
+    /// Getter of the response length (in characters) of a generation or chat response
+    fn get_response_chars(&self) -> Option<usize> {
+        match self {
+            OllamaResponse::GenerateResponse(response) => Some(response.response.chars().count()),
+            OllamaResponse::ChatMessageResponse(response) => {
+                Some(response.message.content.chars().count())
+            }
+            _ => None,
+        }
+    }
+
+    /// Getter of the license text, for a model info response
+    pub fn get_license(&self) -> Option<&str> {
+        match self {
+            OllamaResponse::ModelInfo(info) => Some(info.license.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Getter of the prompt template, for a model info response
+    pub fn get_template(&self) -> Option<&str> {
+        match self {
+            OllamaResponse::ModelInfo(info) => Some(info.template.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Getter of the default system prompt, for a model info response that declare one in its modelfile
+    pub fn get_system(&self) -> Option<&str> {
+        match self {
+            OllamaResponse::ModelInfo(info) => parse_modelfile(&info.modelfile)
+                .into_iter()
+                .find(|(instruction, _)| instruction.eq_ignore_ascii_case("SYSTEM"))
+                .map(|(_, system)| system.trim()),
+            _ => None,
+        }
+    }
+}
+
+impl From<GenerationResponse> for OllamaResponse {
+    fn from(response: GenerationResponse) -> Self {
+        OllamaResponse::GenerateResponse(Box::new(response))
+    }
+}
+
+impl From<GenerateEmbeddingsResponse> for OllamaResponse {
+    fn from(response: GenerateEmbeddingsResponse) -> Self {
+        OllamaResponse::GenerateEmbeddingsResponse(Box::new(response))
+    }
+}
+
+impl From<ChatMessageResponse> for OllamaResponse {
+    fn from(response: ChatMessageResponse) -> Self {
+        OllamaResponse::ChatMessageResponse(Box::new(response))
+    }
+}
+
+/// Convert a duration reported by the backend (in ns) to ms
+fn duration_ms(duration: u64) -> u64 {
+    duration / 1000000
+}
+
+/// Meters to log AI statistics
+struct OllamaMeters {
+    prompt_call_counter: Counter<u64>,
+    gen_call_counter: Counter<u64>,
+    token_histogram: Histogram<u64>,
+    bad_embeddings_counter: Counter<u64>,
+    empty_prompt_counter: Counter<u64>,
+    ping_histogram: Histogram<u64>,
+    unknown_service_counter: Counter<u64>,
+    retry_counter: Counter<u64>,
+    request_size_histogram: Histogram<u64>,
+    model_loaded_counter: Counter<u64>,
+    cold_start_counter: Counter<u64>,
+    model_unloaded_counter: Counter<u64>,
+    pull_progress_gauge: Gauge<u64>,
+    pull_eta_gauge: Gauge<u64>,
+    push_progress_gauge: Gauge<u64>,
+    embedding_counter: Counter<u64>,
+    request_error_counter: Counter<u64>,
+    latency_histogram: Histogram<u64>,
+    circuit_state_gauge: Gauge<u64>,
+    backend_selected_counter: Counter<u64>,
+    cache_hit_counter: Counter<u64>,
+    queue_depth_gauge: Gauge<u64>,
+    queue_wait_histogram: Histogram<u64>,
+    stream_cancelled_counter: Counter<u64>,
+    fallback_used_counter: Counter<u64>,
+    models_available_gauge: Gauge<u64>,
+}
+
+impl OllamaMeters {
+    fn new(meter: &Meter, prefix: &str) -> OllamaMeters {
+        OllamaMeters {
+            prompt_call_counter: meter
+                .u64_counter(format!("{prefix}_prompt_token_count"))
+                .with_description("Counter of prompt tokens")
+                .build(),
+            gen_call_counter: meter
+                .u64_counter(format!("{prefix}_gen_token_count"))
+                .with_description("Counter of generated tokens")
+                .build(),
+            token_histogram: meter
+                .u64_histogram(format!("{prefix}_token_histogram"))
+                .with_description("Histogram generations")
+                .build(),
+            bad_embeddings_counter: meter
+                .u64_counter(format!("{prefix}_bad_embeddings"))
+                .with_description("Counter of empty or degenerated embedding vectors")
+                .build(),
+            empty_prompt_counter: meter
+                .u64_counter(format!("{prefix}_empty_prompts"))
+                .with_description("Counter of generation requests with an empty prompt")
+                .build(),
+            ping_histogram: meter
+                .u64_histogram(format!("{prefix}_ping_ms"))
+                .with_description("Histogram of the backend round-trip latency (ms)")
+                .build(),
+            unknown_service_counter: meter
+                .u64_counter(format!("{prefix}_unknown_service"))
+                .with_description("Counter of requests for a service not handled by the processor")
+                .build(),
+            retry_counter: meter
+                .u64_counter(format!("{prefix}_retry_count"))
+                .with_description("Counter of Ollama call retries on recoverable errors")
+                .build(),
+            request_size_histogram: meter
+                .u64_histogram(format!("{prefix}_request_size"))
+                .with_description(
+                    "Histogram of request sizes (prompt characters, batch size, images)",
+                )
+                .build(),
+            model_loaded_counter: meter
+                .u64_counter(format!("{prefix}_model_loaded"))
+                .with_description("Counter of models preloaded at startup")
+                .build(),
+            cold_start_counter: meter
+                .u64_counter(format!("{prefix}_cold_starts"))
+                .with_description("Counter of the responses that loaded their model beyond the cold start threshold")
+                .build(),
+            model_unloaded_counter: meter
+                .u64_counter(format!("{prefix}_model_unloaded"))
+                .with_description("Counter of models unloaded on demand")
+                .build(),
+            pull_progress_gauge: meter
+                .u64_gauge(format!("{prefix}_pull_progress_bytes"))
+                .with_description("Bytes downloaded by the pull of a model")
+                .build(),
+            pull_eta_gauge: meter
+                .u64_gauge(format!("{prefix}_pull_eta_seconds"))
+                .with_description("Estimated time remaining of the pull of a model, 0 once completed")
+                .build(),
+            push_progress_gauge: meter
+                .u64_gauge(format!("{prefix}_push_progress"))
+                .with_description("Size in bytes of the layers pushed for a model")
+                .build(),
+            embedding_counter: meter
+                .u64_counter(format!("{prefix}_embedding_count"))
+                .with_description("Counter of embedding vectors produced")
+                .build(),
+            request_error_counter: meter
+                .u64_counter(format!("{prefix}_request_errors"))
+                .with_description("Counter of request errors")
+                .build(),
+            latency_histogram: meter
+                .u64_histogram(format!("{prefix}_request_latency_ms"))
+                .with_description("Histogram of request latencies, from the dequeue of the request to its response")
+                .build(),
+            models_available_gauge: meter
+                .u64_gauge(format!("{prefix}_models_available"))
+                .with_description("Number of the configured models (`configured` state), and of those available on an Ollama server (`available` state)")
+                .build(),
+            circuit_state_gauge: meter
+                .u64_gauge(format!("{prefix}_circuit_state"))
+                .with_description("State of the circuit breaker: 0 closed, 1 open, 2 half-open")
+                .build(),
+            backend_selected_counter: meter
+                .u64_counter(format!("{prefix}_backend_selected"))
+                .with_description("Counter of the Ollama servers selected for the requests")
+                .build(),
+            cache_hit_counter: meter
+                .u64_counter(format!("{prefix}_cache_hit"))
+                .with_description("Counter of the generations answered from the cache")
+                .build(),
+            queue_depth_gauge: meter
+                .u64_gauge(format!("{prefix}_queue_depth"))
+                .with_description("Number of messages waiting in the queue of the processor, and of requests waiting for a slot")
+                .build(),
+            queue_wait_histogram: meter
+                .u64_histogram(format!("{prefix}_queue_wait_ms"))
+                .with_description("Histogram of the time between the creation of a request and the start of its processing")
+                .build(),
+            fallback_used_counter: meter
+                .u64_counter(format!("{prefix}_fallback_used"))
+                .with_description("Counter of the requests sent to the fallback model, their model being missing")
+                .build(),
+            stream_cancelled_counter: meter
+                .u64_counter(format!("{prefix}_stream_cancelled"))
+                .with_description("Counter of the streamed generations cancelled before their end")
+                .build(),
+        }
+    }
+
+    /// Record the size of an Ollama request, with additional attributes
+    fn record_request(&self, request: &OllamaRequest<'_>, attributes: &[KeyValue]) {
+        let prompt_chars = request.get_prompt_chars().unwrap_or_default();
+        let sizes: &[(&str, usize)] = match request {
+            OllamaRequest::GenerateRequest(request)
+            | OllamaRequest::GenerateStreamRequest(request) => &[
+                ("prompt_chars", prompt_chars),
+                ("images", request.images.len()),
+            ],
+            OllamaRequest::GenerateEmbeddingsRequest(request) => match &request.input {
+                EmbeddingsInput::Single(input) => {
+                    &[("prompt_chars", input.chars().count()), ("batch_size", 1)]
+                }
+                EmbeddingsInput::Multiple(inputs) => &[
+                    (
+                        "prompt_chars",
+                        inputs.iter().map(|input| input.chars().count()).sum(),
+                    ),
+                    ("batch_size", inputs.len()),
+                ],
+            },
+            OllamaRequest::ChatRequest(request) => &[
+                ("prompt_chars", prompt_chars),
+                (
+                    "images",
+                    request
+                        .messages
+                        .iter()
+                        .map(|message| message.images.as_ref().map(Vec::len).unwrap_or_default())
+                        .sum(),
+                ),
+            ],
+            OllamaRequest::CountTokens { text, .. } => &[("prompt_chars", text.chars().count())],
+            OllamaRequest::Ping
+            | OllamaRequest::Status
+            | OllamaRequest::Version
+            | OllamaRequest::ListLocalModels
+            | OllamaRequest::ListRunningModels
+            | OllamaRequest::ModelInfo(_)
+            | OllamaRequest::ModelExists(_)
+            | OllamaRequest::ModelParameters(_)
+            | OllamaRequest::DeleteModel(_)
+            | OllamaRequest::CopyModel { .. }
+            | OllamaRequest::CreateModel { .. }
+            | OllamaRequest::PushModel { .. }
+            | OllamaRequest::Warmup(_)
+            | OllamaRequest::UnloadModel(_)
+            | OllamaRequest::Raw { .. } => &[],
+        };
+
+        let request_type = request.get_type();
+        let model = request.get_model_name().unwrap_or_default().to_string();
+        for (measure, size) in sizes {
+            self.request_size_histogram.record(
+                *size as u64,
+                &[
+                    &[
+                        KeyValue::new("type", request_type),
+                        KeyValue::new("model", model.clone()),
+                        KeyValue::new("measure", *measure),
+                    ],
+                    attributes,
+                ]
+                .concat(),
+            );
+        }
+    }
+
+    /// Record the statistics of an Ollama response, with additional attributes.
+    /// The `model` of the request is used for the responses that don't carry it
+    fn record(&self, response: &OllamaResponse, model: Option<&str>, attributes: &[KeyValue]) {
+        match response {
+            OllamaResponse::GenerateResponse(response) => self.record_generation(
+                "gen",
+                &response.model,
+                response.prompt_eval_count,
+                response.eval_count,
+                [
+                    ("total", response.total_duration),
+                    ("load", response.load_duration),
+                    ("prompt", response.prompt_eval_duration),
+                    ("eval", response.eval_duration),
+                ],
+                attributes,
+            ),
+            OllamaResponse::ChatMessageResponse(response) => {
+                if let Some(final_data) = &response.final_data {
+                    self.record_generation(
+                        "chat",
+                        &response.model,
+                        Some(final_data.prompt_eval_count),
+                        Some(final_data.eval_count),
+                        [
+                            ("total", Some(final_data.total_duration)),
+                            ("load", Some(final_data.load_duration)),
+                            ("prompt", Some(final_data.prompt_eval_duration)),
+                            ("eval", Some(final_data.eval_duration)),
+                        ],
+                        attributes,
+                    );
+                }
+            }
+            OllamaResponse::GenerateEmbeddingsResponse(response) => {
+                // The backend doesn't return the prompt token count of the embeddings
+                self.embedding_counter.add(
+                    response.embeddings.len() as u64,
+                    &[
+                        &[
+                            KeyValue::new("type", "embed"),
+                            KeyValue::new("model", model.unwrap_or_default().to_string()),
+                        ],
+                        attributes,
+                    ]
+                    .concat(),
+                );
+            }
+            OllamaResponse::PartialEmbeddingsResponse { response, errors } => {
+                // Only the vectors of the accepted inputs are produced
+                self.embedding_counter.add(
+                    response.embeddings.len().saturating_sub(errors.len()) as u64,
+                    &[
+                        &[
+                            KeyValue::new("type", "embed"),
+                            KeyValue::new("model", model.unwrap_or_default().to_string()),
+                        ],
+                        attributes,
+                    ]
+                    .concat(),
+                );
+            }
+            OllamaResponse::ModelUnloaded(model) => {
+                self.model_unloaded_counter.add(
+                    1,
+                    &[&[KeyValue::new("model", model.clone())], attributes].concat(),
+                );
+            }
+            OllamaResponse::Pong(duration) => {
+                self.ping_histogram
+                    .record(duration.as_millis() as u64, attributes);
+            }
+            OllamaResponse::Version(_)
+            | OllamaResponse::Status { .. }
+            | OllamaResponse::LocalModels(_)
+            | OllamaResponse::RunningModels(_)
+            | OllamaResponse::ModelInfo(_)
+            | OllamaResponse::ModelExists(_)
+            | OllamaResponse::ModelParameters(_)
+            | OllamaResponse::ModelDeleted(_)
+            | OllamaResponse::ModelCopied
+            | OllamaResponse::ModelCreated(_)
+            | OllamaResponse::ModelPushed(_)
+            | OllamaResponse::TokenCount(_)
+            | OllamaResponse::WarmupResult { .. }
+            | OllamaResponse::MarkedEmbeddingsResponse { .. }
+            | OllamaResponse::Raw(_) => {}
+        }
+    }
+
+    /// Record the number of the configured models, and of those available on a server
+    fn record_models_available(
+        &self,
+        settings: &OllamaProcSettings,
+        host: &str,
+        local_models: &[LocalModel],
+    ) {
+        let available = settings
+            .models
+            .iter()
+            .filter(|model| is_local_model(local_models, settings.resolve_model(model)))
+            .count();
+        self.models_available_gauge.record(
+            settings.models.len() as u64,
+            &[
+                KeyValue::new("host", host.to_string()),
+                KeyValue::new("state", "configured"),
+            ],
+        );
+        self.models_available_gauge.record(
+            available as u64,
+            &[
+                KeyValue::new("host", host.to_string()),
+                KeyValue::new("state", "available"),
+            ],
+        );
+    }
+
+    /// Count a cold start if the load duration of a generation or chat response exceeds the threshold
+    fn record_cold_start(&self, response: &OllamaResponse, threshold: Duration) {
+        let (model, load_duration) = match response {
+            OllamaResponse::GenerateResponse(response) => (&response.model, response.load_duration),
+            OllamaResponse::ChatMessageResponse(response) => (
+                &response.model,
+                response
+                    .final_data
+                    .as_ref()
+                    .map(|final_data| final_data.load_duration),
+            ),
+            _ => return,
+        };
+        if load_duration
+            .is_some_and(|load_duration| Duration::from_nanos(load_duration) > threshold)
+        {
+            self.cold_start_counter
+                .add(1, &[KeyValue::new("model", model.clone())]);
+        }
+    }
+
+    /// Record the token counts and durations (in ns) of a generation
+    fn record_generation(
+        &self,
+        generation_type: &'static str,
+        model: &str,
+        prompt_eval_count: Option<u64>,
+        eval_count: Option<u64>,
+        durations: [(&'static str, Option<u64>); 4],
+        attributes: &[KeyValue],
+    ) {
+        let model = KeyValue::new("model", model.to_string());
+        if let Some(prompt_eval_count) = prompt_eval_count {
+            self.prompt_call_counter.add(
+                prompt_eval_count,
+                &[
+                    &[KeyValue::new("type", generation_type), model.clone()],
+                    attributes,
+                ]
+                .concat(),
+            );
+        }
+        if let Some(eval_count) = eval_count {
+            self.gen_call_counter.add(
+                eval_count,
+                &[
+                    &[KeyValue::new("type", generation_type), model.clone()],
+                    attributes,
+                ]
+                .concat(),
+            );
+        }
+        for (duration_type, duration) in durations {
+            if let Some(duration) = duration {
+                self.token_histogram.record(
+                    duration_ms(duration),
+                    &[
+                        &[KeyValue::new("type", duration_type), model.clone()],
+                        attributes,
+                    ]
+                    .concat(),
+                );
+            }
+        }
+    }
+}
+
+/// Prompt and response pair sampled for offline evaluation
+#[derive(Debug, Clone, Serialize)]
+pub struct EvalSample {
+    /// Service of the request
+    pub service: String,
+    /// Model that generated the response
+    pub model: String,
+    /// Prompt of the request
+    pub prompt: String,
+    /// Generated response
+    pub response: String,
+}
+
+/// Maximum number of evaluation samples waiting to be written, samples are dropped beyond it
+const EVAL_SAMPLE_QUEUE_SIZE: usize = 256;
+
+/// Sampler of the generation requests, writing the samples to a file in a separate task
+struct EvalSampler {
+    rate: f64,
+    count: u64,
+    sender: Option<mpsc::Sender<EvalSample>>,
+}
+
+impl EvalSampler {
+    fn new(rate: f64, file: Option<&PathBuf>) -> Self {
+        let sender = file.filter(|_| rate > 0.0).map(|file| {
+            let (sender, mut receiver) = mpsc::channel::<EvalSample>(EVAL_SAMPLE_QUEUE_SIZE);
+            let file = file.clone();
+            tokio::spawn(async move {
+                let mut output = match tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&file)
+                    .await
+                {
+                    Ok(output) => output,
+                    Err(e) => {
+                        warn!("Can't open the eval sampling file {}: {e}", file.display());
+                        return;
+                    }
+                };
+                while let Some(sample) = receiver.recv().await {
+                    match serde_json::to_string(&sample) {
+                        Ok(mut line) => {
+                            line.push('\n');
+                            if let Err(e) = output.write_all(line.as_bytes()).await {
+                                warn!("Can't write the eval sample to {}: {e}", file.display());
+                            }
+                        }
+                        Err(e) => warn!("Can't serialize the eval sample: {e}"),
+                    }
+                }
+            });
+            sender
+        });
+
+        EvalSampler {
+            rate: rate.clamp(0.0, 1.0),
+            count: 0,
+            sender,
+        }
+    }
+
+    /// Method to know if the next request should be sampled. Samples are evenly spread to respect the rate
+    fn should_sample(&mut self) -> bool {
+        if self.sender.is_none() {
+            return false;
+        }
+
+        let previous = (self.count as f64 * self.rate).floor();
+        self.count = self.count.wrapping_add(1);
+        (self.count as f64 * self.rate).floor() > previous
+    }
+
+    /// Queue a sample to be written, without waiting
+    fn send(&self, sample: EvalSample) {
+        if let Some(sender) = &self.sender
+            && sender.try_send(sample).is_err()
+        {
+            debug!("Eval sampling queue full, the sample is dropped");
+        }
+    }
+}
+
+/// Cache of the model information, to avoid asking the backend on each request
+#[derive(Debug, Default)]
+struct ModelInfoCache {
+    models: HashMap<String, (Instant, ModelInfo)>,
+}
+
+impl ModelInfoCache {
+    /// Getter of the model information, if it was cached within the `ttl`
+    fn get(&self, model: &str, ttl: Duration) -> Option<ModelInfo> {
+        self.models
+            .get(model)
+            .filter(|(cached, _)| cached.elapsed() < ttl)
+            .map(|(_, info)| info.clone())
+    }
+
+    /// Update the cache with the response of a request. The models deleted or (re)created are invalidated
+    fn update(&mut self, request: &OllamaRequest<'_>, response: &OllamaResponse) {
+        match (request, response) {
+            (OllamaRequest::ModelInfo(model), OllamaResponse::ModelInfo(info)) => {
+                self.models
+                    .insert(model.clone(), (Instant::now(), info.clone()));
+            }
+            (OllamaRequest::DeleteModel(model), _)
+            | (OllamaRequest::CreateModel { name: model, .. }, _)
+            | (
+                OllamaRequest::CopyModel {
+                    destination: model, ..
+                },
+                _,
+            ) => {
+                self.models.remove(model);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// State of the circuit breaker around the Ollama backend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum CircuitState {
+    /// Requests are sent to the backend
+    #[default]
+    Closed,
+    /// Requests are rejected since the given instant
+    Open(Instant),
+    /// A probe request is sent to the backend since the given instant, the other requests are rejected
+    HalfOpen(Instant),
+}
+
+impl CircuitState {
+    /// Value of the state for the `prosa_ollama_circuit_state` gauge
+    fn gauge_value(&self) -> u64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::Open(_) => 1,
+            CircuitState::HalfOpen(_) => 2,
+        }
+    }
+
+    /// Getter of the state name
+    fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open(_) => "open",
+            CircuitState::HalfOpen(_) => "half_open",
+        }
+    }
+}
+
+/// Circuit breaker, to reject the requests without calling a failing backend
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    state: CircuitState,
+    consecutive_failures: u32,
+}
+
+impl CircuitBreaker {
+    /// Check if a request can be sent to the backend. After the `cooldown`, a single probe request is let through.
+    /// If that probe never records its outcome (dropped or hung), another probe is let through after a new `cooldown`
+    fn allow(&mut self, cooldown: Duration, meters: &OllamaMeters) -> Result<(), OllamaError> {
+        match self.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open(since) | CircuitState::HalfOpen(since)
+                if since.elapsed() >= cooldown =>
+            {
+                debug!("Probe the Ollama backend after the circuit breaker cool-down");
+                self.set_state(CircuitState::HalfOpen(Instant::now()), meters);
+                Ok(())
+            }
+            CircuitState::Open(_) | CircuitState::HalfOpen(_) => Err(OllamaError::CircuitOpen),
+        }
+    }
+
+    /// Record the outcome of a backend call. A failure of the probe reopens the circuit
+    fn record(&mut self, failure: bool, threshold: u32, meters: &OllamaMeters) {
+        if failure {
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+            if matches!(self.state, CircuitState::HalfOpen(_))
+                || self.consecutive_failures >= threshold
+            {
+                if self.state == CircuitState::Closed {
+                    warn!(
+                        "Circuit breaker opened after {} consecutive failures of the Ollama backend",
+                        self.consecutive_failures
+                    );
+                }
+                self.set_state(CircuitState::Open(Instant::now()), meters);
+            }
+        } else {
+            if self.state != CircuitState::Closed {
+                info!("Circuit breaker closed, the Ollama backend is back");
+                self.set_state(CircuitState::Closed, meters);
+            }
+            self.consecutive_failures = 0;
+        }
+    }
+
+    fn set_state(&mut self, state: CircuitState, meters: &OllamaMeters) {
+        self.state = state;
+        meters.circuit_state_gauge.record(state.gauge_value(), &[]);
+    }
+}
+
+/// Key of a generation request in the cache: the serialized request, indexed by its hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GenerationCacheKey {
+    hash: u64,
+    request: String,
+}
+
+/// Cache of the generation responses, from the least to the most recently used.
+/// A response is only returned for the very request it was cached for, a hash collision is a miss
+#[derive(Debug, Default)]
+struct GenerationCache {
+    responses: HashMap<u64, (Instant, String, GenerationResponse)>,
+    order: VecDeque<u64>,
+}
+
+impl GenerationCache {
+    /// Key of a generation request in the cache, the request without its keep alive.
+    /// Only the deterministic generations (temperature set to 0) have a key, unless `nondeterministic` is set
+    fn key(request: &OllamaRequest<'_>, nondeterministic: bool) -> Option<GenerationCacheKey> {
+        let OllamaRequest::GenerateRequest(request) = request else {
+            return None;
+        };
+        if !nondeterministic
+            && serde_json::to_value(&request.options)
+                .ok()
+                .and_then(|options| options.get("temperature")?.as_f64())
+                != Some(0.0)
+        {
+            return None;
+        }
+
+        let mut request = request.as_ref().clone();
+        request.keep_alive = None;
+        let request = serde_json::to_string(&request).ok()?;
+        let mut hasher = DefaultHasher::new();
+        request.hash(&mut hasher);
+        Some(GenerationCacheKey {
+            hash: hasher.finish(),
+            request,
+        })
+    }
+
+    /// Getter of a cached response, if it was cached for the same request within the `ttl`. The response becomes the most recently used
+    fn get(
+        &mut self,
+        key: &GenerationCacheKey,
+        ttl: Option<Duration>,
+    ) -> Option<GenerationResponse> {
+        let (cached, request, response) = self.responses.get(&key.hash)?;
+        if *request != key.request {
+            return None;
+        }
+        if ttl.is_some_and(|ttl| cached.elapsed() >= ttl) {
+            self.responses.remove(&key.hash);
+            self.order.retain(|k| *k != key.hash);
+            return None;
+        }
+
+        let response = response.clone();
+        self.order.retain(|k| *k != key.hash);
+        self.order.push_back(key.hash);
+        Some(response)
+    }
+
+    /// Cache a response, evicting the least recently used ones beyond the `capacity`.
+    /// A response cached for another request with the same hash is replaced
+    fn insert(&mut self, key: GenerationCacheKey, response: GenerationResponse, capacity: usize) {
+        if self
+            .responses
+            .insert(key.hash, (Instant::now(), key.request, response))
+            .is_some()
+        {
+            self.order.retain(|k| *k != key.hash);
+        }
+        self.order.push_back(key.hash);
+        while self.order.len() > capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.responses.remove(&evicted);
+            }
+        }
+    }
+}
+
+/// Embeddings request waiting for its batch
+struct EmbeddingsBatchEntry {
+    /// Request without its input, to batch the requests with the same model and options
+    key: String,
+    request: GenerateEmbeddingsRequest,
+    /// Embeddings of the request inputs, the error of the batch, or `None` if the batch was rejected because of one of its requests
+    response: oneshot::Sender<Option<Result<GenerateEmbeddingsResponse, Arc<OllamaError>>>>,
+}
+
+/// Batcher of the embeddings requests, to send the requests of a same model in a single backend call
+struct EmbeddingsBatcher {
+    queue: mpsc::Sender<EmbeddingsBatchEntry>,
+}
+
+impl EmbeddingsBatcher {
+    /// Submit a request to its batch, and wait for its embeddings or the error of the batch.
+    /// Return `None` if the batch was rejected because of one of its requests, so the request can be sent alone to get its own error
+    async fn submit(
+        &self,
+        mut request: GenerateEmbeddingsRequest,
+    ) -> Option<Result<GenerateEmbeddingsResponse, OllamaError>> {
+        let input = std::mem::take(&mut request.input);
+        let key = serde_json::to_string(&request).ok()?;
+        request.input = input;
+
+        let (response, batched) = oneshot::channel();
+        self.queue
+            .send(EmbeddingsBatchEntry {
+                key,
+                request,
+                response,
+            })
+            .await
+            .ok()?;
+        batched
+            .await
+            .ok()
+            .flatten()
+            .map(|batched| batched.map_err(OllamaError::Batch))
+    }
+}
+
+/// Batch of embeddings requests with the same model and options
+struct EmbeddingsBatch {
+    /// Instant when the batch is sent, at the end of its window
+    deadline: Instant,
+    /// Number of inputs of the batch
+    size: usize,
+    entries: Vec<EmbeddingsBatchEntry>,
+}
+
+impl EmbeddingsBatch {
+    /// Send the batch in a single call, and give each request the embeddings of its inputs
+    async fn call<M, A>(self, ctx: &RequestContext<M, A>)
+    where
+        M: 'static
+            + std::marker::Send
+            + std::marker::Sync
+            + std::marker::Sized
+            + std::clone::Clone
+            + std::fmt::Debug
+            + Tvf
+            + std::default::Default,
+        A: OllamaAdaptor<M>,
+    {
+        let mut batch_request: Option<GenerateEmbeddingsRequest> = None;
+        let mut inputs = Vec::with_capacity(self.size);
+        let mut senders = Vec::with_capacity(self.entries.len());
+        for mut entry in self.entries {
+            let entry_inputs = match std::mem::take(&mut entry.request.input) {
+                EmbeddingsInput::Single(input) => vec![input],
+                EmbeddingsInput::Multiple(entry_inputs) => entry_inputs,
+            };
+            senders.push((entry_inputs.len(), entry.response));
+            inputs.extend(entry_inputs);
+            batch_request.get_or_insert(entry.request);
+        }
+        let Some(mut batch_request) = batch_request else {
+            return;
+        };
+
+        debug!(
+            "Generate embeddings for a batch of {} requests ({} inputs)",
+            senders.len(),
+            inputs.len()
+        );
+        let size = inputs.len();
+        batch_request.input = EmbeddingsInput::Multiple(inputs);
+        match call_with_auth_refresh(
+            ctx,
+            OllamaRequest::GenerateEmbeddingsRequest(Box::new(batch_request)),
+        )
+        .await
+        {
+            Ok(OllamaResponse::GenerateEmbeddingsResponse(response))
+                if response.embeddings.len() == size =>
+            {
+                let mut embeddings = response.embeddings.into_iter();
+                for (count, sender) in senders {
+                    let _ = sender.send(Some(Ok(GenerateEmbeddingsResponse {
+                        embeddings: embeddings.by_ref().take(count).collect(),
+                    })));
+                }
+            }
+            // One of the requests may be invalid, each of them is sent alone to get its own error
+            Err(e) if is_bad_request(&e) => {
+                debug!("The embeddings batch was rejected, its requests are sent alone: {e}");
+                for (_, sender) in senders {
+                    let _ = sender.send(None);
+                }
+            }
+            result => {
+                let error = Arc::new(match result {
+                    Err(e) => e,
+                    Ok(_) => OllamaError::Other(format!(
+                        "Unexpected response to the embeddings batch of {size} inputs"
+                    )),
+                });
+                debug!("The embeddings batch failed: {error}");
+                for (_, sender) in senders {
+                    let _ = sender.send(Some(Err(error.clone())));
+                }
+            }
+        }
+    }
+}
+
+/// Gather the embeddings requests in batches, sent once full or at the end of their window
+async fn run_embeddings_batcher<M, A>(
+    ctx: Weak<RequestContext<M, A>>,
+    mut queue: mpsc::Receiver<EmbeddingsBatchEntry>,
+    window: Duration,
+    max_batch_size: usize,
+) where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: 'static + OllamaAdaptor<M> + std::marker::Send + std::marker::Sync,
+{
+    let mut batches: HashMap<String, EmbeddingsBatch> = HashMap::new();
+    loop {
+        let next_deadline = batches.values().map(|batch| batch.deadline).min();
+        tokio::select! {
+            entry = queue.recv() => match entry {
+                Some(entry) => {
+                    let size = match &entry.request.input {
+                        EmbeddingsInput::Single(_) => 1,
+                        EmbeddingsInput::Multiple(inputs) => inputs.len(),
+                    };
+                    let batch = batches.entry(entry.key.clone()).or_insert_with(|| EmbeddingsBatch {
+                        deadline: Instant::now() + window,
+                        size: 0,
+                        entries: Vec::new(),
+                    });
+                    batch.size += size;
+                    batch.entries.push(entry);
+                }
+                // The processor is stopped
+                None => break,
+            },
+            _ = tokio::time::sleep_until(next_deadline.unwrap_or_else(Instant::now).into()), if next_deadline.is_some() => {}
+        }
+
+        let now = Instant::now();
+        let ready: Vec<String> = batches
+            .iter()
+            .filter(|(_, batch)| batch.size >= max_batch_size || batch.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in ready {
+            if let Some(batch) = batches.remove(&key)
+                && let Some(ctx) = ctx.upgrade()
+            {
+                tokio::spawn(async move { batch.call(&ctx).await }.in_current_span());
+            }
+        }
+    }
+}
+
+/// Request waiting for a slot in the priority queue
+struct PendingRequest<M>
+where
+    M: Sized + Clone + Tvf,
+{
+    msg: RequestMsg<M>,
+    priority: u8,
+    dequeued: Instant,
+}
+
+/// Requests waiting for a slot when `max_concurrent_requests` is reached, served from the highest priority.
+/// A waiting request gains a priority level every `priority_aging`, so the low priority requests are eventually served
+struct PriorityQueue<M>
+where
+    M: Sized + Clone + Tvf,
+{
+    requests: Vec<PendingRequest<M>>,
+}
+
+impl<M> PriorityQueue<M>
+where
+    M: Sized + Clone + Tvf,
+{
+    fn new() -> Self {
+        PriorityQueue {
+            requests: Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    fn push(&mut self, request: PendingRequest<M>) {
+        self.requests.push(request);
+    }
+
+    /// Pop the request with the highest priority (aged by its wait), the oldest first for a same priority
+    fn pop(&mut self, aging: Duration) -> Option<PendingRequest<M>> {
+        let effective_priority = |request: &PendingRequest<M>| {
+            let aged = if aging.is_zero() {
+                u128::MAX
+            } else {
+                request.dequeued.elapsed().as_nanos() / aging.as_nanos()
+            };
+            (request.priority as u128).saturating_add(aged)
+        };
+        let index = self
+            .requests
+            .iter()
+            .enumerate()
+            .max_by(|(i, a), (j, b)| {
+                effective_priority(a)
+                    .cmp(&effective_priority(b))
+                    .then(b.dequeued.cmp(&a.dequeued))
+                    .then(j.cmp(i))
+            })
+            .map(|(index, _)| index)?;
+        Some(self.requests.swap_remove(index))
+    }
+
+    /// Take all the waiting requests, e.g. on shutdown
+    fn drain(&mut self) -> std::vec::Drain<'_, PendingRequest<M>> {
+        self.requests.drain(..)
+    }
+}
+
+/// Spawn the ramp-up of the concurrency: all the slots but one are held, and released one by one over the ramp-up duration.
+/// Aborting the ramp-up releases the slots still held
+fn spawn_concurrency_rampup(
+    semaphore: &Arc<Semaphore>,
+    max_concurrent_requests: usize,
+    rampup_duration: Duration,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let held_slots = max_concurrent_requests
+        .checked_sub(1)
+        .filter(|slots| *slots > 0)?;
+    let mut held = semaphore
+        .clone()
+        .try_acquire_many_owned(u32::try_from(held_slots).ok()?)
+        .ok()?;
+    let step = rampup_duration / held_slots as u32;
+    Some(tokio::spawn(async move {
+        while held.num_permits() > 0 {
+            tokio::time::sleep(step).await;
+            drop(held.split(1));
+        }
+        debug!("Concurrency ramped up to {max_concurrent_requests} requests");
+    }))
+}
+
+/// Spawn the processing of a request, holding a slot of `max_concurrent_requests` until it's answered
+fn spawn_request<M, A>(
+    context: &Arc<RequestContext<M, A>>,
+    service_table: Arc<ServiceTable<M>>,
+    msg: RequestMsg<M>,
+    dequeued: Instant,
+    permit: OwnedSemaphorePermit,
+) where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: 'static + OllamaAdaptor<M> + std::marker::Send + std::marker::Sync,
+{
+    // The wait ends once the request holds a slot, out of the queue and of the waiting requests
+    context.meters.queue_wait_histogram.record(
+        msg.elapsed().as_millis() as u64,
+        &[KeyValue::new("service", msg.get_service().clone())],
+    );
+    let context = context.clone();
+    let span = msg.get_span().clone();
+    tokio::spawn(
+        async move {
+            if let Err(e) = handle_request(&context, service_table, msg, dequeued).await {
+                warn!("Can't return the Ollama response: {e}");
+            }
+            drop(permit);
+        }
+        .instrument(span),
+    );
+}
+
+/// Models loaded by the backend for the processor, from the least to the most recently used
+#[derive(Debug, Default)]
+struct LoadedModels {
+    models: VecDeque<String>,
+}
+
+impl LoadedModels {
+    /// Mark the model as the most recently used, and return the models to unload to stay within `max_loaded_models`
+    fn touch(&mut self, model: &str, max_loaded_models: usize) -> Vec<String> {
+        if let Some(index) = self.models.iter().position(|m| m == model) {
+            if let Some(model) = self.models.remove(index) {
+                self.models.push_back(model);
+            }
+            Vec::new()
+        } else {
+            let mut unloaded = Vec::new();
+            while !self.models.is_empty() && self.models.len() >= max_loaded_models {
+                unloaded.extend(self.models.pop_front());
+            }
+            self.models.push_back(model.to_string());
+            unloaded
+        }
+    }
+
+    /// Forget a model unloaded on demand
+    fn remove(&mut self, model: &str) {
+        self.models.retain(|m| m != model);
+    }
+
+    /// Reconcile the tracked models with the models loaded by the servers (a model without tag is the `latest` one).
+    /// The models unloaded by the backend (e.g. expired keep alive) are forgotten, and the models loaded by other clients are tracked as the least recently used
+    fn reconcile(&mut self, running_models: &[RunningModel]) {
+        fn base_name(model: &str) -> &str {
+            model.strip_suffix(":latest").unwrap_or(model)
+        }
+
+        self.models.retain(|model| {
+            running_models
+                .iter()
+                .any(|running_model| base_name(&running_model.name) == base_name(model))
+        });
+        for running_model in running_models {
+            if !self
+                .models
+                .iter()
+                .any(|model| base_name(model) == base_name(&running_model.name))
+            {
+                self.models.push_front(running_model.name.clone());
+            }
+        }
+    }
+}
+
+/// State of the processor shared by the requests in flight
+struct RequestContext<M, A>
+where
+    M: Sized + Clone + Tvf,
+{
+    /// Settings of the processor, replaced on a configuration reload
+    settings: RwLock<Arc<OllamaProcSettings>>,
+    /// Ollama clients, replaced when the auth token is refreshed
+    ollama: RwLock<OllamaPool>,
+    /// Lock of the auth token refresh, so concurrent rejections refresh it once
+    auth_refresh: Mutex<()>,
+    adaptor: Mutex<A>,
+    meters: OllamaMeters,
+    /// Models loaded by the backend, to respect `max_loaded_models`
+    loaded_models: Mutex<LoadedModels>,
+    /// Sampler of prompts and responses for offline evaluation
+    eval_sampler: Mutex<EvalSampler>,
+    /// Signal to abort the Ollama calls in flight, once the shutdown grace period elapsed
+    shutdown: watch::Sender<bool>,
+    /// Cache of the model information, to respect `model_info_cache_ttl`
+    model_info_cache: Mutex<ModelInfoCache>,
+    /// Circuit breaker around the backend, to respect `circuit_breaker_threshold`
+    circuit_breaker: Mutex<CircuitBreaker>,
+    /// Cache of the generation responses, to respect `generation_cache_capacity`
+    generation_cache: Mutex<GenerationCache>,
+    /// Batcher of the embeddings requests, to respect `embeddings_batch_window`
+    embeddings_batcher: Option<EmbeddingsBatcher>,
+    phantom: PhantomData<M>,
+}
+
+impl<M, A> RequestContext<M, A>
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: OllamaAdaptor<M>,
+{
+    /// Getter of the current settings
+    async fn settings(&self) -> Arc<OllamaProcSettings> {
+        self.settings.read().await.clone()
+    }
+
+    /// Getter of the current client of the next healthy Ollama server, round-robin
+    async fn ollama(&self) -> Result<OllamaClient, OllamaError> {
+        let ollama = self
+            .ollama
+            .read()
+            .await
+            .select()
+            .cloned()
+            .ok_or(OllamaError::NoHealthyBackend)?;
+        self.meters
+            .backend_selected_counter
+            .add(1, &[KeyValue::new("host", ollama.host().to_string())]);
+        Ok(ollama)
+    }
+
+    /// Start a call to a server, to record its outcome
+    async fn backend_call(&self, ollama: &OllamaClient) -> BackendCall {
+        BackendCall {
+            ollama: ollama.clone(),
+            unhealthy_threshold: self.settings().await.backend_unhealthy_threshold,
+            recorded: false,
+        }
+    }
+
+    /// Getter of the current clients of all the Ollama servers
+    async fn ollama_pool(&self) -> OllamaPool {
+        self.ollama.read().await.clone()
+    }
+
+    /// Getter of the status of every server of the pool
+    async fn backend_statuses(&self) -> Vec<BackendStatus> {
+        let circuit_state = self.circuit_breaker.lock().await.state.as_str();
+        self.ollama_pool()
+            .await
+            .clients()
+            .iter()
+            .map(|ollama| ollama.status(circuit_state))
+            .collect()
+    }
+
+    /// Unload models from every server of the pool (request with a `keep_alive` of 0), each call bounded by the `request_timeout`
+    async fn unload_models(&self, models: &[String]) {
+        let request_timeout = self.settings().await.request_timeout;
+        for model in models {
+            debug!("Unload the model {model}");
+            // The model may be loaded by every server of the pool
+            for ollama in self.ollama_pool().await.clients() {
+                let unload = GenerationRequest::new(model.clone(), "")
+                    .keep_alive(KeepAlive::UnloadOnCompletion);
+                if let Err(e) = with_request_timeout(
+                    request_timeout,
+                    ollama.post_api::<_, GenerationResponse>("generate", &unload),
+                )
+                .await
+                {
+                    warn!(
+                        "Fail to unload the model {model} from {}: {e}",
+                        ollama.host()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Replace the Ollama clients with the ones of reloaded settings, the servers kept in the pool keep their health.
+    /// The calls in flight on the replaced clients can finish within the `drain_timeout`, the new calls use the new clients
+    async fn reload_ollama(&self, pool: OllamaPool, drain_timeout: Duration) -> OllamaPool {
+        let (replaced, pool) = {
+            let mut ollama = self.ollama.write().await;
+            let pool = pool.with_health_of(&ollama);
+            (std::mem::replace(&mut *ollama, pool.clone()), pool)
+        };
+        tokio::spawn(replaced.retire(drain_timeout).in_current_span());
+        pool
+    }
+
+    /// Replace the Ollama clients with a new auth token, after the server rejected the token of the `rejected` client.
+    /// The health of the servers is kept. Return the rejection error if no new token can be fetched.
+    /// The token is refreshed once for concurrent rejections: the calls rejected by a replaced pool get a client of the current one
+    async fn refresh_ollama(
+        &self,
+        rejected: &OllamaClient,
+        error: OllamaError,
+    ) -> Result<OllamaClient, OllamaError> {
+        let _refresh = self.auth_refresh.lock().await;
+        if self.ollama.read().await.generation != rejected.generation {
+            debug!("The Ollama auth token was already refreshed");
+            return self.ollama().await;
+        }
+
+        let settings = self.settings().await;
+        // The adaptor is not locked while the token is fetched from the settings
+        let token = self.adaptor.lock().await.refresh_auth_token(&settings);
+        let token = match token {
+            Ok(None) => settings.refresh_auth_token().await,
+            token => token,
+        };
+        match token {
+            Ok(Some(token)) => {
+                info!("Refreshed the Ollama auth token");
+                let ollama = settings.get_ollama_with_token(&token)?;
+                let mut pool = self.ollama.write().await;
+                *pool = ollama.with_health_of(&pool);
+                drop(pool);
+                self.ollama().await
+            }
+            Ok(None) => Err(error),
+            Err(refresh_err) => {
+                warn!("Can't refresh the Ollama auth token: {refresh_err}");
+                Err(error)
+            }
+        }
+    }
+}
+
+/// Bound a call of the processor itself (not of a service request) by the `request_timeout`, if set
+async fn with_request_timeout<T, F>(
+    request_timeout: Option<Duration>,
+    call: F,
+) -> Result<T, OllamaError>
+where
+    F: Future<Output = Result<T, OllamaError>>,
+{
+    match request_timeout {
+        Some(request_timeout) => tokio::time::timeout(request_timeout, call)
+            .await
+            .unwrap_or_else(|_| {
+                Err(OllamaError::Other(format!(
+                    "No reply of the Ollama server within {request_timeout:?}"
+                )))
+            }),
+        None => call.await,
+    }
+}
+
+/// Method to know if a model is in the local models. A model without tag is the `latest` one
+fn is_local_model(local_models: &[LocalModel], model: &str) -> bool {
+    local_models.iter().any(|local_model| {
+        local_model.name == model
+            || (!model.contains(':')
+                && local_model
+                    .name
+                    .strip_prefix(model)
+                    .is_some_and(|tag| tag == ":latest"))
+    })
+}
+
+/// Probe the servers of the pool until they answer, with an interval doubling from `readiness_probe_interval`, within `readiness_max_wait`
+async fn wait_for_readiness(
+    pool: &OllamaPool,
+    settings: &OllamaProcSettings,
+) -> Result<(), OllamaError> {
+    let readiness_begin = Instant::now();
+    let mut probe_interval = settings.readiness_probe_interval;
+    for ollama in pool.clients() {
+        loop {
+            match ollama.get_api::<ServerVersion>("version").await {
+                Ok(version) => {
+                    info!(
+                        "Ollama server {} {} ready after {:?}",
+                        ollama.host(),
+                        version.version,
+                        readiness_begin.elapsed()
+                    );
+                    break;
+                }
+                Err(e)
+                    if readiness_begin.elapsed() + probe_interval
+                        <= settings.readiness_max_wait =>
+                {
+                    debug!(
+                        "Ollama server {} not ready ({e}), next probe in {probe_interval:?}",
+                        ollama.host()
+                    );
+                    tokio::time::sleep(probe_interval).await;
+                    probe_interval *= 2;
+                }
+                Err(e) => {
+                    warn!(
+                        "Ollama server {} not ready after {:?}",
+                        ollama.host(),
+                        readiness_begin.elapsed()
+                    );
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// List the local models of a server, and check that all the configured models are available, to fail clearly on a misspelled model
+async fn check_models_available(
+    ollama: &OllamaClient,
+    settings: &OllamaProcSettings,
+    models: &[String],
+) -> Result<Vec<LocalModel>, OllamaError> {
+    let local_models = ollama.local_models().await?;
+    for model in models {
+        let model = settings.resolve_model(model);
+        if !is_local_model(&local_models, model) {
+            return Err(OllamaError::Other(format!(
+                "The configured model `{model}` is not available on the Ollama server {} after its pull, check its name",
+                ollama.host()
+            )));
+        }
+    }
+    Ok(local_models)
+}
+
+/// Period between two logs of the progress of a model pull or push
+const PULL_PROGRESS_LOG_PERIOD: Duration = Duration::from_secs(10);
+
+/// Estimated time remaining of a pull, at the average bandwidth since its start. `None` until some bytes are downloaded
+fn pull_eta(remaining: u64, downloaded: u64, elapsed: Duration) -> Option<Duration> {
+    (downloaded > 0).then(|| {
+        Duration::from_secs_f64(elapsed.as_secs_f64() * remaining as f64 / downloaded as f64)
+    })
+}
+
+/// Pull a model with the streaming API, to record and log the download progress and the estimated time remaining. Return the last status of the pull
+async fn pull_model(
+    ollama: &OllamaClient,
+    model: &str,
+    allow_insecure: bool,
+    meters: &OllamaMeters,
+) -> Result<PullModelStatus, OllamaError> {
+    let pull_error = |source, status: Option<&PullModelStatus>| OllamaError::Pull {
+        model: model.to_string(),
+        status: status.map(|status| status.message.clone()),
+        source,
+    };
+
+    let mut stream = ollama
+        .pull_model_stream(model.to_string(), allow_insecure)
+        .await
+        .map_err(|e| pull_error(e, None))?;
+
+    let attributes = [KeyValue::new("model", model.to_string())];
+    // Total and completed bytes of each layer
+    let mut layers: HashMap<String, (u64, u64)> = HashMap::new();
+    // Bytes already downloaded when the pull resumed, not counted in the bandwidth
+    let mut resumed = None;
+    let started = Instant::now();
+    let mut last_log = Instant::now();
+    let mut last_status = None;
+    let pulled = async {
+        while let Some(status) = stream.next().await {
+            let status = status.map_err(|e| pull_error(e, last_status.as_ref()))?;
+            if let Some(digest) = &status.digest
+                && let (Some(total), Some(completed)) = (status.total, status.completed)
+            {
+                layers.insert(digest.clone(), (total, completed));
+                let (total, completed) = layers
+                    .values()
+                    .fold((0, 0), |(t, c), (total, completed)| (t + total, c + completed));
+                meters.pull_progress_gauge.record(completed, &attributes);
+                let resumed = *resumed.get_or_insert(completed);
+                let eta = pull_eta(
+                    total.saturating_sub(completed),
+                    completed.saturating_sub(resumed),
+                    started.elapsed(),
+                );
+                if let Some(eta) = eta {
+                    meters.pull_eta_gauge.record(eta.as_secs(), &attributes);
+                }
+                if total > 0 && last_log.elapsed() >= PULL_PROGRESS_LOG_PERIOD {
+                    match eta {
+                        Some(eta) => info!(
+                            "Pull of the model {model}: {}% ({completed}/{total} bytes), {}s remaining",
+                            completed * 100 / total,
+                            eta.as_secs()
+                        ),
+                        None => info!(
+                            "Pull of the model {model}: {}% ({completed}/{total} bytes)",
+                            completed * 100 / total
+                        ),
+                    }
+                    last_log = Instant::now();
+                }
+            }
+            last_status = Some(status);
+        }
+        Ok::<_, OllamaError>(())
+    }
+    .await;
+    // The pull is over, even if it failed
+    meters.pull_eta_gauge.record(0, &attributes);
+    pulled?;
+
+    last_status.ok_or_else(|| {
+        pull_error(
+            ollama_rs::error::OllamaError::Other("the pull ended without status".to_string()),
+            None,
+        )
+    })
+}
+
+/// Push a model with the streaming API, to record and log the upload progress
+async fn push_model(
+    ollama: &OllamaClient,
+    model: &str,
+    allow_insecure: bool,
+    meters: &OllamaMeters,
+) -> Result<(), OllamaError> {
+    let mut stream = ollama
+        .push_model_stream(model.to_string(), allow_insecure)
+        .await?;
+
+    // The push status doesn't report the uploaded bytes, so the progress is the size of the layers being pushed
+    let attributes = [KeyValue::new("model", model.to_string())];
+    let mut layers: HashMap<String, u64> = HashMap::new();
+    let mut last_log = Instant::now();
+    while let Some(status) = stream.next().await {
+        let status = status?;
+        if let Some(digest) = &status.digest
+            && let Some(total) = status.total
+            && layers.insert(digest.clone(), total).is_none()
+        {
+            meters
+                .push_progress_gauge
+                .record(layers.values().sum(), &attributes);
+        }
+        if last_log.elapsed() >= PULL_PROGRESS_LOG_PERIOD {
+            info!("Push of the model {model}: {}", status.message);
+            last_log = Instant::now();
+        }
+    }
+
+    info!("Pushed the model {model}");
+    Ok(())
+}
+
+/// Pull the configured models that are missing on the Ollama servers, after a configuration reload or on a periodic synchronization
+async fn pull_missing_models(
+    pool: &OllamaPool,
+    settings: &OllamaProcSettings,
+    meters: &OllamaMeters,
+) -> Result<(), OllamaError> {
+    for ollama in pool.clients() {
+        let local_models = ollama.local_models().await?;
+        for model in &settings.models {
+            let model = settings.resolve_model(model);
+            if !is_local_model(&local_models, model) {
+                if !settings.auto_pull {
+                    warn!(
+                        "The configured model `{model}` is not available on the Ollama server {}, and is not pulled",
+                        ollama.host()
+                    );
+                    continue;
+                }
+
+                let pull_model_status =
+                    pull_model(ollama, model, settings.allow_insecure, meters).await?;
+                info!(
+                    "Pulled the model {} on {}: {}",
+                    model,
+                    ollama.host(),
+                    pull_model_status.message
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Embed the inputs of a rejected embeddings request one by one, to return the vectors of the accepted inputs with the errors of the rejected ones.
+/// A backend failure (e.g. unreachable server) or the rejection of every input is returned in error
+async fn call_embeddings_partial<M, A>(
+    ctx: &RequestContext<M, A>,
+    request: &GenerateEmbeddingsRequest,
+    inputs: &[String],
+) -> Result<OllamaResponse, OllamaError>
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: OllamaAdaptor<M>,
+{
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    let mut errors = Vec::new();
+    let mut last_error = None;
+    for (index, input) in inputs.iter().enumerate() {
+        let input_request = GenerateEmbeddingsRequest {
+            model_name: request.model_name.clone(),
+            input: EmbeddingsInput::Single(input.clone()),
+            truncate: request.truncate,
+            options: request.options.clone(),
+            keep_alive: request.keep_alive.clone(),
+            dimensions: request.dimensions,
+        };
+        match call_with_auth_refresh(
+            ctx,
+            OllamaRequest::GenerateEmbeddingsRequest(Box::new(input_request)),
+        )
+        .await
+        {
+            Ok(OllamaResponse::GenerateEmbeddingsResponse(response)) => {
+                embeddings.push(response.embeddings.into_iter().next().unwrap_or_default());
+            }
+            Ok(_) => {
+                return Err(OllamaError::Other(
+                    "Unexpected response to an embeddings request".to_string(),
+                ));
+            }
+            Err(e) if e.recoverable() => return Err(e),
+            Err(e) => {
+                errors.push((index, e.to_string()));
+                embeddings.push(Vec::new());
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if errors.len() == inputs.len() => Err(e),
+        _ => Ok(OllamaResponse::PartialEmbeddingsResponse {
+            response: Box::new(GenerateEmbeddingsResponse { embeddings }),
+            errors,
+        }),
+    }
+}
+
+/// Call Ollama, refreshing the auth token and retrying once if the server rejects the current one
+async fn call_with_auth_refresh<M, A>(
+    ctx: &RequestContext<M, A>,
+    request: OllamaRequest<'_>,
+) -> Result<OllamaResponse, OllamaError>
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: OllamaAdaptor<M>,
+{
+    let ollama = ctx.ollama().await?;
+    let backend_call = ctx.backend_call(&ollama).await;
+    let in_flight = ollama.start_call();
+    let response = ollama
+        .until_retired(request.clone().call(&ollama, &ctx.meters))
+        .await;
+    drop(in_flight);
+    let (backend_call, response) = match response {
+        Err(e) if is_unauthorized(&e) => {
+            // The server answered, with the rejection of the auth token
+            backend_call.record(false);
+            let ollama = ctx.refresh_ollama(&ollama, e).await?;
+            let backend_call = ctx.backend_call(&ollama).await;
+            let _in_flight = ollama.start_call();
+            let response = ollama
+                .until_retired(request.call(&ollama, &ctx.meters))
+                .await;
+            (backend_call, response)
+        }
+        response => (backend_call, response),
+    };
+    backend_call.record(response.as_ref().is_err_and(OllamaError::recoverable));
+    response
+}
+
+/// Stream a generation, giving each chunk to the adaptor and sending its partial messages to the stream queue.
+/// The time to the first chunk is bounded by `first_chunk_timeout`, and the whole stream by `max_stream_duration`.
+/// Return the complete response, with the text of all the chunks
+async fn call_stream<M, A>(
+    ctx: &RequestContext<M, A>,
+    request: GenerationRequest<'_>,
+    stream_sink: &StreamSink<'_, M>,
+    first_chunk_timeout: Option<Duration>,
+) -> Result<OllamaResponse, OllamaError>
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: OllamaAdaptor<M>,
+{
+    debug!("Generate stream");
+    let started = tokio::time::Instant::now();
+    let first_chunk = async {
+        let ollama = ctx.ollama().await?;
+        let backend_call = ctx.backend_call(&ollama).await;
+        let in_flight = ollama.start_call();
+        // The call is in flight until the end of the stream
+        let (ollama, backend_call, in_flight, stream) = match ollama
+            .until_retired(ollama.generate_stream_api(&request))
+            .await
+        {
+            Err(e) if is_unauthorized(&e) => {
+                drop(in_flight);
+                backend_call.record(false);
+                let ollama = ctx.refresh_ollama(&ollama, e).await?;
+                let backend_call = ctx.backend_call(&ollama).await;
+                let in_flight = ollama.start_call();
+                let stream = ollama
+                    .until_retired(ollama.generate_stream_api(&request))
+                    .await;
+                (ollama, backend_call, in_flight, stream)
+            }
+            stream => (ollama, backend_call, in_flight, stream),
+        };
+        backend_call.record(stream.as_ref().is_err_and(OllamaError::recoverable));
+        let mut stream = stream?;
+        let chunks = ollama
+            .until_retired(async { Ok(stream.next().await) })
+            .await?;
+        Ok((ollama, in_flight, stream, chunks))
+    };
+    let (ollama, _in_flight, mut stream, first_chunks) = match first_chunk_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, first_chunk)
+            .await
+            .unwrap_or_else(|_| {
+                Err(OllamaError::Timeout {
+                    service: stream_sink.service.to_string(),
+                    timeout,
+                })
+            })?,
+        None => first_chunk.await?,
+    };
+
+    // Dropping the stream closes the connection, so the backend stops the generation
+    let max_response_bytes = ctx.settings().await.max_response_bytes;
+    let streamed = async move {
+        let mut text = String::new();
+        let mut final_chunk = None;
+        let mut next_chunks = first_chunks;
+        'stream: while let Some(chunks) = next_chunks {
+            for mut chunk in chunks? {
+                if let Some(max_response_bytes) = max_response_bytes
+                    && text.len() + chunk.response.len() > max_response_bytes
+                {
+                    // The chunk is cut at the limit, then followed by the truncation marker as last chunk
+                    let mut end = max_response_bytes - text.len();
+                    while !chunk.response.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    chunk.response.truncate(end);
+                    let mut marker = chunk.clone();
+                    marker.response = STREAM_TRUNCATION_MARKER.to_string();
+                    marker.done = true;
+                    chunk.done = false;
+                    if !chunk.response.is_empty() {
+                        forward_chunk(ctx, stream_sink, &chunk).await?;
+                        text.push_str(&chunk.response);
+                    }
+                    forward_chunk(ctx, stream_sink, &marker).await?;
+                    text.push_str(&marker.response);
+
+                    ctx.meters
+                        .stream_cancelled_counter
+                        .add(1, &[KeyValue::new("reason", "max_bytes")]);
+                    warn!(
+                        "Generation stream truncated, its response exceeded {max_response_bytes} bytes"
+                    );
+                    final_chunk = Some(marker);
+                    break 'stream;
+                }
+
+                forward_chunk(ctx, stream_sink, &chunk).await?;
+                text.push_str(&chunk.response);
+                if chunk.done {
+                    final_chunk = Some(chunk);
+                }
+            }
+            next_chunks = stream.next().await;
+        }
+
+        Ok((text, final_chunk))
+    };
+    let streamed = ollama.until_retired(streamed);
+    let (text, final_chunk) = match ctx.settings().await.max_stream_duration {
+        Some(max_stream_duration) => {
+            tokio::time::timeout_at(started + max_stream_duration, streamed)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(stream_cancelled(
+                        ctx,
+                        "max_duration",
+                        format!("the generation exceeded {max_stream_duration:?}"),
+                    ))
+                })?
+        }
+        None => streamed.await?,
+    };
+
+    let mut response = final_chunk.ok_or_else(|| {
+        OllamaError::Other("The generation stream ended before its final chunk".to_string())
+    })?;
+    response.response = text;
+    Ok(response.into())
+}
+
+/// Text of the last chunk of a streamed generation truncated at `max_response_bytes`
+pub const STREAM_TRUNCATION_MARKER: &str = "[truncated]";
+
+/// Give a chunk of a streamed generation to the adaptor, and send its partial message to the stream sink
+async fn forward_chunk<M, A>(
+    ctx: &RequestContext<M, A>,
+    stream_sink: &StreamSink<'_, M>,
+    chunk: &GenerationResponse,
+) -> Result<(), OllamaError>
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: OllamaAdaptor<M>,
+{
+    // A `RequestMsg` doesn't expose the response queue of its sender, so the departure of the requesting client can't be detected.
+    // Only the closing of the stream service queue (its processor stopped) cancels the stream
+    if let Some(target) = &stream_sink.target
+        && target.queue.is_closed()
+    {
+        return Err(stream_cancelled(
+            ctx,
+            "stream_service_closed",
+            format!("the stream service {} is closed", target.service),
+        ));
+    }
+
+    let partial = ctx.adaptor.lock().await.process_stream_chunk(
+        stream_sink.service,
+        stream_sink.request_id,
+        chunk,
+    )?;
+    if let Some(partial) = partial
+        && let Some(target) = &stream_sink.target
+    {
+        // The partial message is traced as a child of the streamed request
+        let partial = match stream_sink.trace_id.clone() {
+            Some(trace_id) => RequestMsg::new_with_trace_id(
+                target.service.to_string(),
+                partial,
+                target.response_queue.clone(),
+                trace_id,
+            ),
+            None => RequestMsg::new(
+                target.service.to_string(),
+                partial,
+                target.response_queue.clone(),
+            ),
+        };
+        if target
+            .queue
+            .send(InternalMsg::Request(partial))
+            .await
+            .is_err()
+        {
+            return Err(stream_cancelled(
+                ctx,
+                "stream_service_closed",
+                format!("the stream service {} is closed", target.service),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Count a cancelled streamed generation, and build its error
+fn stream_cancelled<M, A>(
+    ctx: &RequestContext<M, A>,
+    reason: &'static str,
+    cause: String,
+) -> OllamaError
+where
+    M: Sized + Clone + Tvf,
+{
+    ctx.meters
+        .stream_cancelled_counter
+        .add(1, &[KeyValue::new("reason", reason)]);
+    warn!("Generation stream cancelled: {cause}");
+    OllamaError::Other(format!("Generation stream cancelled, {cause}"))
+}
+
+/// Origin and destination of the partial messages of a streamed generation
+struct StreamSink<'a, M>
+where
+    M: Sized + Clone + Tvf,
+{
+    /// Service of the streamed request
+    service: &'a str,
+    /// Id of the streamed request message, to tell apart the chunks of concurrent streams
+    request_id: u64,
+    /// Span of the streamed request, parent of the spans of the partial messages
+    trace_id: Option<tracing::span::Id>,
+    /// Stream service where the partial messages are sent, if set
+    target: Option<StreamTarget<'a, M>>,
+}
+
+impl<'a, M> StreamSink<'a, M>
+where
+    M: Sized + Clone + Tvf,
+{
+    /// Create the sink of a streamed request, with the stream service receiving its partial messages if any
+    fn new(
+        service: &'a str,
+        request_id: u64,
+        trace_id: Option<tracing::span::Id>,
+        target: Option<StreamTarget<'a, M>>,
+    ) -> Self {
+        StreamSink {
+            service,
+            request_id,
+            trace_id,
+            target,
+        }
+    }
+}
+
+/// Stream service receiving the partial messages of a streamed generation
+struct StreamTarget<'a, M>
+where
+    M: Sized + Clone + Tvf,
+{
+    /// Service where the partial messages are sent
+    service: &'a str,
+    /// Queue of the processor handling the service
+    queue: mpsc::Sender<InternalMsg<M>>,
+    /// Queue where the responses of the partial messages are received
+    response_queue: mpsc::Sender<InternalMsg<M>>,
+}
+
+impl<'a, M> StreamTarget<'a, M>
+where
+    M: 'static + Sized + Clone + Tvf + std::fmt::Debug + std::marker::Send,
+{
+    /// Create the target of a stream, with its own queue of responses drained in background.
+    /// The stream service can always acknowledge the partial messages, so it never blocks the stream that sends them
+    fn new(service: &'a str, queue: mpsc::Sender<InternalMsg<M>>) -> Self {
+        let (response_queue, mut responses) = mpsc::channel(STREAM_RESPONSE_QUEUE_SIZE);
+        tokio::spawn(async move {
+            // Ends once the stream and its partial messages are dropped
+            while let Some(response) = responses.recv().await {
+                match response {
+                    InternalMsg::Response(msg) => debug!("Stream chunk acknowledged {:?}", msg),
+                    InternalMsg::Error(err) => warn!("Stream chunk error {:?}", err),
+                    msg => debug!("Ignore message on the stream response queue: {:?}", msg),
+                }
+            }
+        });
+        StreamTarget {
+            service,
+            queue,
+            response_queue,
+        }
+    }
+}
+
+/// Size of the queue of the responses to the partial messages of a stream
+const STREAM_RESPONSE_QUEUE_SIZE: usize = 64;
+
+/// Dispatch a request to Ollama (streamed or not), within the request deadline if any, or else the `request_timeout` (until the first chunk of a streamed generation).
+/// Recoverable errors are retried up to `max_retries` times, except for streamed generations that may have sent partial messages
+async fn dispatch<M, A>(
+    ctx: &RequestContext<M, A>,
+    stream_sink: Option<StreamSink<'_, M>>,
+    service: &str,
+    mut request: OllamaRequest<'_>,
+    deadline: Option<Instant>,
+) -> Result<OllamaResponse, OllamaError>
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: OllamaAdaptor<M>,
+{
+    let settings = ctx.settings().await;
+    let max_retries = match request {
+        OllamaRequest::GenerateRequest(_)
+        | OllamaRequest::ChatRequest(_)
+        | OllamaRequest::GenerateEmbeddingsRequest(_) => settings.max_retries,
+        _ => 0,
+    };
+
+    // The chunks of a streamed generation are given to the adaptor with the request they come from
+    if matches!(request, OllamaRequest::GenerateStreamRequest(_)) && stream_sink.is_none() {
+        return Err(OllamaError::Other(
+            "A streamed generation needs the sink of its chunks".to_string(),
+        ));
+    }
+
+    // The status is answered by the processor, even when the circuit is open. The services are only declared once the servers are ready
+    if let OllamaRequest::Status = request {
+        debug!("Status");
+        let backends = ctx.backend_statuses().await;
+        return Ok(OllamaResponse::Status {
+            urls: settings.get_redacted_urls(),
+            auth_mode: settings.get_auth_mode(),
+            models: settings.models.clone(),
+            backend_healthy: backends.iter().any(|backend| backend.healthy),
+            backends,
+        });
+    }
+
+    if let Some(ttl) = settings.model_info_cache_ttl
+        && let OllamaRequest::ModelInfo(model) = &request
+        && let Some(info) = ctx.model_info_cache.lock().await.get(model, ttl)
+    {
+        debug!("Model info of {model} from the cache");
+        return Ok(OllamaResponse::ModelInfo(info));
+    }
+
+    // A model with cached info exists, as the cache is invalidated when the model is deleted
+    if let Some(ttl) = settings.model_info_cache_ttl
+        && let OllamaRequest::ModelExists(model) = &request
+        && ctx.model_info_cache.lock().await.get(model, ttl).is_some()
+    {
+        debug!("Model {model} exists from the model info cache");
+        return Ok(OllamaResponse::ModelExists(true));
+    }
+
+    // Admission control: a model that fits nowhere would only fail after a slow load attempt
+    if let Some(vram_capacity) = settings.vram_capacity
+        && let Some(model) = request.get_loaded_model_name()
+        && !ctx
+            .ollama_pool()
+            .await
+            .selectable()
+            .any(|ollama| ollama.fits_model(model, vram_capacity))
+    {
+        debug!("The model {model} doesn't fit in the free VRAM of the Ollama servers");
+        return Err(OllamaError::OverCapacity {
+            model: model.to_string(),
+        });
+    }
+
+    // Nobody waits for the response of a request past its deadline. Checked before the circuit breaker, which expects the outcome of the requests it lets through
+    if let Some(deadline) = deadline
+        && deadline <= Instant::now()
+    {
+        debug!("The request for {service} is past its deadline, it's not sent");
+        return Err(OllamaError::Timeout {
+            service: service.to_string(),
+            timeout: Duration::ZERO,
+        });
+    }
+
+    if settings.circuit_breaker_threshold.is_some() {
+        ctx.circuit_breaker
+            .lock()
+            .await
+            .allow(settings.circuit_breaker_cooldown, &ctx.meters)?;
+    }
+
+    let mut attempt = 0;
+    let mut fallback_used = false;
+    // Outcome of the last backend call, recorded by the circuit breaker if no other call is sent
+    let mut last_call_failed = false;
+    loop {
+        let timeout = match deadline {
+            Some(deadline) => Some(deadline.saturating_duration_since(Instant::now())),
+            None => settings.request_timeout,
+        };
+
+        // The deadline is past after a fallback or a retry: the outcome of the last call is recorded
+        if deadline.is_some() && timeout.is_some_and(|timeout| timeout.is_zero()) {
+            debug!("The request for {service} is past its deadline, it's not sent");
+            if let Some(threshold) = settings.circuit_breaker_threshold {
+                ctx.circuit_breaker
+                    .lock()
+                    .await
+                    .record(last_call_failed, threshold, &ctx.meters);
+            }
+            return Err(OllamaError::Timeout {
+                service: service.to_string(),
+                timeout: Duration::ZERO,
+            });
+        }
+
+        let call = async {
+            if let OllamaRequest::GenerateStreamRequest(stream_request) = request.clone()
+                && let Some(stream_sink) = &stream_sink
+            {
+                call_stream(ctx, *stream_request, stream_sink, timeout)
+                    .instrument(request.backend_span())
+                    .await
+            } else if let Some(batcher) = &ctx.embeddings_batcher
+                && let OllamaRequest::GenerateEmbeddingsRequest(embeddings_request) =
+                    request.clone()
+            {
+                match batcher.submit(*embeddings_request).await {
+                    Some(response) => response.map(|response| {
+                        OllamaResponse::GenerateEmbeddingsResponse(Box::new(response))
+                    }),
+                    // The batch was rejected, the request is sent alone to get its own error
+                    None => call_with_auth_refresh(ctx, request.clone()).await,
+                }
+            } else {
+                call_with_auth_refresh(ctx, request.clone()).await
+            }
+        };
+
+        // A stream is only bounded by the timeout until its first chunk, then by `max_stream_duration`
+        let result = if let Some(timeout) = timeout
+            && !matches!(request, OllamaRequest::GenerateStreamRequest(_))
+        {
+            tokio::time::timeout(timeout, call)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(OllamaError::Timeout {
+                        service: service.to_string(),
+                        timeout,
+                    })
+                })
+        } else {
+            call.await
+        };
+
+        // A missing model is replaced once by the fallback model, unless the adaptor opts out
+        if !fallback_used
+            && let Err(e) = &result
+            && is_model_not_found(e)
+            && let Some(fallback_model) = settings.fallback_model.as_deref()
+            && matches!(
+                request,
+                OllamaRequest::GenerateRequest(_)
+                    | OllamaRequest::GenerateStreamRequest(_)
+                    | OllamaRequest::ChatRequest(_)
+            )
+            && request.get_model_name() != Some(settings.resolve_model(fallback_model))
+            && ctx
+                .adaptor
+                .lock()
+                .await
+                .allow_fallback_model(service, &request)
+        {
+            let fallback_model = settings.resolve_model(fallback_model);
+            let model = request.get_model_name().unwrap_or_default().to_string();
+            warn!(
+                "The model {model} is unavailable, the request for {service} falls back to {fallback_model}: {e}"
+            );
+            ctx.meters.fallback_used_counter.add(
+                1,
+                &[
+                    KeyValue::new("model", model),
+                    KeyValue::new("fallback_model", fallback_model.to_string()),
+                ],
+            );
+            if let Some(model_name) = request.get_model_name_mut() {
+                *model_name = fallback_model.to_string();
+            }
+            fallback_used = true;
+            last_call_failed = false;
+            continue;
+        }
+
+        // A multi-input embeddings request rejected by the backend is embedded input by input, so only the rejected inputs fail
+        let result = if settings.partial_embeddings
+            && let Err(e) = &result
+            && !e.recoverable()
+            && let OllamaRequest::GenerateEmbeddingsRequest(embeddings_request) = &request
+            && let EmbeddingsInput::Multiple(inputs) = &embeddings_request.input
+            && inputs.len() > 1
+        {
+            warn!(
+                "The embeddings request for {service} is rejected, its {} inputs are embedded one by one: {e}",
+                inputs.len()
+            );
+            call_embeddings_partial(ctx, embeddings_request, inputs).await
+        } else {
+            result
+        };
+
+        match result {
+            // A retry that can't start before the deadline is not attempted
+            Err(e)
+                if attempt < max_retries
+                    && e.recoverable()
+                    && deadline.is_none_or(|deadline| {
+                        Instant::now() + settings.get_retry_backoff(attempt + 1) < deadline
+                    }) =>
+            {
+                attempt += 1;
+                warn!("Retry {attempt}/{max_retries} of the Ollama request for {service}: {e}");
+                ctx.meters
+                    .retry_counter
+                    .add(1, &[KeyValue::new("service", service.to_string())]);
+                tokio::time::sleep(settings.get_retry_backoff(attempt)).await;
+                last_call_failed = true;
+            }
+            result => {
+                if let Some(threshold) = settings.circuit_breaker_threshold {
+                    // Only the backend failures are counted, the other errors are answers to a wrong request
+                    let failure = result.as_ref().is_err_and(OllamaError::is_backend_failure);
+                    ctx.circuit_breaker
+                        .lock()
+                        .await
+                        .record(failure, threshold, &ctx.meters);
+                }
+                if settings.model_info_cache_ttl.is_some()
+                    && let Ok(response) = &result
+                {
+                    ctx.model_info_cache.lock().await.update(&request, response);
+                }
+                return result;
+            }
+        }
+    }
+}
+
+/// Process a request message, from the adaptor up to the response returned to the sender
+async fn handle_request<M, A>(
+    ctx: &RequestContext<M, A>,
+    service_table: Arc<ServiceTable<M>>,
+    mut msg: RequestMsg<M>,
+    dequeued: Instant,
+) -> Result<(), SendError<InternalMsg<M>>>
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: OllamaAdaptor<M>,
+{
+    let settings = ctx.settings().await;
+    let mut completion_log = CompletionLog::new(&msg);
+
+    // A request without data still gets an answer (and its completion log), so its sender doesn't hang
+    let Some(data) = msg.take_data() else {
+        warn!("Empty request for {}", msg.get_service());
+        completion_log.emit(settings.completion_log_level, "error");
+        return return_result(
+            ctx,
+            msg,
+            "rejected",
+            dequeued,
+            Err(OllamaError::BadRequest {
+                field: None,
+                reason: "empty request".to_string(),
+            }),
+        )
+        .await;
+    };
+
+    // Least recently used models to unload beyond `max_loaded_models`, once the response is returned
+    let mut unloaded_models = Vec::new();
+    let known_service = settings.services.contains(msg.get_service());
+    let mut deadline = None;
+    if known_service {
+        let adaptor = ctx.adaptor.lock().await;
+        completion_log.metadata =
+            bound_request_metadata(adaptor.request_metadata(msg.get_service(), &data));
+        deadline = adaptor
+            .request_deadline(msg.get_service(), &data)
+            .map(|deadline| {
+                Instant::now()
+                    + deadline
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default()
+            });
+    }
+    let mut metadata_attributes: Vec<KeyValue> = settings
+        .metadata_metric_keys
+        .iter()
+        .filter_map(|key| {
+            completion_log
+                .metadata
+                .get(key)
+                .map(|value| KeyValue::new(key.clone(), value.clone()))
+        })
+        .collect();
+    // Type of the request for the metrics, known once the adaptor built it
+    let mut request_type = "rejected";
+    let adaptor_request = if known_service {
+        ctx.adaptor
+            .lock()
+            .await
+            .process_request_or_response(msg.get_service(), data)
+    } else {
+        ctx.meters
+            .unknown_service_counter
+            .add(1, &[KeyValue::new("service", msg.get_service().clone())]);
+        Err(OllamaError::UnknownService(msg.get_service().clone()))
+    }
+    .and_then(|adaptor_request| {
+        let AdaptorRequest::Ollama(mut request) = adaptor_request else {
+            return Ok(adaptor_request);
+        };
+        request_type = request.get_kind().as_str();
+        if settings.introspection_only && !request.is_introspection() {
+            return Err(OllamaError::BadRequest {
+                field: None,
+                reason: "the processor only allows introspection requests".to_string(),
+            });
+        }
+        if !settings.allowed_request_kinds.is_empty()
+            && !settings.allowed_request_kinds.contains(&request.get_kind())
+        {
+            return Err(OllamaError::BadRequest {
+                field: None,
+                reason: format!(
+                    "the processor doesn't allow the `{}` requests",
+                    request.get_kind().as_str()
+                ),
+            });
+        }
+        settings.apply_defaults(msg.get_service(), &mut request)?;
+        if let Some(keep_alive) = &settings.keep_alive
+            && let Some(request_keep_alive) = request.get_keep_alive_mut()
+            && request_keep_alive.is_none()
+        {
+            *request_keep_alive = Some(keep_alive.clone());
+        }
+        if let Some(request_format) = request.get_format_mut()
+            && request_format.is_none()
+        {
+            *request_format = settings.get_default_format()?;
+        }
+        if let Some(model) = request.get_model_name_mut()
+            && let Some(concrete_model) = settings.aliases.get(model.as_str())
+        {
+            completion_log.model_alias = Some(std::mem::replace(model, concrete_model.clone()));
+        }
+        if settings.enforce_model_allowlist
+            && matches!(
+                request,
+                OllamaRequest::GenerateRequest(_)
+                    | OllamaRequest::GenerateStreamRequest(_)
+                    | OllamaRequest::ChatRequest(_)
+                    | OllamaRequest::GenerateEmbeddingsRequest(_)
+            )
+            && let Some(model) = request.get_model_name()
+            && !settings.is_configured_model(model)
+        {
+            return Err(OllamaError::bad_field(
+                "model",
+                format!("The model `{model}` is not in the configured models"),
+            ));
+        }
+        // The chunks of a stream would be lost without their stream service
+        if let OllamaRequest::GenerateStreamRequest(_) = request
+            && let Some(stream_service) = settings.stream_service.as_deref()
+            && service_table.get_proc_service(stream_service).is_none()
+        {
+            return Err(OllamaError::bad_field(
+                "stream_service",
+                format!("The stream service `{stream_service}` is not available"),
+            ));
+        }
+        settings
+            .empty_prompt_policy
+            .apply(&mut request, &ctx.meters)?;
+        // An oversized prompt is rejected before reaching the backend
+        if let Some(max_prompt_chars) = settings.max_prompt_chars
+            && let Some(prompt_chars) = request.get_prompt_chars()
+            && prompt_chars > max_prompt_chars
+        {
+            return Err(OllamaError::bad_field(
+                "prompt",
+                format!(
+                    "The prompt of {prompt_chars} characters exceeds the limit of {max_prompt_chars} characters"
+                ),
+            ));
+        }
+        Ok(AdaptorRequest::Ollama(request))
+    });
+    let result = match adaptor_request {
+        // Answered by the adaptor, without any backend metric
+        Ok(AdaptorRequest::Response(response)) => {
+            request_type = "local";
+            Ok(response)
+        }
+        Ok(AdaptorRequest::Ollama(request)) => {
+            let request_kind = request.get_kind();
+            completion_log.model = request.get_model_name().map(str::to_string);
+            metadata_attributes.push(KeyValue::new("service", msg.get_service().clone()));
+            if let Some(alias) = &completion_log.model_alias {
+                metadata_attributes.push(KeyValue::new("model_alias", alias.clone()));
+            }
+            ctx.meters.record_request(&request, &metadata_attributes);
+            let effective_options = request.get_options().cloned();
+            let prompt_chars = if settings.log_payload_sizes {
+                request.get_prompt_chars()
+            } else {
+                None
+            };
+            let cache_key = settings.generation_cache_capacity.and_then(|capacity| {
+                GenerationCache::key(&request, settings.generation_cache_nondeterministic)
+                    .map(|key| (key, capacity))
+            });
+            let cached_response = match &cache_key {
+                Some((key, _)) => ctx
+                    .generation_cache
+                    .lock()
+                    .await
+                    .get(key, settings.generation_cache_ttl),
+                None => None,
+            };
+            let eval_prompt = match &request {
+                OllamaRequest::GenerateRequest(request)
+                | OllamaRequest::GenerateStreamRequest(request) => ctx
+                    .eval_sampler
+                    .lock()
+                    .await
+                    .should_sample()
+                    .then(|| request.prompt.to_string()),
+                _ => None,
+            };
+            // The model is only tracked as loaded once the backend answered with it
+            let loaded_model = settings
+                .max_loaded_models
+                .zip(request.get_loaded_model_name().map(str::to_string));
+            let stream_sink =
+                matches!(request, OllamaRequest::GenerateStreamRequest(_)).then(|| {
+                    let target = settings.stream_service.as_deref().and_then(|service| {
+                        service_table.get_proc_service(service).map(|proc_service| {
+                            StreamTarget::new(service, proc_service.proc_queue.clone())
+                        })
+                    });
+                    StreamSink::new(msg.get_service(), msg.get_id(), msg.get_span().id(), target)
+                });
+            let cache_hit = cached_response.is_some();
+            let response = if let Some(response) = cached_response {
+                debug!("Generation from the cache");
+                ctx.meters
+                    .cache_hit_counter
+                    .add(1, &[KeyValue::new("model", response.model.clone())]);
+                Ok(OllamaResponse::GenerateResponse(Box::new(response)))
+            } else {
+                let mut shutdown = ctx.shutdown.subscribe();
+                let response = tokio::select! {
+                    response = dispatch(ctx, stream_sink, msg.get_service(), request, deadline) => response,
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => Err(OllamaError::Shutdown),
+                };
+                if let Some((key, capacity)) = cache_key
+                    && let Ok(OllamaResponse::GenerateResponse(response)) = &response
+                {
+                    ctx.generation_cache.lock().await.insert(
+                        key,
+                        response.as_ref().clone(),
+                        capacity,
+                    );
+                }
+                response
+            };
+            match response {
+                Ok(response) => {
+                    // A cached generation doesn't use the backend
+                    if !cache_hit {
+                        ctx.meters.record(
+                            &response,
+                            completion_log.model.as_deref(),
+                            &metadata_attributes,
+                        );
+                        ctx.meters
+                            .record_cold_start(&response, settings.cold_start_threshold);
+                    }
+                    if let Some((max_loaded_models, model)) = loaded_model
+                        && !cache_hit
+                    {
+                        unloaded_models = ctx
+                            .loaded_models
+                            .lock()
+                            .await
+                            .touch(&model, max_loaded_models);
+                    }
+                    if let OllamaResponse::ModelUnloaded(model) = &response {
+                        ctx.loaded_models.lock().await.remove(model);
+                    }
+                    completion_log.set_token_counts(&response);
+                    // Only the sizes are logged, never the content
+                    if let Some(prompt_chars) = prompt_chars
+                        && let Some(response_chars) = response.get_response_chars()
+                    {
+                        info!(
+                            request_id = completion_log.request_id,
+                            service = completion_log.service,
+                            model = completion_log.model.as_deref().unwrap_or_default(),
+                            prompt_chars,
+                            prompt_tokens = completion_log.prompt_tokens,
+                            response_chars,
+                            eval_tokens = completion_log.eval_tokens,
+                            "Ollama payload sizes"
+                        );
+                    }
+                    let mut adaptor = ctx.adaptor.lock().await;
+                    if let Some(prompt) = eval_prompt
+                        && let OllamaResponse::GenerateResponse(generation) = &response
+                        && let Some(sample) = adaptor.redact_eval_sample(EvalSample {
+                            service: msg.get_service().clone(),
+                            model: generation.model.clone(),
+                            prompt,
+                            response: generation.response.clone(),
+                        })
+                    {
+                        ctx.eval_sampler.lock().await.send(sample);
+                    }
+                    settings
+                        .invalid_embeddings
+                        .check(response, completion_log.model.as_deref(), &ctx.meters)
+                        .and_then(|response| {
+                            adaptor.process_ollama_response_with_options(
+                                response,
+                                effective_options.as_ref(),
+                            )
+                        })
+                }
+                // The adaptor can observe the backend error, and replace it
+                Err(e) => Err(ctx
+                    .adaptor
+                    .lock()
+                    .await
+                    .on_backend_error(request_kind, &e)
+                    .unwrap_or(e)),
+            }
+        }
+        Err(e) => {
+            warn!("Request error: {e}");
+            Err(e)
+        }
+    };
+
+    completion_log.emit(
+        settings.completion_log_level,
+        if result.is_ok() { "ok" } else { "error" },
+    );
+    let returned = return_result(ctx, msg, request_type, dequeued, result).await;
+    ctx.unload_models(&unloaded_models).await;
+    returned
+}
+
+/// Return the result of a request to its sender, recording its latency and counting the errors by request type.
+/// With `errors_as_response`, the adaptor can turn the error into a response
+async fn return_result<M, A>(
+    ctx: &RequestContext<M, A>,
+    msg: RequestMsg<M>,
+    request_type: &'static str,
+    dequeued: Instant,
+    result: Result<M, OllamaError>,
+) -> Result<(), SendError<InternalMsg<M>>>
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: OllamaAdaptor<M>,
+{
+    let service = msg.get_service().clone();
+    let returned = match result {
+        Ok(resp) => msg.return_to_sender(resp).await,
+        Err(e) => {
+            ctx.meters.request_error_counter.add(
+                1,
+                &[
+                    KeyValue::new("type", request_type),
+                    KeyValue::new("category", e.category()),
+                ],
+            );
+            let resp = if ctx.settings().await.errors_as_response {
+                ctx.adaptor.lock().await.process_error(&e)
+            } else {
+                None
+            };
+            match resp {
+                Some(resp) => msg.return_to_sender(resp).await,
+                None => msg.return_error_to_sender(None, e.into()).await,
+            }
+        }
+    };
+
+    ctx.meters.latency_histogram.record(
+        dequeued.elapsed().as_millis() as u64,
+        &[
+            KeyValue::new("service", service),
+            KeyValue::new("type", request_type),
+        ],
+    );
+    returned
+}
+
+/// Structured log emitted once a request is completed
+///
+/// The field set is stable: `request_id`, `service`, `model`, `model_alias`, `duration_ms`, `prompt_tokens`, `eval_tokens`, `metadata` and `outcome` (`ok` or `error`).
+struct CompletionLog {
+    request_id: u64,
+    service: String,
+    model: Option<String>,
+    model_alias: Option<String>,
+    prompt_tokens: u64,
+    eval_tokens: u64,
+    metadata: RequestMetadata,
+    begin: Instant,
+}
+
+impl CompletionLog {
+    fn new<M>(msg: &RequestMsg<M>) -> CompletionLog
+    where
+        M: Sized + Clone + Tvf,
+    {
+        CompletionLog {
+            request_id: msg.get_id(),
+            service: msg.get_service().clone(),
+            model: None,
+            model_alias: None,
+            prompt_tokens: 0,
+            eval_tokens: 0,
+            metadata: RequestMetadata::new(),
+            begin: Instant::now(),
+        }
+    }
+
+    fn set_token_counts(&mut self, response: &OllamaResponse) {
+        let usage = response.get_token_usage().unwrap_or_default();
+        (self.prompt_tokens, self.eval_tokens) = (usage.prompt_tokens, usage.eval_tokens);
+    }
+
+    /// Metadata serialized as a JSON object, so a structured pipeline can parse its entries
+    fn metadata_json(&self) -> String {
+        serde_json::to_string(&self.metadata).unwrap_or_default()
+    }
+
+    fn emit(&self, level: TelemetryLevel, outcome: &str) {
+        macro_rules! completion_event {
+            ($level:expr) => {
+                tracing::event!(
+                    $level,
+                    request_id = self.request_id,
+                    service = self.service,
+                    model = self.model.as_deref().unwrap_or_default(),
+                    model_alias = self.model_alias.as_deref(),
+                    duration_ms = self.begin.elapsed().as_millis() as u64,
+                    prompt_tokens = self.prompt_tokens,
+                    eval_tokens = self.eval_tokens,
+                    metadata = %self.metadata_json(),
+                    outcome,
+                    "Ollama request completed"
+                )
+            };
+        }
+
+        match level {
+            TelemetryLevel::OFF => {}
+            TelemetryLevel::ERROR => completion_event!(Level::ERROR),
+            TelemetryLevel::WARN => completion_event!(Level::WARN),
+            TelemetryLevel::INFO => completion_event!(Level::INFO),
+            TelemetryLevel::DEBUG => completion_event!(Level::DEBUG),
+            TelemetryLevel::TRACE => completion_event!(Level::TRACE),
+        }
+    }
+}
+
+#[proc(settings = OllamaProcSettings)]
+pub struct OllamaProc {}
+
+// You must implement the trait Proc to define your processing
+#[proc]
+impl<A> Proc<A> for OllamaProc
+where
+    A: 'static + Default + Adaptor + OllamaAdaptor<M> + std::marker::Send + std::marker::Sync,
+{
+    async fn internal_run(
+        &mut self,
+        _name: String,
+    ) -> Result<(), Box<dyn ProcError + Send + Sync>> {
+        let pool = self.settings.get_ollama()?;
+        self.settings.get_default_format()?;
+
+        // Declare the processor, to be notified of a shutdown while pulling models
+        self.proc.add_proc().await?;
+
+        // Meter to log AI statistics
+        let meters = OllamaMeters::new(
+            &self.get_proc_param().meter(self.settings.get_meter_name()),
+            self.settings.get_metric_prefix(),
+        );
+
+        // Wait for the servers to be ready before any other call, so the services are not declared on an unreachable backend
+        {
+            let readiness = wait_for_readiness(&pool, &self.settings);
+            tokio::pin!(readiness);
+            loop {
+                tokio::select! {
+                    readiness = &mut readiness => break readiness?,
+                    msg = self.internal_rx_queue.recv() => match msg {
+                        Some(InternalMsg::Shutdown) => {
+                            warn!("Shutdown while waiting for the Ollama server to be ready");
+                            self.proc.remove_proc(None).await?;
+                            return Ok(());
+                        }
+                        Some(InternalMsg::Service(table)) => self.service = table,
+                        Some(msg) => debug!("Ignore message while waiting for the Ollama server: {:?}", msg),
+                        None => {}
+                    },
+                }
+            }
+        }
+
+        // Pull missing models, unless the processor is read-only
+        let models_to_pull: &[String] = if self.settings.introspection_only {
+            &[]
+        } else {
+            &self.settings.models
+        };
+
+        // Models loaded by the backend, to respect `max_loaded_models`
+        let mut loaded_models = LoadedModels::default();
+
+        // Local models of the first server, given to the adaptor
+        let mut ready_models = None;
+
+        // Every server of the pool must have the models
+        for ollama in pool.clients() {
+            // List of models
+            let local_models = ollama.local_models().await?;
+
+            for model in models_to_pull {
+                let model = self.settings.resolve_model(model);
+                if is_local_model(&local_models, model) {
+                    continue;
+                }
+
+                // Without auto pull, only the available models are served
+                if !self.settings.auto_pull {
+                    warn!(
+                        "The configured model `{model}` is not available on the Ollama server {}, and is not pulled",
+                        ollama.host()
+                    );
+                    continue;
+                }
+
+                // Pull model, aborted if the processor is shutting down. Ollama keeps the downloaded layers so the pull can be resumed later
+                let pull = pull_model(ollama, model, self.settings.allow_insecure, &meters);
+                tokio::pin!(pull);
+                let pull_model_status = loop {
+                    tokio::select! {
+                        pull_model_status = &mut pull => break pull_model_status?,
+                        msg = self.internal_rx_queue.recv() => match msg {
+                            Some(InternalMsg::Shutdown) => {
+                                warn!("Shutdown during the pull of the model {}, the pull is aborted", model);
+                                self.proc.remove_proc(None).await?;
+                                return Ok(());
+                            }
+                            Some(InternalMsg::Service(table)) => self.service = table,
+                            Some(msg) => debug!("Ignore message while pulling models: {:?}", msg),
+                            None => {}
+                        },
+                    }
+                };
+                info!(
+                    "Pulled the model {} on {}: {}",
+                    model,
+                    ollama.host(),
+                    pull_model_status.message
+                );
+            }
+
+            // Check that all the models are now available, to fail clearly on a misspelled model
+            let local_models = if models_to_pull.is_empty() || !self.settings.auto_pull {
+                local_models
+            } else {
+                check_models_available(ollama, &self.settings, models_to_pull).await?
+            };
+            meters.record_models_available(&self.settings, ollama.host(), &local_models);
+
+            // Preload the models (within `max_loaded_models`), so the first requests don't pay the load time
+            if self.settings.preload_models {
+                let max_loaded_models = self.settings.max_loaded_models.unwrap_or(usize::MAX);
+                let mut preloaded_models = 0;
+                for model in models_to_pull
+                    .iter()
+                    .map(|model| self.settings.resolve_model(model))
+                    .filter(|model| is_local_model(&local_models, model))
+                {
+                    if preloaded_models >= max_loaded_models {
+                        break;
+                    }
+
+                    // Only the models able to generate are preloaded, Ollama rejects an empty generation of an embedding model
+                    let preload = with_request_timeout(self.settings.request_timeout, async {
+                        let model_info: Result<ModelInfo, OllamaError> =
+                            ollama.post_api("show", &ShowModelRequest { model }).await;
+                        if model_info.is_ok_and(|model_info| {
+                            !model_info.capabilities.is_empty()
+                                && !model_info
+                                    .capabilities
+                                    .iter()
+                                    .any(|capability| capability == "completion")
+                        }) {
+                            return Ok(false);
+                        }
+
+                        let mut preload = GenerationRequest::new(model.to_string(), "");
+                        preload.keep_alive = self.settings.keep_alive.clone();
+                        ollama
+                            .post_api::<_, GenerationResponse>("generate", &preload)
+                            .await
+                            .map(|_| true)
+                    });
+                    tokio::pin!(preload);
+                    let preload_status = loop {
+                        tokio::select! {
+                            preload_status = &mut preload => break preload_status,
+                            msg = self.internal_rx_queue.recv() => match msg {
+                                Some(InternalMsg::Shutdown) => {
+                                    warn!("Shutdown during the preload of the model {}, the preload is aborted", model);
+                                    self.proc.remove_proc(None).await?;
+                                    return Ok(());
+                                }
+                                Some(InternalMsg::Service(table)) => self.service = table,
+                                Some(msg) => debug!("Ignore message while preloading models: {:?}", msg),
+                                None => {}
+                            },
+                        }
+                    };
+                    match preload_status {
+                        Ok(true) => {
+                            info!("Preloaded the model {model} on {}", ollama.host());
+                            meters
+                                .model_loaded_counter
+                                .add(1, &[KeyValue::new("model", model.to_string())]);
+                            loaded_models.touch(model, max_loaded_models);
+                            preloaded_models += 1;
+                        }
+                        Ok(false) => debug!(
+                            "The model {model} on {} is not preloaded, without the completion capability",
+                            ollama.host()
+                        ),
+                        Err(e) => warn!(
+                            "Fail to preload the model {model} on {}: {e}",
+                            ollama.host()
+                        ),
+                    }
+                }
+            }
+
+            ready_models.get_or_insert(local_models);
+        }
+
+        // Initiate an adaptor for the Ollama processor
+        let mut adaptor = A::new(self)?;
+        adaptor.on_models_ready(&ready_models.unwrap_or_default())?;
+
+        // Add all service to listen
+        self.proc
+            .add_service_proc(self.settings.services.clone())
+            .await?;
+
+        // Batcher of the embeddings requests, run in background
+        let (embeddings_batcher, embeddings_batch_queue) =
+            match self.settings.embeddings_batch_window {
+                Some(window) => {
+                    let (queue, batch_queue) =
+                        mpsc::channel(self.settings.embeddings_max_batch_size.max(1));
+                    (
+                        Some(EmbeddingsBatcher { queue }),
+                        Some((batch_queue, window)),
+                    )
+                }
+                None => (None, None),
+            };
+
+        let context = Arc::new(RequestContext {
+            settings: RwLock::new(Arc::new(self.settings.clone())),
+            ollama: RwLock::new(pool),
+            auth_refresh: Mutex::new(()),
+            adaptor: Mutex::new(adaptor),
+            meters,
+            loaded_models: Mutex::new(loaded_models),
+            eval_sampler: Mutex::new(EvalSampler::new(
+                self.settings.eval_sampling_rate,
+                self.settings.eval_sampling_file.as_ref(),
+            )),
+            shutdown: watch::Sender::new(false),
+            model_info_cache: Mutex::new(ModelInfoCache::default()),
+            circuit_breaker: Mutex::new(CircuitBreaker::default()),
+            generation_cache: Mutex::new(GenerationCache::default()),
+            embeddings_batcher,
+            phantom: PhantomData,
+        });
+        if let Some((batch_queue, window)) = embeddings_batch_queue {
+            tokio::spawn(run_embeddings_batcher(
+                Arc::downgrade(&context),
+                batch_queue,
+                window,
+                self.settings.embeddings_max_batch_size,
+            ));
+        }
+
+        // Probe the unhealthy servers of a pool, to restore them once they recover
+        let backend_probe = {
+            // The probes stop with the processor
+            let context = Arc::downgrade(&context);
+            let mut probe_interval = self.settings.backend_probe_interval;
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(probe_interval).await;
+                    let Some(context) = context.upgrade() else {
+                        break;
+                    };
+                    probe_interval = context.settings().await.backend_probe_interval;
+                    for ollama in context.ollama_pool().await.clients() {
+                        if !ollama.is_healthy()
+                            && ollama.get_api::<ServerVersion>("version").await.is_ok()
+                        {
+                            info!("The Ollama server {} is healthy again", ollama.host());
+                            ollama.set_healthy();
+                        }
+                    }
+                }
+            })
+        };
+
+        // Count the configured models still available, to notice a model deleted out of band
+        let models_refresh = {
+            // The refreshes stop with the processor
+            let context = Arc::downgrade(&context);
+            let mut refresh_interval = self.settings.models_refresh_interval;
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(refresh_interval).await;
+                    let Some(context) = context.upgrade() else {
+                        break;
+                    };
+                    let settings = context.settings().await;
+                    refresh_interval = settings.models_refresh_interval;
+                    for ollama in context.ollama_pool().await.clients() {
+                        match ollama.local_models().await {
+                            Ok(local_models) => context.meters.record_models_available(
+                                &settings,
+                                ollama.host(),
+                                &local_models,
+                            ),
+                            Err(e) => debug!(
+                                "Fail to list the models of the Ollama server {}: {e}",
+                                ollama.host()
+                            ),
+                        }
+                    }
+                }
+            })
+        };
+
+        // Synchronize the configured models in background, so the requests are served meanwhile
+        let model_sync = self
+            .settings
+            .model_sync_interval
+            .filter(|_| !self.settings.introspection_only)
+            .map(|sync_interval| {
+                // The synchronizations stop with the processor, and never overlap
+                let context = Arc::downgrade(&context);
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(sync_interval).await;
+                        let Some(context) = context.upgrade() else {
+                            break;
+                        };
+                        let settings = context.settings().await;
+                        let pool = context.ollama_pool().await;
+                        debug!("Synchronize the models of the Ollama servers");
+                        if let Err(e) = pull_missing_models(&pool, &settings, &context.meters).await
+                        {
+                            warn!("Fail to synchronize the models of the Ollama servers: {e}");
+                        }
+                    }
+                })
+            });
+
+        // Refresh the models loaded by the servers, to reconcile the models tracked for `max_loaded_models`
+        let running_models_refresh = self
+            .settings
+            .running_models_refresh_interval
+            .map(|refresh_interval| {
+                // The refreshes stop with the processor
+                let context = Arc::downgrade(&context);
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(refresh_interval).await;
+                        let Some(context) = context.upgrade() else {
+                            break;
+                        };
+                        let settings = context.settings().await;
+                        let mut running_models = Vec::new();
+                        let mut refreshed = true;
+                        for ollama in context.ollama_pool().await.clients() {
+                            match with_request_timeout(
+                                settings.request_timeout,
+                                ollama.refresh_running_models(),
+                            )
+                            .await
+                            {
+                                Ok(models) => running_models.extend(models),
+                                Err(e) => {
+                                    debug!(
+                                        "Fail to list the running models of the Ollama server {}: {e}",
+                                        ollama.host()
+                                    );
+                                    refreshed = false;
+                                }
+                            }
+                        }
+                        // A server in error may still keep its models loaded
+                        if refreshed && settings.max_loaded_models.is_some() {
+                            context.loaded_models.lock().await.reconcile(&running_models);
+                        }
+                    }
+                })
+            });
+
+        // Bound of the requests in flight, processed one at a time if not set.
+        // A single request is then in flight in its own task, so the processor keeps reading its queue and notices a shutdown
+        let serial = self.settings.max_concurrent_requests.is_none();
+        let max_concurrent_requests = self.settings.max_concurrent_requests.unwrap_or(1);
+        let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
+
+        // Start with a single slot, to let the backend load its models
+        let concurrency_rampup =
+            self.settings
+                .concurrency_rampup_duration
+                .and_then(|rampup_duration| {
+                    spawn_concurrency_rampup(&semaphore, max_concurrent_requests, rampup_duration)
+                });
+
+        // Requests waiting for a slot, with `priority_queue_capacity`, or the next one when processed one at a time
+        let mut priority_queue = PriorityQueue::new();
+
+        loop {
+            let received = tokio::select! {
+                // A slot is released, the most urgent waiting request is served
+                permit = semaphore.clone().acquire_owned(), if !priority_queue.is_empty() => Err(permit.ok()),
+                // Processed one at a time, a single request waits for its turn out of the queue.
+                // The others stay in the bounded queue, in their arrival order
+                received = async {
+                    match self.settings.connection_warmup {
+                        Some(period) => tokio::time::timeout(period, self.internal_rx_queue.recv())
+                            .await
+                            .ok(),
+                        None => Some(self.internal_rx_queue.recv().await),
+                    }
+                }, if !serial || priority_queue.is_empty() => Ok(received),
+            };
+            let msg = match received {
+                Ok(Some(msg)) => msg,
+                Err(permit) => {
+                    if let Some(permit) = permit
+                        && let Some(pending) = priority_queue.pop(self.settings.priority_aging)
+                    {
+                        context.meters.queue_depth_gauge.record(
+                            (self.internal_rx_queue.len() + priority_queue.len()) as u64,
+                            &[],
+                        );
+                        spawn_request(
+                            &context,
+                            self.service.clone(),
+                            pending.msg,
+                            pending.dequeued,
+                            permit,
+                        );
+                    }
+                    continue;
+                }
+                Ok(None) => {
+                    // Idle backend, a light call keeps the pooled connection open for the next request.
+                    // The calls are bounded and don't block the processor loop
+                    let context = context.clone();
+                    tokio::spawn(async move {
+                        let request_timeout = context.settings().await.request_timeout;
+                        for ollama in context.ollama_pool().await.clients() {
+                            if let Err(e) =
+                                with_request_timeout(request_timeout, ollama.local_models()).await
+                            {
+                                debug!(
+                                    "Fail to warm up the connection to Ollama {}: {e}",
+                                    ollama.host()
+                                );
+                            }
+                        }
+                    });
+                    continue;
+                }
+            };
+
+            if let Some(msg) = msg {
+                context.meters.queue_depth_gauge.record(
+                    (self.internal_rx_queue.len() + priority_queue.len()) as u64,
+                    &[],
+                );
+                match msg {
+                    InternalMsg::Request(msg) => {
+                        let dequeued = Instant::now();
+                        let span = msg.get_span().clone();
+                        // The waiting requests are served first, by priority
+                        let permit = if priority_queue.is_empty() {
+                            semaphore.clone().try_acquire_owned().ok()
+                        } else {
+                            None
+                        };
+                        match permit {
+                            Some(permit) => {
+                                spawn_request(&context, self.service.clone(), msg, dequeued, permit)
+                            }
+                            // Processed one at a time, the request waits for the current one
+                            None if serial => priority_queue.push(PendingRequest {
+                                msg,
+                                priority: 0,
+                                dequeued,
+                            }),
+                            None if self
+                                .settings
+                                .priority_queue_capacity
+                                .is_some_and(|capacity| priority_queue.len() < capacity) =>
+                            {
+                                let priority = match msg.get_data() {
+                                    Ok(data) => context
+                                        .adaptor
+                                        .lock()
+                                        .await
+                                        .request_priority(msg.get_service(), data),
+                                    Err(_) => 0,
+                                };
+                                priority_queue.push(PendingRequest {
+                                    msg,
+                                    priority,
+                                    dequeued,
+                                });
+                            }
+                            None => {
+                                let e = OllamaError::Overloaded {
+                                    max_concurrent_requests,
+                                };
+                                warn!("Request rejected: {e}");
+                                return_result(&context, msg, "rejected", dequeued, Err(e))
+                                    .instrument(span)
+                                    .await?;
+                            }
+                        }
+                    }
+                    InternalMsg::Response(msg) => panic!(
+                        "The Ollama processor {} receive a response {:?}",
+                        self.get_proc_id(),
+                        msg
+                    ),
+                    InternalMsg::Error(err) => panic!(
+                        "The Ollama processor {} receive an error {:?}",
+                        self.get_proc_id(),
+                        err
+                    ),
+                    InternalMsg::Command(command) if command == BACKEND_STATUS_COMMAND => {
+                        match serde_json::to_string(&context.backend_statuses().await) {
+                            Ok(backends) => info!(backends, "Status of the Ollama servers"),
+                            Err(e) => {
+                                warn!("Can't serialize the status of the Ollama servers: {e}")
+                            }
+                        }
+                    }
+                    InternalMsg::Command(command) => {
+                        let processed = context.adaptor.lock().await.process_command(&command);
+                        if let Err(e) = processed {
+                            warn!("Fail to process the command `{command}`: {e}");
+                        }
+                    }
+                    InternalMsg::Config => {
+                        let reloaded = context.adaptor.lock().await.reload_config(&self.settings);
+                        match reloaded {
+                            Ok(Some(mut settings)) => match settings
+                                .get_default_format()
+                                .and_then(|_| settings.get_ollama())
+                            {
+                                Ok(pool) => {
+                                    // The semaphore, the batcher, the background tasks and the meters are built at startup
+                                    let unchanged =
+                                        settings.retain_startup_settings(&self.settings);
+                                    if !unchanged.is_empty() {
+                                        warn!(
+                                            "The settings {} are only applied on the next start, their running value is kept",
+                                            unchanged.join(", ")
+                                        );
+                                    }
+
+                                    // Update the services to listen
+                                    let removed_services: Vec<String> = self
+                                        .settings
+                                        .services
+                                        .iter()
+                                        .filter(|service| !settings.services.contains(service))
+                                        .cloned()
+                                        .collect();
+                                    if !removed_services.is_empty() {
+                                        self.proc.remove_service_proc(removed_services).await?;
+                                    }
+                                    let added_services: Vec<String> = settings
+                                        .services
+                                        .iter()
+                                        .filter(|service| !self.settings.services.contains(service))
+                                        .cloned()
+                                        .collect();
+                                    if !added_services.is_empty() {
+                                        self.proc.add_service_proc(added_services).await?;
+                                    }
+
+                                    // The calls in flight on the replaced clients are drained like on a shutdown
+                                    let pool = context
+                                        .reload_ollama(pool, settings.shutdown_grace_period)
+                                        .await;
+                                    if settings.eval_sampling_rate
+                                        != self.settings.eval_sampling_rate
+                                        || settings.eval_sampling_file
+                                            != self.settings.eval_sampling_file
+                                    {
+                                        *context.eval_sampler.lock().await = EvalSampler::new(
+                                            settings.eval_sampling_rate,
+                                            settings.eval_sampling_file.as_ref(),
+                                        );
+                                    }
+                                    *context.settings.write().await = Arc::new(settings.clone());
+                                    self.settings = settings;
+                                    info!("Reloaded the Ollama settings");
+                                    context
+                                        .adaptor
+                                        .lock()
+                                        .await
+                                        .on_config_reload(&self.settings);
+
+                                    // Pull the new models in background, to keep serving the requests meanwhile
+                                    if !self.settings.introspection_only {
+                                        let context = context.clone();
+                                        tokio::spawn(async move {
+                                            let settings = context.settings().await;
+                                            if let Err(e) = pull_missing_models(
+                                                &pool,
+                                                &settings,
+                                                &context.meters,
+                                            )
+                                            .await
+                                            {
+                                                warn!(
+                                                    "Fail to pull the models of the reloaded settings: {e}"
+                                                );
+                                            }
+                                        });
+                                    }
+                                }
+                                Err(e) => warn!(
+                                    "The reloaded settings are not applied, they are invalid: {e}"
+                                ),
+                            },
+                            Ok(None) => debug!("No Ollama settings to reload"),
+                            Err(e) => warn!("Fail to reload the Ollama settings: {e}"),
+                        }
+                    }
+                    InternalMsg::Service(table) => self.service = table,
+                    InternalMsg::Shutdown => {
+                        // Stop accepting new requests
+                        if let Err(e) = self
+                            .proc
+                            .remove_service_proc(self.settings.services.clone())
+                            .await
+                        {
+                            debug!("Fail to remove the services on shutdown: {e}");
+                        }
+
+                        // The requests waiting for a slot will not be served
+                        for pending in priority_queue.drain() {
+                            return_result(
+                                &context,
+                                pending.msg,
+                                "rejected",
+                                pending.dequeued,
+                                Err(OllamaError::Shutdown),
+                            )
+                            .await?;
+                        }
+
+                        // Let the requests in flight finish within the grace period
+                        if let Some(concurrency_rampup) = &concurrency_rampup {
+                            concurrency_rampup.abort();
+                        }
+                        let in_flight = semaphore.acquire_many(max_concurrent_requests as u32);
+                        tokio::pin!(in_flight);
+                        let grace_period = tokio::time::sleep(self.settings.shutdown_grace_period);
+                        tokio::pin!(grace_period);
+                        let mut aborted = false;
+                        loop {
+                            tokio::select! {
+                                _ = &mut in_flight => break,
+                                _ = &mut grace_period, if !aborted => {
+                                    warn!("Shutdown grace period elapsed, the requests in flight are aborted");
+                                    context.shutdown.send_replace(true);
+                                    aborted = true;
+                                }
+                                msg = self.internal_rx_queue.recv() => match msg {
+                                    Some(InternalMsg::Request(msg)) => {
+                                        return_result(
+                                            &context,
+                                            msg,
+                                            "rejected",
+                                            Instant::now(),
+                                            Err(OllamaError::Shutdown),
+                                        )
+                                        .await?
+                                    }
+                                    Some(msg) => debug!("Ignore message during the shutdown: {:?}", msg),
+                                    None => {}
+                                },
+                            }
+                        }
+
+                        // Requests still queued
+                        while let Ok(msg) = self.internal_rx_queue.try_recv() {
+                            if let InternalMsg::Request(msg) = msg {
+                                return_result(
+                                    &context,
+                                    msg,
+                                    "rejected",
+                                    Instant::now(),
+                                    Err(OllamaError::Shutdown),
+                                )
+                                .await?;
+                            }
+                        }
+
+                        backend_probe.abort();
+                        models_refresh.abort();
+                        if let Some(model_sync) = &model_sync {
+                            model_sync.abort();
+                        }
+                        if let Some(running_models_refresh) = &running_models_refresh {
+                            running_models_refresh.abort();
+                        }
+                        let mut adaptor = context.adaptor.lock().await;
+                        if let Err(e) = adaptor.try_terminate() {
+                            warn!("The adaptor fails to terminate: {e}");
+                        }
+                        adaptor.terminate();
+                        drop(adaptor);
+                        self.proc.remove_proc(None).await?;
                         return Ok(());
                     }
                 }
@@ -477,3 +6439,1690 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::metrics::MeterProvider as _;
+    use prosa_utils::msg::simple_string_tvf::SimpleStringTvf;
+    use tokio::io::AsyncReadExt as _;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Reply of the fake server: HTTP status and body, or `None` to drop the connection without reply
+    type Reply = Option<(u16, String)>;
+
+    /// Fake Ollama server, answering each request with a handler of its path and of the number of previous requests on this path
+    struct FakeServer {
+        url: Url,
+        hits: Arc<std::sync::Mutex<HashMap<String, usize>>>,
+        /// Head of the last request received on each path
+        heads: Arc<std::sync::Mutex<HashMap<String, String>>>,
+    }
+
+    impl FakeServer {
+        async fn start<F>(handler: F) -> FakeServer
+        where
+            F: Fn(&str, usize) -> Reply + Send + Sync + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+            let hits: Arc<std::sync::Mutex<HashMap<String, usize>>> = Arc::default();
+            let heads: Arc<std::sync::Mutex<HashMap<String, String>>> = Arc::default();
+            let handler = Arc::new(handler);
+            tokio::spawn({
+                let hits = hits.clone();
+                let heads = heads.clone();
+                async move {
+                    while let Ok((mut socket, _)) = listener.accept().await {
+                        let handler = handler.clone();
+                        let hits = hits.clone();
+                        let heads = heads.clone();
+                        tokio::spawn(async move {
+                            let Some((path, head)) = read_request(&mut socket).await else {
+                                return;
+                            };
+                            heads.lock().unwrap().insert(path.clone(), head);
+                            let hit = {
+                                let mut hits = hits.lock().unwrap();
+                                let count = hits.entry(path.clone()).or_default();
+                                *count += 1;
+                                *count - 1
+                            };
+                            if let Some((status, body)) = handler(&path, hit) {
+                                let reply = format!(
+                                    "HTTP/1.1 {status} Fake\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                                    body.len()
+                                );
+                                let _ = socket.write_all(reply.as_bytes()).await;
+                            }
+                        });
+                    }
+                }
+            });
+
+            FakeServer { url, hits, heads }
+        }
+
+        /// Number of requests received on a path
+        fn hits(&self, path: &str) -> usize {
+            self.hits
+                .lock()
+                .unwrap()
+                .get(path)
+                .copied()
+                .unwrap_or_default()
+        }
+
+        /// Value of a header of the last request received on a path
+        fn header(&self, path: &str, name: &str) -> Option<String> {
+            let prefix = format!("{}:", name.to_lowercase());
+            self.heads
+                .lock()
+                .unwrap()
+                .get(path)?
+                .lines()
+                .find_map(|line| {
+                    line.to_lowercase()
+                        .starts_with(&prefix)
+                        .then(|| line[prefix.len()..].trim().to_string())
+                })
+        }
+
+        /// Settings of a processor using the fake server, with the services of the [`TestAdaptor`]
+        fn settings(&self) -> OllamaProcSettings {
+            OllamaProcSettings::new(
+                self.url.clone(),
+                false,
+                vec!["gen".to_string(), "info".to_string(), "embed".to_string()],
+            )
+        }
+
+        /// Client of the fake server
+        fn client(&self) -> OllamaClient {
+            self.settings().get_ollama().unwrap().clients()[0].clone()
+        }
+    }
+
+    /// Read a request up to the end of its body, and return its path and its head
+    async fn read_request(socket: &mut TcpStream) -> Option<(String, String)> {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = socket.read(&mut buffer).await.ok()?;
+            if read == 0 {
+                return None;
+            }
+            request.extend_from_slice(&buffer[..read]);
+            if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&request[..end]).to_string();
+                let length = head
+                    .lines()
+                    .find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("content-length:")
+                            .map(|length| length.trim().parse::<usize>().unwrap_or_default())
+                    })
+                    .unwrap_or_default();
+                if request.len() >= end + 4 + length {
+                    let path = head.split_whitespace().nth(1)?.to_string();
+                    return Some((path, head));
+                }
+            }
+        }
+    }
+
+    /// Adaptor of the tests: the prompt (or the model of the info request) is the field 1 of the message, and so is the response text
+    #[derive(Default, Adaptor)]
+    struct TestAdaptor;
+
+    impl OllamaAdaptor<SimpleStringTvf> for TestAdaptor {
+        fn new(_proc: &OllamaProc<SimpleStringTvf>) -> Result<Self, OllamaError> {
+            Ok(TestAdaptor)
+        }
+
+        fn process_request<'a>(
+            &mut self,
+            service_name: &str,
+            request: SimpleStringTvf,
+        ) -> Result<OllamaRequest<'a>, OllamaError> {
+            let field = request
+                .get_string(1)
+                .map_err(|e| OllamaError::bad_field("1", e.to_string()))?
+                .into_owned();
+            match service_name {
+                "gen" => Ok(OllamaRequest::GenerateRequest(Box::new(
+                    GenerationRequest::new("ollama".to_string(), field),
+                ))),
+                "stream" => Ok(OllamaRequest::GenerateStreamRequest(Box::new(
+                    GenerationRequest::new("ollama".to_string(), field),
+                ))),
+                "info" => Ok(OllamaRequest::ModelInfo(field)),
+                "embed" => Ok(OllamaRequest::GenerateEmbeddingsRequest(Box::new(
+                    GenerateEmbeddingsRequest::new("ollama".to_string(), field.into()),
+                ))),
+                _ => Err(OllamaError::UnknownService(service_name.to_string())),
+            }
+        }
+
+        fn process_ollama_response(
+            &mut self,
+            response: OllamaResponse,
+        ) -> Result<SimpleStringTvf, OllamaError> {
+            let mut msg = SimpleStringTvf::default();
+            match response {
+                OllamaResponse::GenerateResponse(response) => msg.put_string(1, response.response),
+                OllamaResponse::GenerateEmbeddingsResponse(response) => {
+                    msg.put_string(1, response.embeddings.len().to_string())
+                }
+                _ => msg.put_string(1, "other"),
+            }
+            Ok(msg)
+        }
+
+        fn process_stream_chunk(
+            &mut self,
+            service_name: &str,
+            request_id: u64,
+            chunk: &GenerationResponse,
+        ) -> Result<Option<SimpleStringTvf>, OllamaError> {
+            let mut msg = SimpleStringTvf::default();
+            msg.put_string(1, chunk.response.clone());
+            msg.put_string(2, format!("{service_name}#{request_id}"));
+            Ok(Some(msg))
+        }
+    }
+
+    /// Context of the requests of the tests, with the queue of the processor
+    fn test_context(
+        settings: OllamaProcSettings,
+        meter: &Meter,
+    ) -> (
+        RequestContext<SimpleStringTvf, TestAdaptor>,
+        mpsc::Receiver<InternalMsg<SimpleStringTvf>>,
+    ) {
+        let (_, queue) = mpsc::channel(16);
+        let context = RequestContext {
+            ollama: RwLock::new(settings.get_ollama().unwrap()),
+            auth_refresh: Mutex::new(()),
+            meters: OllamaMeters::new(meter, settings.get_metric_prefix()),
+            settings: RwLock::new(Arc::new(settings)),
+            adaptor: Mutex::new(TestAdaptor),
+            loaded_models: Mutex::new(LoadedModels::default()),
+            eval_sampler: Mutex::new(EvalSampler::new(0.0, None)),
+            shutdown: watch::Sender::new(false),
+            model_info_cache: Mutex::new(ModelInfoCache::default()),
+            circuit_breaker: Mutex::new(CircuitBreaker::default()),
+            generation_cache: Mutex::new(GenerationCache::default()),
+            embeddings_batcher: None,
+            phantom: PhantomData,
+        };
+        (context, queue)
+    }
+
+    /// Request to a service of the tests, with the queue of its response
+    fn test_request(
+        service: &str,
+        field: &str,
+    ) -> (
+        RequestMsg<SimpleStringTvf>,
+        mpsc::Receiver<InternalMsg<SimpleStringTvf>>,
+    ) {
+        let (response_queue, responses) = mpsc::channel(1);
+        let mut data = SimpleStringTvf::default();
+        data.put_string(1, field);
+        (
+            RequestMsg::new(service.to_string(), data, response_queue),
+            responses,
+        )
+    }
+
+    /// Body of a generation response
+    fn generation_reply(response: &str) -> String {
+        serde_json::json!({
+            "model": "ollama",
+            "created_at": "2024-01-01T00:00:00Z",
+            "response": response,
+            "done": true,
+        })
+        .to_string()
+    }
+
+    /// Body of a streamed generation, with a chunk of each response
+    fn generation_stream_reply(responses: &[String]) -> String {
+        responses
+            .iter()
+            .enumerate()
+            .map(|(index, response)| {
+                let chunk = serde_json::json!({
+                    "model": "ollama",
+                    "created_at": "2024-01-01T00:00:00Z",
+                    "response": response,
+                    "done": index + 1 == responses.len(),
+                });
+                chunk.to_string() + "\n"
+            })
+            .collect()
+    }
+
+    /// Meter provider exporting the metrics in memory, to assert the recorded values
+    fn test_meter_provider() -> (
+        opentelemetry_sdk::metrics::SdkMeterProvider,
+        opentelemetry_sdk::metrics::InMemoryMetricExporter,
+    ) {
+        let exporter = opentelemetry_sdk::metrics::InMemoryMetricExporter::default();
+        let provider = opentelemetry_sdk::metrics::SdkMeterProvider::builder()
+            .with_reader(
+                opentelemetry_sdk::metrics::PeriodicReader::builder(exporter.clone()).build(),
+            )
+            .build();
+        (provider, exporter)
+    }
+
+    /// Value of a gauge with the given attributes at the last export, `None` if nothing is recorded on it
+    fn gauge_value(
+        provider: &opentelemetry_sdk::metrics::SdkMeterProvider,
+        exporter: &opentelemetry_sdk::metrics::InMemoryMetricExporter,
+        name: &str,
+        attributes: &[KeyValue],
+    ) -> Option<u64> {
+        provider.force_flush().unwrap();
+        exporter
+            .get_finished_metrics()
+            .unwrap()
+            .last()?
+            .scope_metrics
+            .iter()
+            .flat_map(|scope_metrics| &scope_metrics.metrics)
+            .filter(|metric| metric.name == name)
+            .filter_map(|metric| {
+                metric
+                    .data
+                    .as_any()
+                    .downcast_ref::<opentelemetry_sdk::metrics::data::Gauge<u64>>()
+            })
+            .flat_map(|gauge| &gauge.data_points)
+            .find(|point| {
+                attributes
+                    .iter()
+                    .all(|attribute| point.attributes.contains(attribute))
+            })
+            .map(|point| point.value)
+    }
+
+    /// Sum of the points of a counter with the given attributes at the last export (cumulative), `None` if nothing is recorded on it
+    fn counter_sum(
+        provider: &opentelemetry_sdk::metrics::SdkMeterProvider,
+        exporter: &opentelemetry_sdk::metrics::InMemoryMetricExporter,
+        name: &str,
+        attributes: &[KeyValue],
+    ) -> Option<u64> {
+        provider.force_flush().unwrap();
+        exporter
+            .get_finished_metrics()
+            .unwrap()
+            .last()?
+            .scope_metrics
+            .iter()
+            .flat_map(|scope_metrics| &scope_metrics.metrics)
+            .filter(|metric| metric.name == name)
+            .filter_map(|metric| {
+                metric
+                    .data
+                    .as_any()
+                    .downcast_ref::<opentelemetry_sdk::metrics::data::Sum<u64>>()
+            })
+            .flat_map(|sum| &sum.data_points)
+            .filter(|point| {
+                attributes
+                    .iter()
+                    .all(|attribute| point.attributes.contains(attribute))
+            })
+            .map(|point| point.value)
+            .reduce(|sum, value| sum + value)
+    }
+
+    /// Error of a failed call, the responses can't be formatted
+    fn expect_error<T>(result: Result<T, OllamaError>) -> OllamaError {
+        match result {
+            Ok(_) => panic!("The call succeeded"),
+            Err(e) => e,
+        }
+    }
+
+    /// Ollama error of a failed call, unwrapped from the processor error
+    fn ollama_rs_error(error: OllamaError) -> ollama_rs::error::OllamaError {
+        match error {
+            OllamaError::Ollama(error) => error,
+            error => panic!("Not an ollama-rs error: {error}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_connection_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/version", listener.local_addr().unwrap());
+        drop(listener);
+
+        let error = ollama_rs::error::OllamaError::from(reqwest::get(url).await.unwrap_err());
+        assert!(is_transient(&error));
+        assert!(OllamaError::Ollama(error).recoverable());
+    }
+
+    #[tokio::test]
+    async fn transient_timeout() {
+        // The connection is accepted, but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/version", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _sockets: Vec<_> = vec![listener.accept().await];
+            std::future::pending::<()>().await;
+        });
+
+        let error = reqwest::Client::new()
+            .get(url)
+            .timeout(Duration::from_millis(50))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(is_transient(&error.into()));
+    }
+
+    #[tokio::test]
+    async fn transient_dropped_connection() {
+        let server = FakeServer::start(|_, _| None).await;
+        let error = server
+            .client()
+            .get_api::<ServerVersion>("version")
+            .await
+            .unwrap_err();
+        assert!(is_transient(&ollama_rs_error(error)));
+    }
+
+    #[tokio::test]
+    async fn not_transient_invalid_reply() {
+        let server = FakeServer::start(|_, _| Some((200, "not json".to_string()))).await;
+        let error = server
+            .client()
+            .get_api::<ServerVersion>("version")
+            .await
+            .unwrap_err();
+        assert!(!is_transient(&ollama_rs_error(error)));
+    }
+
+    #[test]
+    fn not_transient_deterministic_errors() {
+        let json_error = serde_json::from_str::<u8>("x").unwrap_err();
+        for error in [
+            ollama_rs::error::OllamaError::ToolCallError(
+                ollama_rs::error::ToolCallError::UnknownToolName,
+            ),
+            ollama_rs::error::OllamaError::JsonError(json_error),
+            ollama_rs::error::OllamaError::InternalError(ollama_rs::error::InternalOllamaError {
+                message: "invalid options".to_string(),
+            }),
+            ollama_rs::error::OllamaError::Other("model \"x\" not found".to_string()),
+        ] {
+            assert!(!is_transient(&error), "{error:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn error_reply_status() {
+        let server = FakeServer::start(|path, _| match path {
+            "/api/generate" => Some((503, r#"{"error":"server busy"}"#.to_string())),
+            "/api/chat" => Some((
+                500,
+                r#"{"error":"llama runner process has terminated"}"#.to_string(),
+            )),
+            "/api/embed" => Some((429, String::new())),
+            "/api/show" => Some((
+                404,
+                r#"{"error":"model \"x\" not found, try pulling it first"}"#.to_string(),
+            )),
+            "/api/tags" => Some((401, String::new())),
+            _ => Some((400, r#"{"error":"invalid request"}"#.to_string())),
+        })
+        .await;
+        let client = server.client();
+        let call = |endpoint: &'static str| {
+            let client = client.clone();
+            async move {
+                client
+                    .post_api::<_, serde_json::Value>(endpoint, &serde_json::json!({}))
+                    .await
+                    .unwrap_err()
+            }
+        };
+
+        let busy = call("generate").await;
+        assert!(
+            matches!(&busy, OllamaError::Http { status, message } if status.as_u16() == 503 && message == "server busy")
+        );
+        assert!(busy.recoverable());
+        assert_eq!(busy.category(), "server_error");
+        assert_eq!(server.hits("/api/generate"), 1);
+        assert!(call("chat").await.recoverable());
+        assert!(call("embed").await.recoverable());
+
+        let not_found = call("show").await;
+        assert!(!not_found.recoverable());
+        assert!(is_model_not_found(&not_found));
+        assert_eq!(not_found.category(), "backend");
+
+        let unauthorized = client.local_models().await.unwrap_err();
+        assert!(!unauthorized.recoverable());
+        assert!(is_unauthorized(&unauthorized));
+
+        assert!(!call("create").await.recoverable());
+    }
+
+    #[tokio::test]
+    async fn auth_refresh_retries_with_new_token() {
+        let server = FakeServer::start(|path, hit| match (path, hit) {
+            ("/api/generate", 0) => Some((401, r#"{"error":"unauthorized"}"#.to_string())),
+            ("/api/generate", _) => Some((200, generation_reply("refreshed"))),
+            _ => None,
+        })
+        .await;
+        let mut settings = server.settings();
+        settings.auth_refresh_command = Some("echo new-token".to_string());
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+        let health = ctx.ollama_pool().await.clients()[0].health.clone();
+
+        let response = call_with_auth_refresh(
+            &ctx,
+            OllamaRequest::GenerateRequest(Box::new(GenerationRequest::new(
+                "ollama".to_string(),
+                "prompt",
+            ))),
+        )
+        .await
+        .unwrap();
+        let OllamaResponse::GenerateResponse(response) = response else {
+            panic!("Not a generation response");
+        };
+        assert_eq!(response.response, "refreshed");
+        assert_eq!(server.hits("/api/generate"), 2);
+        // The rebuilt clients keep the health of the servers
+        assert!(Arc::ptr_eq(
+            &health,
+            &ctx.ollama_pool().await.clients()[0].health
+        ));
+    }
+
+    #[tokio::test]
+    async fn concurrent_rejections_refresh_once() {
+        let server = FakeServer::start(|path, hit| match path {
+            "/api/generate" if hit < 4 => Some((401, r#"{"error":"unauthorized"}"#.to_string())),
+            "/api/generate" => Some((200, generation_reply("refreshed"))),
+            _ => None,
+        })
+        .await;
+        let refreshes =
+            env::temp_dir().join(format!("prosa-ollama-refreshes-{}", std::process::id()));
+        let _ = std::fs::remove_file(&refreshes);
+        let mut settings = server.settings();
+        // The slow refresh lets every call be rejected before it ends
+        settings.auth_refresh_command = Some(format!(
+            "echo >> {}; sleep 0.2; echo new-token",
+            refreshes.display()
+        ));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+        let call = || {
+            call_with_auth_refresh(
+                &ctx,
+                OllamaRequest::GenerateRequest(Box::new(GenerationRequest::new(
+                    "ollama".to_string(),
+                    "prompt",
+                ))),
+            )
+        };
+
+        let responses = tokio::join!(call(), call(), call(), call());
+        for response in [responses.0, responses.1, responses.2, responses.3] {
+            assert!(response.is_ok());
+        }
+        assert_eq!(server.hits("/api/generate"), 8);
+        assert_eq!(
+            std::fs::read_to_string(&refreshes).unwrap().lines().count(),
+            1
+        );
+        std::fs::remove_file(&refreshes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn backend_auth_kept_on_refresh() {
+        let global =
+            FakeServer::start(|_, _| Some((200, r#"{"version":"0.9.0"}"#.to_string()))).await;
+        let own_key =
+            FakeServer::start(|_, _| Some((200, r#"{"version":"0.9.0"}"#.to_string()))).await;
+        let url_credentials =
+            FakeServer::start(|_, _| Some((200, r#"{"version":"0.9.0"}"#.to_string()))).await;
+        let mut credentials_url = url_credentials.url.clone();
+        credentials_url.set_username("user").unwrap();
+        credentials_url.set_password(Some("password")).unwrap();
+        let own_key_host = format!(
+            "{}:{}",
+            own_key.url.host_str().unwrap(),
+            own_key.url.port().unwrap()
+        );
+
+        let mut settings = global.settings();
+        settings.set_urls(vec![
+            global.url.clone(),
+            own_key.url.clone(),
+            credentials_url,
+        ]);
+        settings.set_auth_token(Some("global-token".to_string()));
+        settings.set_headers(HashMap::from([(
+            "X-Tenant-Id".to_string(),
+            "tenant-a".to_string(),
+        )]));
+        settings.set_backend_auth(
+            own_key_host,
+            BackendAuth::new(
+                Some("own-key".to_string()),
+                HashMap::from([("X-Tenant-Id".to_string(), "tenant-b".to_string())]),
+            ),
+        );
+        let debug = format!("{settings:?}");
+        assert!(
+            !debug.contains("own-key") && !debug.contains("tenant-b"),
+            "{debug}"
+        );
+        assert_eq!(settings.get_auth_mode(), "bearer+basic");
+
+        // The refreshed token only replaces the global one
+        for ollama in settings
+            .get_ollama_with_token("new-token")
+            .unwrap()
+            .clients()
+        {
+            ollama.get_api::<ServerVersion>("version").await.unwrap();
+        }
+        assert_eq!(
+            global.header("/api/version", "Authorization").as_deref(),
+            Some("Bearer new-token")
+        );
+        assert_eq!(
+            global.header("/api/version", "X-Tenant-Id").as_deref(),
+            Some("tenant-a")
+        );
+        assert_eq!(
+            own_key.header("/api/version", "Authorization").as_deref(),
+            Some("Bearer own-key")
+        );
+        assert_eq!(
+            own_key.header("/api/version", "X-Tenant-Id").as_deref(),
+            Some("tenant-b")
+        );
+        assert_eq!(
+            url_credentials
+                .header("/api/version", "Authorization")
+                .as_deref(),
+            Some(format!("Basic {}", STANDARD.encode("user:password")).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn unauthorized_from_status_only() {
+        let server = FakeServer::start(|path, _| match path {
+            "/api/version" => Some((500, r#"{"error":"upstream replied 401"}"#.to_string())),
+            _ => Some((401, String::new())),
+        })
+        .await;
+        let client = server.client();
+
+        assert!(!is_unauthorized(
+            &client
+                .get_api::<ServerVersion>("version")
+                .await
+                .unwrap_err()
+        ));
+        assert!(is_unauthorized(
+            &client.get_api::<ServerVersion>("tags").await.unwrap_err()
+        ));
+    }
+
+    #[tokio::test]
+    async fn loaded_model_tracked_after_success() {
+        let server = FakeServer::start(|path, hit| match (path, hit) {
+            ("/api/generate", 0) => Some((500, r#"{"error":"load failed"}"#.to_string())),
+            ("/api/generate", _) => Some((200, generation_reply("loaded"))),
+            _ => None,
+        })
+        .await;
+        let mut settings = server.settings();
+        settings.set_max_loaded_models(Some(1));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+        ctx.loaded_models.lock().await.touch("other", 1);
+
+        // A failed request doesn't evict the loaded model
+        let (msg, _responses) = test_request("gen", "prompt");
+        handle_request(&ctx, Arc::default(), msg, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(ctx.loaded_models.lock().await.models, ["other"]);
+        assert_eq!(server.hits("/api/generate"), 1);
+
+        // A successful one evicts it, with an unload request
+        let (msg, _responses) = test_request("gen", "prompt");
+        handle_request(&ctx, Arc::default(), msg, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(ctx.loaded_models.lock().await.models, ["ollama"]);
+        assert_eq!(server.hits("/api/generate"), 3);
+    }
+
+    #[tokio::test]
+    async fn readiness_after_probes() {
+        let server = FakeServer::start(|path, hit| match path {
+            "/api/version" if hit >= 3 => Some((200, r#"{"version":"0.6.0"}"#.to_string())),
+            _ => None,
+        })
+        .await;
+        let mut settings = server.settings();
+        settings.set_readiness_probe(Duration::from_millis(5), Duration::from_secs(5));
+
+        wait_for_readiness(&settings.get_ollama().unwrap(), &settings)
+            .await
+            .unwrap();
+        assert_eq!(server.hits("/api/version"), 4);
+
+        // Not ready within the maximum wait
+        let server = FakeServer::start(|_, _| None).await;
+        let mut settings = server.settings();
+        settings.set_readiness_probe(Duration::from_millis(20), Duration::from_millis(100));
+        assert!(
+            wait_for_readiness(&settings.get_ollama().unwrap(), &settings)
+                .await
+                .is_err()
+        );
+        assert_eq!(server.hits("/api/version"), 3);
+    }
+
+    #[tokio::test]
+    async fn missing_model_named() {
+        let server = FakeServer::start(|path, _| match path {
+            "/api/tags" => Some((200, r#"{"models":[]}"#.to_string())),
+            _ => None,
+        })
+        .await;
+
+        let error = check_models_available(
+            &server.client(),
+            &server.settings(),
+            &["mistral:7b".to_string()],
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, OllamaError::Other(_)));
+        assert!(error.to_string().contains("`mistral:7b`"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn backend_status_snapshot() {
+        let server = FakeServer::start(|_, _| None).await;
+        let (ctx, _queue) = test_context(server.settings(), &opentelemetry::global::meter("test"));
+        let ollama = ctx.ollama_pool().await.clients()[0].clone();
+
+        let in_flight = ollama.start_call();
+        let OllamaResponse::Status { backends, .. } =
+            dispatch(&ctx, None, "gen", OllamaRequest::Status, None)
+                .await
+                .unwrap()
+        else {
+            panic!("Not a status response");
+        };
+        assert_eq!(backends.len(), 1);
+        assert_eq!(backends[0].host, ollama.host());
+        assert!(backends[0].healthy);
+        assert_eq!(backends[0].in_flight, 1);
+        assert_eq!(backends[0].circuit_state, "closed");
+
+        drop(in_flight);
+        assert_eq!(ctx.backend_statuses().await[0].in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn concurrency_rampup() {
+        let semaphore = Arc::new(Semaphore::new(3));
+        let rampup = spawn_concurrency_rampup(&semaphore, 3, Duration::from_millis(100)).unwrap();
+        assert_eq!(semaphore.available_permits(), 1);
+
+        tokio::time::sleep(Duration::from_millis(75)).await;
+        assert_eq!(semaphore.available_permits(), 2);
+        rampup.await.unwrap();
+        assert_eq!(semaphore.available_permits(), 3);
+
+        // Aborting the ramp-up releases all the slots
+        let rampup = spawn_concurrency_rampup(&semaphore, 3, Duration::from_secs(60)).unwrap();
+        rampup.abort();
+        assert!(rampup.await.unwrap_err().is_cancelled());
+        assert_eq!(semaphore.available_permits(), 3);
+        assert!(spawn_concurrency_rampup(&semaphore, 1, Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn embeddings_counted_as_vectors() {
+        let (provider, exporter) = test_meter_provider();
+        let meters = OllamaMeters::new(&provider.meter("test"), "prosa_ollama");
+
+        let response =
+            OllamaResponse::GenerateEmbeddingsResponse(Box::new(GenerateEmbeddingsResponse {
+                embeddings: vec![vec![0.1, 0.2], vec![0.3, 0.4]],
+            }));
+        meters.record(&response, Some("all-minilm"), &[]);
+        assert_eq!(
+            counter_sum(&provider, &exporter, "prosa_ollama_embedding_count", &[]),
+            Some(2)
+        );
+        // The token counters are not polluted by the embeddings
+        assert_eq!(
+            counter_sum(&provider, &exporter, "prosa_ollama_gen_token_count", &[]),
+            None
+        );
+        assert_eq!(
+            counter_sum(&provider, &exporter, "prosa_ollama_prompt_token_count", &[]),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn token_count_out_of_generation_metrics() {
+        let server = FakeServer::start(|path, _| match path {
+            "/api/generate" => Some((
+                200,
+                serde_json::json!({
+                    "model": "ollama",
+                    "created_at": "2024-01-01T00:00:00Z",
+                    "response": "a",
+                    "done": true,
+                    "prompt_eval_count": 7,
+                    "eval_count": 1,
+                })
+                .to_string(),
+            )),
+            _ => None,
+        })
+        .await;
+        let (provider, exporter) = test_meter_provider();
+        let (ctx, _queue) = test_context(server.settings(), &provider.meter("test"));
+
+        let response = dispatch(
+            &ctx,
+            None,
+            "count",
+            OllamaRequest::CountTokens {
+                model: "ollama".to_string(),
+                text: "Hello world".to_string(),
+            },
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(response, OllamaResponse::TokenCount(7)));
+        ctx.meters.record(&response, Some("ollama"), &[]);
+        assert_eq!(
+            counter_sum(&provider, &exporter, "prosa_ollama_gen_token_count", &[]),
+            None
+        );
+        assert_eq!(
+            counter_sum(&provider, &exporter, "prosa_ollama_prompt_token_count", &[]),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn model_info_cached_within_ttl() {
+        let server = FakeServer::start(|path, _| match path {
+            "/api/show" => Some((200, r#"{"parameters":"temperature 0.7"}"#.to_string())),
+            _ => None,
+        })
+        .await;
+        let mut settings = server.settings();
+        settings.set_model_info_cache_ttl(Some(Duration::from_secs(60)));
+        let (provider, _exporter) = test_meter_provider();
+        let (ctx, _queue) = test_context(settings, &provider.meter("test"));
+
+        for _ in 0..2 {
+            let response = dispatch(
+                &ctx,
+                None,
+                "info",
+                OllamaRequest::ModelInfo("ollama".to_string()),
+                None,
+            )
+            .await;
+            let Ok(OllamaResponse::ModelInfo(info)) = response else {
+                panic!("Expect a model info response");
+            };
+            assert_eq!(info.parameters, "temperature 0.7");
+        }
+        // The second request is served by the cache
+        assert_eq!(server.hits("/api/show"), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_request_error_reply() {
+        let server = FakeServer::start(|_, _| None).await;
+        let (ctx, _queue) = test_context(server.settings(), &opentelemetry::global::meter("test"));
+
+        let (mut msg, mut responses) = test_request("gen", "prompt");
+        msg.take_data();
+        handle_request(&ctx, Arc::default(), msg, Instant::now())
+            .await
+            .unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(1), responses.recv())
+            .await
+            .expect("Expect a reply, not a timeout");
+        let Some(InternalMsg::Error(error)) = reply else {
+            panic!("Expect an error reply");
+        };
+        assert!(matches!(error.get_err(), ServiceError::ProtocolError(_)));
+        assert_eq!(server.hits("/api/generate"), 0);
+    }
+
+    #[tokio::test]
+    async fn model_allowlist_protocol_error() {
+        let server = FakeServer::start(|_, _| None).await;
+        let mut settings = server.settings();
+        settings.set_models(vec!["mistral".to_string()]);
+        settings.set_enforce_model_allowlist(true);
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+
+        let (msg, mut responses) = test_request("gen", "prompt");
+        handle_request(&ctx, Arc::default(), msg, Instant::now())
+            .await
+            .unwrap();
+        let Some(InternalMsg::Error(error)) = responses.recv().await else {
+            panic!("Expect an error reply");
+        };
+        assert!(matches!(error.get_err(), ServiceError::ProtocolError(_)));
+        assert_eq!(server.hits("/api/generate"), 0);
+    }
+
+    #[tokio::test]
+    async fn prompt_too_long_protocol_error() {
+        let server = FakeServer::start(|_, _| None).await;
+        let mut settings = server.settings();
+        settings.set_max_prompt_chars(Some(4));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+
+        let (msg, mut responses) = test_request("gen", "prompt");
+        handle_request(&ctx, Arc::default(), msg, Instant::now())
+            .await
+            .unwrap();
+        let Some(InternalMsg::Error(error)) = responses.recv().await else {
+            panic!("Expect an error reply");
+        };
+        assert!(
+            matches!(error.get_err(), ServiceError::ProtocolError(reason) if reason.contains("limit of 4 characters"))
+        );
+        assert_eq!(server.hits("/api/generate"), 0);
+    }
+
+    #[tokio::test]
+    async fn rejected_request_error_type() {
+        let server = FakeServer::start(|_, _| None).await;
+        let mut settings = server.settings();
+        settings.introspection_only = true;
+        let (provider, exporter) = test_meter_provider();
+        let (ctx, _queue) = test_context(settings, &provider.meter("test"));
+
+        // Rejected once built by the adaptor, the type is known
+        let (msg, _responses) = test_request("gen", "prompt");
+        handle_request(&ctx, Arc::default(), msg, Instant::now())
+            .await
+            .unwrap();
+        // Rejected before, for an unknown service
+        let (msg, _responses) = test_request("other", "prompt");
+        handle_request(&ctx, Arc::default(), msg, Instant::now())
+            .await
+            .unwrap();
+
+        let errors = |request_type| {
+            counter_sum(
+                &provider,
+                &exporter,
+                "prosa_ollama_request_errors",
+                &[KeyValue::new("type", request_type)],
+            )
+        };
+        assert_eq!(errors("gen"), Some(1));
+        assert_eq!(errors("rejected"), Some(1));
+    }
+
+    #[test]
+    fn retry_backoff_capped() {
+        let mut settings = OllamaProcSettings::default();
+        settings.set_retries(10, Duration::from_secs(1));
+        settings.set_retry_max_backoff(Duration::from_secs(5));
+        assert_eq!(settings.get_retry_backoff(1), Duration::from_secs(1));
+        assert_eq!(settings.get_retry_backoff(3), Duration::from_secs(4));
+        assert_eq!(settings.get_retry_backoff(4), Duration::from_secs(5));
+        assert_eq!(settings.get_retry_backoff(100), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn retry_within_deadline() {
+        let server =
+            FakeServer::start(|_, _| Some((503, r#"{"error":"restarting"}"#.to_string()))).await;
+        let mut settings = server.settings();
+        settings.set_retries(3, Duration::from_millis(500));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+        let generation = || {
+            OllamaRequest::GenerateRequest(Box::new(GenerationRequest::new(
+                "ollama".to_string(),
+                "prompt",
+            )))
+        };
+
+        // The first retry would start after the deadline
+        let deadline = Instant::now() + Duration::from_millis(300);
+        let error = expect_error(dispatch(&ctx, None, "gen", generation(), Some(deadline)).await);
+        assert!(matches!(error, OllamaError::Http { .. }));
+        assert_eq!(server.hits("/api/generate"), 1);
+
+        let deadline = Instant::now() + Duration::from_millis(800);
+        expect_error(dispatch(&ctx, None, "gen", generation(), Some(deadline)).await);
+        assert_eq!(server.hits("/api/generate"), 3);
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_outcomes() {
+        let server = FakeServer::start(|path, _| match path {
+            "/api/generate" => Some((200, generation_reply("answer"))),
+            _ => None,
+        })
+        .await;
+        let mut settings = server.settings();
+        settings.set_urls(vec![server.url.clone(), server.url.clone()]);
+        settings.set_circuit_breaker(Some(1), Duration::ZERO);
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+        let generation = || {
+            OllamaRequest::GenerateRequest(Box::new(GenerationRequest::new(
+                "ollama".to_string(),
+                "prompt",
+            )))
+        };
+
+        // Without healthy server, the backend fails
+        for ollama in ctx.ollama_pool().await.clients() {
+            ollama.record_call(true, 1);
+        }
+        let error = expect_error(dispatch(&ctx, None, "gen", generation(), None).await);
+        assert!(matches!(error, OllamaError::NoHealthyBackend));
+        assert!(matches!(
+            ctx.circuit_breaker.lock().await.state,
+            CircuitState::Open(_)
+        ));
+
+        // A request past its deadline doesn't take the probe of the backend
+        for ollama in ctx.ollama_pool().await.clients() {
+            ollama.set_healthy();
+        }
+        let error =
+            expect_error(dispatch(&ctx, None, "gen", generation(), Some(Instant::now())).await);
+        assert!(matches!(error, OllamaError::Timeout { .. }));
+        assert!(matches!(
+            ctx.circuit_breaker.lock().await.state,
+            CircuitState::Open(_)
+        ));
+
+        assert!(
+            dispatch(&ctx, None, "gen", generation(), None)
+                .await
+                .is_ok()
+        );
+        assert_eq!(ctx.circuit_breaker.lock().await.state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn circuit_breaker_lost_probe() {
+        let meters = OllamaMeters::new(&opentelemetry::global::meter("test"), "prosa_ollama");
+        let cooldown = Duration::from_millis(50);
+        let mut breaker = CircuitBreaker::default();
+        breaker.record(true, 1, &meters);
+        assert!(breaker.allow(cooldown, &meters).is_err());
+
+        // The probe is taken, but its outcome is never recorded
+        std::thread::sleep(cooldown);
+        assert!(breaker.allow(cooldown, &meters).is_ok());
+        assert!(breaker.allow(cooldown, &meters).is_err());
+
+        // Another probe is let through after a new cool-down
+        std::thread::sleep(cooldown);
+        assert!(breaker.allow(cooldown, &meters).is_ok());
+        assert!(matches!(breaker.state, CircuitState::HalfOpen(_)));
+        breaker.record(false, 1, &meters);
+        assert_eq!(breaker.state, CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn embeddings_batch_failure() {
+        let server = FakeServer::start(|path, hit| match (path, hit) {
+            ("/api/embed", 0) => Some((500, r#"{"error":"boom"}"#.to_string())),
+            ("/api/embed", 1) => Some((400, r#"{"error":"input too long"}"#.to_string())),
+            ("/api/embed", _) => Some((200, r#"{"embeddings":[[0.5]]}"#.to_string())),
+            _ => None,
+        })
+        .await;
+        let (mut ctx, _queue) =
+            test_context(server.settings(), &opentelemetry::global::meter("test"));
+        let (batch_queue, batch_entries) = mpsc::channel(16);
+        ctx.embeddings_batcher = Some(EmbeddingsBatcher { queue: batch_queue });
+        let ctx = Arc::new(ctx);
+        tokio::spawn(run_embeddings_batcher(
+            Arc::downgrade(&ctx),
+            batch_entries,
+            Duration::from_millis(50),
+            32,
+        ));
+        let embeddings = |input: &str| {
+            OllamaRequest::GenerateEmbeddingsRequest(Box::new(GenerateEmbeddingsRequest::new(
+                "ollama".to_string(),
+                input.into(),
+            )))
+        };
+
+        // The error of the batch is returned to each of its requests, without sending them again
+        let (first, second) = tokio::join!(
+            dispatch(&ctx, None, "embed", embeddings("first"), None),
+            dispatch(&ctx, None, "embed", embeddings("second"), None)
+        );
+        for error in [expect_error(first), expect_error(second)] {
+            let OllamaError::Batch(batch_error) = &error else {
+                panic!("Not a batch error: {error}");
+            };
+            assert!(
+                matches!(**batch_error, OllamaError::Http { status, .. } if status.as_u16() == 500)
+            );
+            assert!(error.recoverable());
+        }
+        assert_eq!(server.hits("/api/embed"), 1);
+
+        // A rejected batch may be due to one of its requests, each of them gets its own reply
+        let (first, second) = tokio::join!(
+            dispatch(&ctx, None, "embed", embeddings("first"), None),
+            dispatch(&ctx, None, "embed", embeddings("second"), None)
+        );
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(server.hits("/api/embed"), 4);
+    }
+
+    #[tokio::test]
+    async fn single_backend_health() {
+        let server = FakeServer::start(|_, _| None).await;
+        let mut settings = server.settings();
+        settings.set_backend_health(2, Duration::from_secs(10));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+        let generation = || {
+            OllamaRequest::GenerateRequest(Box::new(GenerationRequest::new(
+                "ollama".to_string(),
+                "prompt",
+            )))
+        };
+
+        // The failures of a single server are recorded, but it's still selected
+        for _ in 0..3 {
+            expect_error(dispatch(&ctx, None, "gen", generation(), None).await);
+        }
+        assert_eq!(server.hits("/api/generate"), 3);
+        let backend = &ctx.backend_statuses().await[0];
+        assert!(!backend.healthy);
+        assert_eq!(backend.consecutive_failures, 3);
+    }
+
+    #[tokio::test]
+    async fn timed_out_call_is_backend_failure() {
+        // Server accepting the connections without ever replying
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+        let mut settings = OllamaProcSettings::new(url, false, vec!["gen".to_string()]);
+        settings.set_request_timeout(Some(Duration::from_millis(50)));
+        settings.set_backend_health(1, Duration::from_secs(10));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+
+        let request = OllamaRequest::GenerateRequest(Box::new(GenerationRequest::new(
+            "ollama".to_string(),
+            "prompt",
+        )));
+        let error = expect_error(dispatch(&ctx, None, "gen", request, None).await);
+        assert!(matches!(error, OllamaError::Timeout { .. }));
+        let backend = &ctx.backend_statuses().await[0];
+        assert!(!backend.healthy);
+        assert_eq!(backend.in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn call_across_reload() {
+        // Server replying slowly, for a call still in flight during the reload
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let slow_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if read_request(&mut socket).await.is_some() {
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                        let body = generation_reply("old");
+                        let reply = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                            body.len()
+                        );
+                        let _ = socket.write_all(reply.as_bytes()).await;
+                    }
+                });
+            }
+        });
+        let server = FakeServer::start(|path, _| match path {
+            "/api/generate" => Some((200, generation_reply("new"))),
+            _ => None,
+        })
+        .await;
+        let slow_settings = OllamaProcSettings::new(slow_url, false, vec!["gen".to_string()]);
+        let (ctx, _queue) =
+            test_context(slow_settings.clone(), &opentelemetry::global::meter("test"));
+        let generation = || {
+            OllamaRequest::GenerateRequest(Box::new(GenerationRequest::new(
+                "ollama".to_string(),
+                "prompt",
+            )))
+        };
+        let response_text = |response: Result<OllamaResponse, OllamaError>| match response {
+            Ok(OllamaResponse::GenerateResponse(response)) => response.response,
+            Ok(_) => panic!("Not a generation response"),
+            Err(e) => panic!("Generation error: {e}"),
+        };
+
+        // The call in flight finishes on the replaced client, the new call uses the new one
+        let (in_flight, after_reload) =
+            tokio::join!(dispatch(&ctx, None, "gen", generation(), None), async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                ctx.reload_ollama(
+                    server.settings().get_ollama().unwrap(),
+                    Duration::from_secs(5),
+                )
+                .await;
+                dispatch(&ctx, None, "gen", generation(), None).await
+            });
+        assert_eq!(response_text(in_flight), "old");
+        assert_eq!(response_text(after_reload), "new");
+
+        // A call not drained in time is aborted
+        ctx.reload_ollama(slow_settings.get_ollama().unwrap(), Duration::ZERO)
+            .await;
+        let (in_flight, _) = tokio::join!(dispatch(&ctx, None, "gen", generation(), None), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            ctx.reload_ollama(
+                server.settings().get_ollama().unwrap(),
+                Duration::from_millis(50),
+            )
+            .await;
+        });
+        let error = expect_error(in_flight);
+        assert!(
+            error.to_string().contains("replaced by a reload"),
+            "{error}"
+        );
+        assert_eq!(ctx.backend_statuses().await[0].in_flight, 0);
+    }
+
+    #[test]
+    fn meter_name_leaked_once() {
+        let mut settings = OllamaProcSettings::default();
+        assert_eq!(settings.get_meter_name(), "ollama");
+
+        settings.set_metric_namespace(None, Some("ollama_b".to_string()));
+        let meter_name = settings.get_meter_name();
+        assert_eq!(meter_name, "ollama_b");
+        assert!(std::ptr::eq(meter_name, settings.clone().get_meter_name()));
+    }
+
+    #[test]
+    fn reload_keeps_startup_settings() {
+        let running = OllamaProcSettings::default();
+        let mut reloaded = OllamaProcSettings::default();
+        reloaded.set_max_concurrent_requests(Some(4));
+        reloaded.set_metric_namespace(Some("ollama_b".to_string()), None);
+        reloaded.set_model_info_cache_ttl(Some(Duration::from_secs(60)));
+
+        assert_eq!(
+            reloaded.retain_startup_settings(&running),
+            vec!["max_concurrent_requests", "metric_prefix"]
+        );
+        assert_eq!(reloaded.max_concurrent_requests, None);
+        assert_eq!(reloaded.get_metric_prefix(), "prosa_ollama");
+        // The other settings are applied
+        assert_eq!(reloaded.model_info_cache_ttl, Some(Duration::from_secs(60)));
+        assert!(reloaded.retain_startup_settings(&running).is_empty());
+    }
+
+    #[test]
+    fn humantime_durations() {
+        let settings: OllamaProcSettings = serde_json::from_value(serde_json::json!({
+            "url": "http://localhost:11434",
+            "request_timeout": "2m",
+            "retry_backoff": "500ms",
+            "embeddings_batch_window": "5ms",
+        }))
+        .unwrap();
+        assert_eq!(settings.request_timeout, Some(Duration::from_secs(120)));
+        assert_eq!(settings.retry_backoff, Duration::from_millis(500));
+        assert_eq!(
+            settings.embeddings_batch_window,
+            Some(Duration::from_millis(5))
+        );
+        assert_eq!(settings.connect_timeout, None);
+        assert_eq!(
+            settings.shutdown_grace_period,
+            OllamaProcSettings::default_shutdown_grace_period()
+        );
+
+        let serialized = serde_json::to_value(&settings).unwrap();
+        assert_eq!(serialized["request_timeout"], "2m");
+    }
+
+    #[tokio::test]
+    async fn admission_over_capacity() {
+        const GB: u64 = 1 << 30;
+        let server = FakeServer::start(|path, _| match path {
+            "/api/tags" => Some((
+                200,
+                serde_json::json!({"models": [
+                    {"name": "ollama:latest", "modified_at": "", "size": 6 * GB},
+                ]})
+                .to_string(),
+            )),
+            "/api/ps" => Some((
+                200,
+                serde_json::json!({"models": [
+                    {"name": "other:latest", "size": 4 * GB, "size_vram": 4 * GB},
+                ]})
+                .to_string(),
+            )),
+            _ => Some((200, generation_reply("loaded"))),
+        })
+        .await;
+        let mut settings = server.settings();
+        settings.set_vram_capacity(Some(8 * GB));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+        let ollama = ctx.ollama_pool().await.clients()[0].clone();
+        ollama.local_models().await.unwrap();
+        ollama.refresh_running_models().await.unwrap();
+        assert!(ollama.fits_model("other", 8 * GB));
+        assert!(!ollama.fits_model("ollama", 8 * GB));
+        assert!(ollama.fits_model("ollama", 10 * GB));
+        // A model of unknown size is not rejected
+        assert!(ollama.fits_model("unknown", 8 * GB));
+
+        let generation = OllamaRequest::GenerateRequest(Box::new(GenerationRequest::new(
+            "ollama".to_string(),
+            "prompt",
+        )));
+        let error = expect_error(dispatch(&ctx, None, "gen", generation, None).await);
+        assert!(matches!(error, OllamaError::OverCapacity { model } if model == "ollama"));
+        assert_eq!(server.hits("/api/generate"), 0);
+    }
+
+    #[test]
+    fn pull_eta_from_bandwidth() {
+        assert_eq!(pull_eta(1000, 0, Duration::from_secs(5)), None);
+        // 100 bytes per second
+        assert_eq!(
+            pull_eta(1000, 500, Duration::from_secs(5)),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            pull_eta(0, 500, Duration::from_secs(5)),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn loaded_models_reconcile() {
+        let running_model = |name: &str| RunningModel {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let mut loaded_models = LoadedModels::default();
+        loaded_models.touch("mistral", 3);
+        loaded_models.touch("llama3:8b", 3);
+
+        // The expired model is forgotten, the model loaded by another client is the least recently used
+        loaded_models.reconcile(&[
+            running_model("mistral:latest"),
+            running_model("phi3:latest"),
+        ]);
+        assert_eq!(loaded_models.models, ["phi3:latest", "mistral"]);
+    }
+
+    #[test]
+    fn generation_stream_error_line() {
+        let chunks = GenerationStream::parse(
+            br#"{"model":"mistral","created_at":"2025-01-01T00:00:00Z","response":"Hi","done":false}
+"#,
+        )
+        .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].response, "Hi");
+
+        let error = GenerationStream::parse(br#"{"error":"out of memory"}"#).unwrap_err();
+        assert!(matches!(
+            ollama_rs_error(error),
+            ollama_rs::error::OllamaError::InternalError(error) if error.message == "out of memory"
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_more_chunks_than_queues() {
+        // More chunks than the queue of the stream service and the queue of its responses can hold
+        let responses: Vec<String> = (0..STREAM_RESPONSE_QUEUE_SIZE * 4)
+            .map(|index| format!("{index} "))
+            .collect();
+        let body = generation_stream_reply(&responses);
+        let server = FakeServer::start(move |path, _| match path {
+            "/api/generate" => Some((200, body.clone())),
+            _ => None,
+        })
+        .await;
+        let (ctx, _queue) = test_context(server.settings(), &opentelemetry::global::meter("test"));
+
+        // Stream service acknowledging each partial message, with a queue of a single message
+        let (stream_queue, mut stream_requests) = mpsc::channel(1);
+        let acknowledged = tokio::spawn(async move {
+            let mut acknowledged = 0;
+            while let Some(InternalMsg::Request(mut msg)) = stream_requests.recv().await {
+                let data = msg.take_data().unwrap();
+                msg.return_to_sender(data).await.unwrap();
+                acknowledged += 1;
+            }
+            acknowledged
+        });
+
+        let sink = StreamSink::new(
+            "gen",
+            1,
+            None,
+            Some(StreamTarget::new("stream", stream_queue)),
+        );
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            call_stream(
+                &ctx,
+                GenerationRequest::new("ollama".to_string(), "prompt"),
+                &sink,
+                None,
+            ),
+        )
+        .await
+        .expect("The stream must not block on its acknowledgements");
+        let Ok(OllamaResponse::GenerateResponse(response)) = response else {
+            panic!("Expect a generation response");
+        };
+        assert_eq!(response.response, responses.concat());
+
+        drop(sink);
+        assert_eq!(acknowledged.await.unwrap(), responses.len());
+    }
+
+    #[test]
+    fn generation_cache_hash_collision_miss() {
+        let mut request = GenerationRequest::new("ollama".to_string(), "prompt");
+        request.options = Some(ModelOptions::default().temperature(0.0));
+        let key = GenerationCache::key(&OllamaRequest::GenerateRequest(Box::new(request)), false)
+            .unwrap();
+        let response: GenerationResponse =
+            serde_json::from_str(&generation_reply("cached")).unwrap();
+        let mut cache = GenerationCache::default();
+        cache.insert(key.clone(), response, 8);
+        assert!(cache.get(&key, None).is_some());
+
+        // Another request with the same hash doesn't get the cached response
+        let colliding = GenerationCacheKey {
+            hash: key.hash,
+            request: key.request.replace("prompt", "other"),
+        };
+        assert!(cache.get(&colliding, None).is_none());
+        assert!(cache.get(&key, None).is_some());
+    }
+
+    #[test]
+    fn completion_log_metadata_json() {
+        let (msg, _responses) = test_request("gen", "prompt");
+        let mut completion_log = CompletionLog::new(&msg);
+        assert_eq!(completion_log.metadata_json(), "{}");
+
+        completion_log.metadata = RequestMetadata::from([
+            ("tenant".to_string(), "acme".to_string()),
+            ("feature".to_string(), "say \"hi\"".to_string()),
+        ]);
+        let metadata: serde_json::Value =
+            serde_json::from_str(&completion_log.metadata_json()).unwrap();
+        assert_eq!(
+            metadata,
+            serde_json::json!({"tenant": "acme", "feature": "say \"hi\""})
+        );
+    }
+
+    #[test]
+    fn models_available_gauge_states() {
+        let (provider, exporter) = test_meter_provider();
+        let meters = OllamaMeters::new(&provider.meter("test"), "prosa_ollama");
+        let mut settings = OllamaProcSettings::new(
+            Url::parse("http://localhost:11434/").unwrap(),
+            false,
+            vec!["gen".to_string()],
+        );
+        settings.set_models(vec!["mistral".to_string(), "llama3".to_string()]);
+        let local_model = LocalModel {
+            name: "mistral:latest".to_string(),
+            modified_at: String::new(),
+            size: 0,
+        };
+        meters.record_models_available(&settings, "localhost:11434", &[local_model]);
+
+        let models = |state| {
+            gauge_value(
+                &provider,
+                &exporter,
+                "prosa_ollama_models_available",
+                &[
+                    KeyValue::new("host", "localhost:11434"),
+                    KeyValue::new("state", state),
+                ],
+            )
+        };
+        assert_eq!(models("configured"), Some(2));
+        assert_eq!(models("available"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn concurrent_streams_partials_origin() {
+        let responses: Vec<String> = (0..8).map(|index| format!("{index} ")).collect();
+        let body = generation_stream_reply(&responses);
+        let server = FakeServer::start(move |path, _| match path {
+            "/api/generate" => Some((200, body.clone())),
+            _ => None,
+        })
+        .await;
+        let (ctx, _queue) = test_context(server.settings(), &opentelemetry::global::meter("test"));
+
+        // Both streams send their partial messages to the same stream service
+        let (stream_queue, mut stream_requests) = mpsc::channel(64);
+        let first = StreamSink::new(
+            "gen",
+            1,
+            None,
+            Some(StreamTarget::new("stream", stream_queue.clone())),
+        );
+        let second = StreamSink::new(
+            "gen",
+            2,
+            None,
+            Some(StreamTarget::new("stream", stream_queue)),
+        );
+        let (first_response, second_response) = tokio::join!(
+            call_stream(
+                &ctx,
+                GenerationRequest::new("ollama".to_string(), "first"),
+                &first,
+                None
+            ),
+            call_stream(
+                &ctx,
+                GenerationRequest::new("ollama".to_string(), "second"),
+                &second,
+                None
+            ),
+        );
+        assert!(first_response.is_ok());
+        assert!(second_response.is_ok());
+
+        // Each partial message is tagged with the request it comes from
+        drop((first, second));
+        let mut partials: HashMap<String, Vec<String>> = HashMap::new();
+        while let Some(InternalMsg::Request(mut msg)) = stream_requests.recv().await {
+            let data = msg.take_data().unwrap();
+            partials
+                .entry(data.get_string(2).unwrap().to_string())
+                .or_default()
+                .push(data.get_string(1).unwrap().to_string());
+        }
+        assert_eq!(partials.len(), 2);
+        assert_eq!(partials["gen#1"], responses);
+        assert_eq!(partials["gen#2"], responses);
+    }
+
+    #[tokio::test]
+    async fn stream_service_missing_protocol_error() {
+        let server = FakeServer::start(|_, _| None).await;
+        let mut settings = server.settings();
+        settings.services.push("stream".to_string());
+        settings.set_stream_service(Some("ollama.stream".to_string()));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+
+        // The stream service isn't in the service table, its chunks would be lost
+        let (msg, mut responses) = test_request("stream", "prompt");
+        handle_request(&ctx, Arc::default(), msg, Instant::now())
+            .await
+            .unwrap();
+        let Some(InternalMsg::Error(error)) = responses.recv().await else {
+            panic!("Expect an error reply");
+        };
+        assert!(
+            matches!(error.get_err(), ServiceError::ProtocolError(reason) if reason.contains("ollama.stream"))
+        );
+        assert_eq!(server.hits("/api/generate"), 0);
+    }
+
+    #[tokio::test]
+    async fn stream_outlasts_request_timeout() {
+        // Server streaming a chunk every 50ms, the whole generation taking longer than the request timeout
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let responses: Vec<String> = (0..6).map(|index| format!("{index} ")).collect();
+        let body = generation_stream_reply(&responses);
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            read_request(&mut socket).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            for line in body.lines() {
+                socket.write_all(line.as_bytes()).await.unwrap();
+                socket.write_all(b"\n").await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        let mut settings = OllamaProcSettings::new(url, false, vec!["gen".to_string()]);
+        settings.set_request_timeout(Some(Duration::from_millis(150)));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+
+        // Only the first chunk is bounded by the request timeout
+        let sink = StreamSink::new("gen", 1, None, None);
+        let request = OllamaRequest::GenerateStreamRequest(Box::new(GenerationRequest::new(
+            "ollama".to_string(),
+            "prompt",
+        )));
+        let Ok(OllamaResponse::GenerateResponse(response)) =
+            dispatch(&ctx, Some(sink), "gen", request, None).await
+        else {
+            panic!("Expect a generation response");
+        };
+        assert_eq!(response.response, responses.concat());
+    }
+
+    #[tokio::test]
+    async fn stream_truncated_at_max_bytes() {
+        // Server streaming a runaway generation, until the client closes the connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let (closed_tx, closed) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            read_request(&mut socket).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let chunk = generation_stream_reply(&["abcd".to_string(), String::new()]);
+            let chunk = chunk.lines().next().unwrap().to_string() + "\n";
+            while socket.write_all(chunk.as_bytes()).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let _ = closed_tx.send(());
+        });
+        let mut settings = OllamaProcSettings::new(url, false, vec!["gen".to_string()]);
+        settings.set_max_response_bytes(Some(10));
+        let (ctx, _queue) = test_context(settings, &opentelemetry::global::meter("test"));
+
+        let (stream_queue, mut stream_requests) = mpsc::channel(16);
+        let sink = StreamSink::new(
+            "gen",
+            1,
+            None,
+            Some(StreamTarget::new("stream", stream_queue)),
+        );
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            call_stream(
+                &ctx,
+                GenerationRequest::new("ollama".to_string(), "prompt"),
+                &sink,
+                None,
+            ),
+        )
+        .await
+        .expect("The stream must stop at the limit");
+        let Ok(OllamaResponse::GenerateResponse(response)) = response else {
+            panic!("Expect a generation response");
+        };
+        assert_eq!(
+            response.response,
+            format!("abcdabcdab{STREAM_TRUNCATION_MARKER}")
+        );
+        assert!(response.done);
+
+        // The truncation marker is the last partial message
+        drop(sink);
+        let mut partials = Vec::new();
+        while let Some(InternalMsg::Request(mut msg)) = stream_requests.recv().await {
+            partials.push(msg.take_data().unwrap().get_string(1).unwrap().to_string());
+        }
+        assert_eq!(partials, ["abcd", "abcd", "ab", STREAM_TRUNCATION_MARKER]);
+
+        // The backend stream is dropped, so its connection is closed
+        tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .expect("The backend stream must be closed")
+            .unwrap();
+    }
+}
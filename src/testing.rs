@@ -0,0 +1,223 @@
+//! In-memory mock of the Ollama backend, to unit test an [`OllamaAdaptor`] without a live server
+
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+
+use prosa_utils::msg::tvf::Tvf;
+
+use crate::adaptor::OllamaAdaptor;
+use crate::proc::{AdaptorRequest, OllamaError, OllamaProcSettings, OllamaRequest, OllamaResponse};
+
+/// Builder of a [`MockOllama`], to stage the canned responses of each service
+pub struct MockOllamaBuilder {
+    responses: HashMap<String, VecDeque<Result<OllamaResponse, OllamaError>>>,
+    settings: OllamaProcSettings,
+}
+
+impl MockOllamaBuilder {
+    /// Create a builder without any staged response
+    pub fn new() -> MockOllamaBuilder {
+        MockOllamaBuilder {
+            responses: HashMap::new(),
+            settings: OllamaProcSettings::default(),
+        }
+    }
+
+    /// Settings of the mocked processor, whose service defaults and default options are merged into the requests built by the adaptor
+    pub fn settings(mut self, settings: OllamaProcSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Stage a response to the next request of a service. The responses of a service are returned in their staging order
+    pub fn response<S>(mut self, service: S, response: OllamaResponse) -> Self
+    where
+        S: Into<String>,
+    {
+        self.responses
+            .entry(service.into())
+            .or_default()
+            .push_back(Ok(response));
+        self
+    }
+
+    /// Stage a backend error to the next request of a service
+    pub fn error<S>(mut self, service: S, error: OllamaError) -> Self
+    where
+        S: Into<String>,
+    {
+        self.responses
+            .entry(service.into())
+            .or_default()
+            .push_back(Err(error));
+        self
+    }
+
+    /// Build the mock around the adaptor to test
+    pub fn build<M, A>(self, adaptor: A) -> MockOllama<M, A>
+    where
+        M: 'static
+            + std::marker::Send
+            + std::marker::Sync
+            + std::marker::Sized
+            + std::clone::Clone
+            + std::fmt::Debug
+            + Tvf
+            + std::default::Default,
+        A: OllamaAdaptor<M>,
+    {
+        MockOllama {
+            adaptor,
+            responses: self.responses,
+            settings: self.settings,
+            requests: Vec::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl Default for MockOllamaBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Mock of the Ollama backend, running the request/response round-trip of an adaptor in memory.
+///
+/// The Ollama requests built by the adaptor are kept, to assert them, and answered with the staged responses of their service.
+///
+/// ```
+/// use ollama_rs::generation::embeddings::GenerateEmbeddingsResponse;
+/// use ollama_rs::generation::embeddings::request::GenerateEmbeddingsRequest;
+/// use prosa::core::adaptor::Adaptor;
+/// use prosa_ollama::adaptor::OllamaAdaptor;
+/// use prosa_ollama::proc::{
+///     OllamaError, OllamaProc, OllamaProcSettings, OllamaRequest, OllamaResponse, ServiceDefaults,
+/// };
+/// use prosa_ollama::testing::MockOllamaBuilder;
+/// use prosa_utils::msg::simple_string_tvf::SimpleStringTvf;
+/// use prosa_utils::msg::tvf::Tvf;
+///
+/// // Adaptor embedding the field 1 of the message, and answering the number of embeddings
+/// #[derive(Adaptor)]
+/// struct EmbedAdaptor;
+///
+/// impl OllamaAdaptor<SimpleStringTvf> for EmbedAdaptor {
+///     fn new(_proc: &OllamaProc<SimpleStringTvf>) -> Result<Self, OllamaError> {
+///         Ok(EmbedAdaptor)
+///     }
+///
+///     fn process_request<'a>(
+///         &mut self,
+///         _service_name: &str,
+///         request: SimpleStringTvf,
+///     ) -> Result<OllamaRequest<'a>, OllamaError> {
+///         let input = request
+///             .get_string(1)
+///             .map_err(|e| OllamaError::bad_field("1", e.to_string()))?;
+///         // The model is given by the service defaults
+///         Ok(OllamaRequest::GenerateEmbeddingsRequest(Box::new(
+///             GenerateEmbeddingsRequest::new(String::new(), input.as_str().into()),
+///         )))
+///     }
+///
+///     fn process_ollama_response(
+///         &mut self,
+///         response: OllamaResponse,
+///     ) -> Result<SimpleStringTvf, OllamaError> {
+///         let mut msg = SimpleStringTvf::default();
+///         if let OllamaResponse::GenerateEmbeddingsResponse(response) = response {
+///             msg.put_string(1, response.embeddings.len().to_string());
+///         }
+///         Ok(msg)
+///     }
+/// }
+///
+/// let mut settings = OllamaProcSettings::default();
+/// settings.set_service_defaults(
+///     "embed".to_string(),
+///     ServiceDefaults {
+///         model: Some("all-minilm".to_string()),
+///         options: None,
+///     },
+/// );
+/// let mut mock = MockOllamaBuilder::new()
+///     .settings(settings)
+///     .response(
+///         "embed",
+///         OllamaResponse::GenerateEmbeddingsResponse(Box::new(GenerateEmbeddingsResponse {
+///             embeddings: vec![vec![0.1, 0.2]],
+///         })),
+///     )
+///     .build(EmbedAdaptor);
+///
+/// let mut request = SimpleStringTvf::default();
+/// request.put_string(1, "Hello");
+/// let response = mock.call("embed", request).unwrap();
+/// assert_eq!(response.get_string(1).unwrap().as_str(), "1");
+/// assert_eq!(mock.requests()[0].1.get_model_name(), Some("all-minilm"));
+/// ```
+pub struct MockOllama<M, A> {
+    adaptor: A,
+    responses: HashMap<String, VecDeque<Result<OllamaResponse, OllamaError>>>,
+    settings: OllamaProcSettings,
+    requests: Vec<(String, OllamaRequest<'static>)>,
+    phantom: PhantomData<M>,
+}
+
+impl<M, A> MockOllama<M, A>
+where
+    M: 'static
+        + std::marker::Send
+        + std::marker::Sync
+        + std::marker::Sized
+        + std::clone::Clone
+        + std::fmt::Debug
+        + Tvf
+        + std::default::Default,
+    A: OllamaAdaptor<M>,
+{
+    /// Send a request message to a service, through [`OllamaAdaptor::process_request_or_response`] then [`OllamaAdaptor::process_ollama_response_with_options`] with the next staged response of the service.
+    /// As by the processor, the service defaults and the default options of the settings are merged into the request, and the merged options are given with the response.
+    ///
+    /// A staged error is given to [`OllamaAdaptor::on_backend_error`], and a service without any staged response left is in error.
+    pub fn call(&mut self, service: &str, request: M) -> Result<M, OllamaError> {
+        let mut request = match self.adaptor.process_request_or_response(service, request)? {
+            AdaptorRequest::Ollama(request) => request,
+            AdaptorRequest::Response(response) => return Ok(response),
+        };
+        self.settings.apply_defaults(service, &mut request)?;
+        let request_kind = request.get_kind();
+        let options = request.get_options().cloned();
+        self.requests.push((service.to_string(), request));
+
+        match self
+            .responses
+            .get_mut(service)
+            .and_then(VecDeque::pop_front)
+        {
+            Some(Ok(response)) => self
+                .adaptor
+                .process_ollama_response_with_options(response, options.as_ref()),
+            Some(Err(e)) => Err(self.adaptor.on_backend_error(request_kind, &e).unwrap_or(e)),
+            None => Err(OllamaError::Other(format!(
+                "No staged response left for the service `{service}`"
+            ))),
+        }
+    }
+
+    /// Getter of the Ollama requests built by the adaptor and merged with the defaults of the settings, with their service, in their sending order
+    pub fn requests(&self) -> &[(String, OllamaRequest<'static>)] {
+        &self.requests
+    }
+
+    /// Getter of the tested adaptor
+    pub fn adaptor(&self) -> &A {
+        &self.adaptor
+    }
+
+    /// Mutable getter of the tested adaptor
+    pub fn adaptor_mut(&mut self) -> &mut A {
+        &mut self.adaptor
+    }
+}